@@ -0,0 +1,58 @@
+// The owning lexer against the borrowing one: `cargo bench --bench lex`.
+// Allocations are counted once per sample before timing.
+
+#[macro_use]
+extern crate criterion;
+extern crate val;
+
+use criterion::{black_box, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static A: Counting = Counting;
+
+fn allocs<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    f();
+    ALLOCS.load(Ordering::Relaxed) - before
+}
+
+// a struct of n binds, each a column of plain and escaped literals
+fn sample(n: usize) -> String {
+    let mut s = String::from("@struct {\n");
+    for i in 0..n {
+        s.push_str(&format!("  @bind k{} {{ @column {{ 'plain{}' n\"it's\" 'a\\tb' }} }} # note\n", i, i));
+    }
+    s.push('}');
+    s
+}
+
+fn bench_lex(c: &mut Criterion) {
+    let text = sample(10_000);
+    println!("lex_spans: {} allocations", allocs(|| { black_box(val::lex_spans(&text)); }));
+    println!("tokens:    {} allocations", allocs(|| { black_box(val::tokens(&text).collect::<Vec<_>>()); }));
+    let mut g = c.benchmark_group("lex");
+    g.throughput(Throughput::Bytes(text.len() as u64));
+    g.bench_function("lex_spans", |b| b.iter(|| val::lex_spans(black_box(&text))));
+    g.bench_function("tokens", |b| b.iter(|| val::tokens(black_box(&text)).count()));
+    g.finish();
+}
+
+criterion_group!(benches, bench_lex);
+criterion_main!(benches);
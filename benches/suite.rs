@@ -0,0 +1,55 @@
+// Lexing, parsing and evaluating generated documents of each shape at two
+// sizes: `cargo bench --bench suite`, or e.g. `-- parse/wide` for one.
+
+#[macro_use]
+extern crate criterion;
+extern crate val;
+
+use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+use val::corpus::{self, has_error, limits, sizes};
+
+fn documents() -> Vec<(String, usize, String)> {
+    let mut docs = vec![];
+    for shape in corpus::SHAPES {
+        for &n in &sizes(shape) {
+            docs.push((shape.to_string(), n, corpus::generate(shape, n).unwrap()));
+        }
+    }
+    docs
+}
+
+fn bench_lex(c: &mut Criterion) {
+    let mut g = c.benchmark_group("lex");
+    for (shape, n, text) in documents() {
+        g.throughput(Throughput::Bytes(text.len() as u64));
+        g.bench_with_input(BenchmarkId::new(shape, n), &text, |b, t| b.iter(|| val::lex_spans(black_box(t))));
+    }
+    g.finish();
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut g = c.benchmark_group("parse");
+    let limits = limits();
+    for (shape, n, text) in documents() {
+        g.throughput(Throughput::Bytes(text.len() as u64));
+        g.bench_with_input(BenchmarkId::new(shape, n), &text,
+            |b, t| b.iter(|| val::parse_str_with(black_box(t), &limits)));
+    }
+    g.finish();
+}
+
+fn bench_eval(c: &mut Criterion) {
+    let mut g = c.benchmark_group("eval");
+    let limits = limits();
+    for (shape, n, text) in documents() {
+        let doc = val::parse_str_with(&text, &limits);
+        assert!(doc.diagnostics().is_empty(), "{} {}", shape, n);
+        assert!(!has_error(&doc.env().eval()), "{} {}", shape, n);
+        g.throughput(Throughput::Elements(n as u64));
+        g.bench_with_input(BenchmarkId::new(shape, n), &doc, |b, d| b.iter(|| d.env().eval()));
+    }
+    g.finish();
+}
+
+criterion_group!(benches, bench_lex, bench_parse, bench_eval);
+criterion_main!(benches);
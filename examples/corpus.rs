@@ -0,0 +1,25 @@
+// Write a generated document to stdout, e.g.
+// cargo run --release --example corpus -- wide 100000 > wide.bv
+
+extern crate val;
+
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let n = args.get(1).and_then(|n| n.parse::<usize>().ok());
+    match (args.first(), n) {
+        (Some(shape), Some(n)) if args.len() == 2 => match val::corpus::generate(shape, n) {
+            Some(text) => print!("{}", text),
+            None => {
+                eprintln!("Unknown shape {}; one of {}", shape, val::corpus::SHAPES.join(" "));
+                process::exit(2)
+            },
+        },
+        _ => {
+            eprintln!("Usage: corpus SHAPE N, SHAPE one of {}", val::corpus::SHAPES.join(" "));
+            process::exit(2)
+        },
+    }
+}
@@ -0,0 +1,375 @@
+// A syntax tree in one arena: nodes are numbered in source order, and each
+// knows its parent, its place in the parent, and its source span, so side
+// tables and references back to the syntax are a number each.
+
+use ast::{Bind, Expr};
+use events::{self, Event};
+use lex::{self, Key, Span, Tok};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// as ast::Expr, with children by number
+#[derive(Debug, PartialEq, Clone)]
+pub enum Node {
+    Literal(String),
+    Column(Vec<NodeId>),
+    Struct(Vec<(String, NodeId)>),
+    // @root, @my, @up, @sys or @lib
+    Key(Key),
+    From(Vec<NodeId>),
+    Call{function: NodeId, arguments: Vec<(String, NodeId)>},
+    Import(String),
+    Error(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ast {
+    nodes: Vec<Node>,
+    parents: Vec<Option<NodeId>>,
+    slots: Vec<u32>,   // place among the parent's children
+    spans: Vec<Span>,  // default when built from an Expr
+    mys: Vec<Option<NodeId>>, // the struct @my means there
+}
+
+// values kept beside the nodes of one Ast, e.g. their types
+#[derive(Debug, PartialEq, Clone)]
+pub struct Side<T> {
+    values: Vec<Option<T>>,
+}
+
+impl<T> Side<T> {
+    pub fn new(ast: &Ast) -> Side<T> {
+        Side{values: (0..ast.len()).map(|_| None).collect()}
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&T> {
+        self.values[id.index()].as_ref()
+    }
+
+    pub fn set(&mut self, id: NodeId, v: T) {
+        self.values[id.index()] = Some(v);
+    }
+}
+
+// nodes added in source order, each to the innermost open one
+struct Builder {
+    ast: Ast,
+    // open nodes, with the name of the @bind being read and whether a
+    // @call has its function yet
+    open: Vec<(NodeId, Option<String>, bool)>,
+}
+
+impl Builder {
+    fn new() -> Builder {
+        Builder{ast: Ast{nodes: vec![], parents: vec![], slots: vec![], spans: vec![], mys: vec![]}, open: vec![]}
+    }
+
+    fn add(&mut self, node: Node, span: Span) -> NodeId {
+        let id = NodeId(self.ast.nodes.len() as u32);
+        let (parent, slot, my) = match self.open.last_mut() {
+            None => (None, 0, None),
+            Some(&mut (p, ref mut name, ref mut has_fn)) => {
+                let slot = match self.ast.nodes[p.index()] {
+                    Node::Struct(ref mut bs) => {
+                        bs.push((name.take().unwrap_or_default(), id));
+                        bs.len() - 1
+                    },
+                    Node::Call{ref mut function, ..} if !*has_fn => {
+                        *has_fn = true;
+                        *function = id;
+                        0
+                    },
+                    Node::Call{ref mut arguments, ..} => {
+                        arguments.push((name.take().unwrap_or_default(), id));
+                        arguments.len()
+                    },
+                    Node::Column(ref mut xs) | Node::From(ref mut xs) => {
+                        xs.push(id);
+                        xs.len() - 1
+                    },
+                    _ => 0,
+                };
+                // a struct is @my for what it holds; anything else passes its own on
+                let my = match self.ast.nodes[p.index()] {
+                    Node::Struct(_) => Some(p),
+                    _ => self.ast.mys[p.index()],
+                };
+                (Some(p), slot, my)
+            },
+        };
+        self.ast.nodes.push(node);
+        self.ast.parents.push(parent);
+        self.ast.slots.push(slot as u32);
+        self.ast.spans.push(span);
+        self.ast.mys.push(my);
+        id
+    }
+
+    fn start(&mut self, node: Node, span: Span) {
+        let id = self.add(node, span);
+        self.open.push((id, None, false));
+    }
+
+    fn bind(&mut self, name: String) {
+        if let Some(o) = self.open.last_mut() {
+            o.1 = Some(name);
+        }
+    }
+
+    fn end(&mut self, hi: usize) {
+        if let Some((id, _, _)) = self.open.pop() {
+            self.ast.spans[id.index()].hi = hi;
+        }
+    }
+
+    fn binds(&mut self, bs: Vec<Bind>) {
+        for b in bs {
+            self.bind(b.name);
+            self.expr(b.value);
+        }
+    }
+
+    fn expr(&mut self, x: Expr) {
+        let span = Span::default();
+        match x {
+            Expr::Literal(s) => { self.add(Node::Literal(s), span); },
+            Expr::Column(xs) => {
+                self.start(Node::Column(vec![]), span);
+                for x in xs {
+                    self.expr(x);
+                }
+                self.end(0);
+            },
+            Expr::Struct(bs) => {
+                self.start(Node::Struct(vec![]), span);
+                self.binds(bs);
+                self.end(0);
+            },
+            Expr::KeyRoot => { self.add(Node::Key(Key::Root), span); },
+            Expr::KeySys => { self.add(Node::Key(Key::Sys), span); },
+            Expr::KeyLib => { self.add(Node::Key(Key::Lib), span); },
+            Expr::KeyUp => { self.add(Node::Key(Key::Up), span); },
+            Expr::KeyMy => { self.add(Node::Key(Key::My), span); },
+            Expr::From(xs) => {
+                self.start(Node::From(vec![]), span);
+                for x in xs {
+                    self.expr(x);
+                }
+                self.end(0);
+            },
+            Expr::Call{function, arguments} => {
+                self.start(Node::Call{function: NodeId(0), arguments: vec![]}, span);
+                self.expr(*function);
+                self.binds(arguments);
+                self.end(0);
+            },
+            Expr::Import(s) => { self.add(Node::Import(s), span); },
+            Expr::Error(s) => { self.add(Node::Error(s), span); },
+        }
+    }
+}
+
+impl Ast {
+    // the arena of a tree from ast::parse; its spans are all default
+    pub fn from_expr(x: &Expr) -> Ast {
+        let mut b = Builder::new();
+        b.expr(x.clone());
+        b.ast
+    }
+
+    // parse straight into the arena, with spans; only a source without
+    // errors has one
+    pub fn parse(text: &str, max_depth: usize) -> Result<Ast, (Span, String)> {
+        let mut b = Builder::new();
+        let mut es = events::events(lex::tokens(text).map(|(span, t)| (span, Tok::from(t))), max_depth);
+        while let Some(e) = es.next() {
+            let span = es.span();
+            match e {
+                Event::StartStruct => b.start(Node::Struct(vec![]), span),
+                Event::StartColumn => b.start(Node::Column(vec![]), span),
+                Event::StartFrom => b.start(Node::From(vec![]), span),
+                Event::StartCall => b.start(Node::Call{function: NodeId(0), arguments: vec![]}, span),
+                Event::Bind(name) => b.bind(name),
+                Event::Literal(s) => { b.add(Node::Literal(s), span); },
+                Event::Key(k) => { b.add(Node::Key(k), span); },
+                Event::Import(s) => { b.add(Node::Import(s), span); },
+                Event::End => b.end(span.hi),
+                Event::Error(span, msg) => return Err((span, msg)),
+            }
+        }
+        Ok(b.ast)
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.index()]
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.parents[id.index()]
+    }
+
+    pub fn span(&self, id: NodeId) -> Span {
+        self.spans[id.index()]
+    }
+
+    // the struct @my stands for at a node, None at the root
+    pub fn my(&self, id: NodeId) -> Option<NodeId> {
+        self.mys[id.index()]
+    }
+
+    // the struct or column @up stands for at a node, None when there is no
+    // struct above @my
+    pub fn up(&self, id: NodeId) -> Option<NodeId> {
+        self.my(id).and_then(|m| self.parent(m))
+    }
+
+    // how many keys longer the path of a value at id is than one at above,
+    // an ancestor: a struct's binds, a column's elements and a call's
+    // arguments each add one, a @from's parts and a call's function none
+    pub fn keys_below(&self, above: NodeId, id: NodeId) -> usize {
+        let mut n = 0;
+        let mut at = id;
+        while at != above {
+            let p = match self.parent(at) {
+                Some(p) => p,
+                None => break,
+            };
+            match *self.node(p) {
+                Node::Struct(_) | Node::Column(_) => n += 1,
+                Node::Call{function, ..} if function != at => n += 1,
+                _ => {},
+            }
+            at = p;
+        }
+        n
+    }
+
+    // the path of the cell a node is in or is, e.g. hosts 0 name
+    pub fn path(&self, id: NodeId) -> Vec<String> {
+        let mut path = vec![];
+        let mut at = id;
+        while let Some(p) = self.parent(at) {
+            let slot = self.slots[at.index()] as usize;
+            match *self.node(p) {
+                Node::Struct(ref bs) => path.push(bs[slot].0.clone()),
+                Node::Column(_) => path.push(slot.to_string()),
+                // inside a @from or @call is no cell of its own
+                _ => path.clear(),
+            }
+            at = p;
+        }
+        path.reverse();
+        path
+    }
+
+    // the node at a cell's path; the last bind of a name counts
+    pub fn at(&self, path: &[String]) -> Option<NodeId> {
+        let mut id = self.root();
+        for k in path {
+            id = match *self.node(id) {
+                Node::Struct(ref bs) => bs.iter().rev().find(|b| b.0 == *k)?.1,
+                Node::Column(ref xs) => *xs.get(k.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(id)
+    }
+
+    // the tree under a node as an ast::Expr
+    pub fn expr(&self, id: NodeId) -> Expr {
+        let binds = |bs: &[(String, NodeId)]| bs.iter()
+            .map(|b| Bind{name: b.0.clone(), value: self.expr(b.1)}).collect();
+        match *self.node(id) {
+            Node::Literal(ref s) => Expr::Literal(s.clone()),
+            Node::Column(ref xs) => Expr::Column(xs.iter().map(|x| self.expr(*x)).collect()),
+            Node::Struct(ref bs) => Expr::Struct(binds(bs)),
+            Node::Key(Key::Root) => Expr::KeyRoot,
+            Node::Key(Key::Sys) => Expr::KeySys,
+            Node::Key(Key::Lib) => Expr::KeyLib,
+            Node::Key(Key::Up) => Expr::KeyUp,
+            Node::Key(Key::My) => Expr::KeyMy,
+            Node::Key(k) => Expr::Error(format!("@{} is not a value", k)),
+            Node::From(ref xs) => Expr::From(xs.iter().map(|x| self.expr(*x)).collect()),
+            Node::Call{function, ref arguments} =>
+                Expr::Call{function: Box::new(self.expr(function)), arguments: binds(arguments)},
+            Node::Import(ref s) => Expr::Import(s.clone()),
+            Node::Error(ref s) => Expr::Error(s.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+const SAMPLE: &str = "@struct {
+  @bind domain { 'example.com' }
+  @bind hosts { @column {
+    @struct { @bind name { 'web' } @bind d { @from @up { '0' 'name' } } }
+  } }
+  @bind id { @call @from @sys { 'text' 'reverse' } { @bind a { @from @my { 'domain' } } } }
+}";
+
+#[test]
+fn test_arena_round_trip() {
+    let x = ::parse_str(SAMPLE).expr().clone();
+    let ast = Ast::from_expr(&x);
+    assert_eq!(ast.expr(ast.root()), x);
+    assert_eq!(Ast::parse(SAMPLE, 64).unwrap().expr(NodeId(0)), x);
+    let name = ast.at(&["hosts".to_string(), "0".to_string(), "name".to_string()]).unwrap();
+    assert_eq!(ast.node(name), &Node::Literal("web".to_string()));
+    assert_eq!(ast.path(name), vec!["hosts", "0", "name"]);
+    assert_eq!(ast.at(&["nope".to_string()]), None);
+    for i in 1..ast.len() {
+        let id = NodeId(i as u32);
+        assert!(ast.parent(id).unwrap() < id);
+        assert_eq!(ast.at(&ast.path(id)).map(|c| c <= id), Some(true));
+    }
+}
+
+#[test]
+fn test_arena_scopes() {
+    let ast = Ast::parse(SAMPLE, 64).unwrap();
+    let keys: Vec<NodeId> = (0..ast.len() as u32).map(NodeId)
+        .filter(|id| *ast.node(*id) == Node::Key(Key::Up) || *ast.node(*id) == Node::Key(Key::My))
+        .collect();
+    assert_eq!(keys.len(), 2);
+    // @up in hosts.0.d is the column hosts; @my in id's argument is the root
+    assert_eq!(ast.path(ast.up(keys[0]).unwrap()), vec!["hosts"]);
+    assert_eq!(ast.my(keys[1]), Some(ast.root()));
+    assert_eq!(ast.path(keys[1]), vec!["id"]);
+    assert_eq!(ast.up(keys[1]), None);
+    // hosts 0 d, below hosts; id a, the call's argument, below the root
+    assert_eq!(ast.keys_below(ast.up(keys[0]).unwrap(), keys[0]), 2);
+    assert_eq!(ast.keys_below(ast.root(), keys[1]), 2);
+    let span = ast.span(ast.at(&["hosts".to_string()]).unwrap());
+    assert_eq!(&SAMPLE[span.lo..span.hi], "@column {
+    @struct { @bind name { 'web' } @bind d { @from @up { '0' 'name' } } }
+  }");
+    assert_eq!(Ast::parse("@column { 'x'", 64), Err((Span{lo: 13, hi: 13}, "Unexpected end of input".to_string())));
+}
+
+#[test]
+fn test_arena_side() {
+    let ast = Ast::from_expr(&Expr::Column(vec![Expr::Literal("a".to_string()), Expr::KeyRoot]));
+    let mut kinds: Side<&str> = Side::new(&ast);
+    kinds.set(NodeId(2), "key");
+    assert_eq!((kinds.get(NodeId(1)), kinds.get(NodeId(2))), (None, Some(&"key")));
+    assert_eq!(ast.len(), 3);
+}
@@ -1,4 +1,5 @@
 use lex;
+use std::mem;
 use std::vec;
 use std::fmt;
 
@@ -9,10 +10,12 @@ pub enum Expr {
     Struct(Vec<Bind>), // KeyStruct LCurl <Bind>* RCurl
     KeyRoot,
     KeySys,
+    KeyLib,
     KeyUp,
     KeyMy,
     From(Vec<Expr>), // KeyFrom <Expr> LCurl <Expr>_ RCurl
     Call{function:Box<Expr>, arguments:Vec<Bind>}, // KeyCall <Expr> LCurl <Bind>* RCurl
+    Import(String), // KeyImport Literal
     Error(String),
 }
 
@@ -24,10 +27,12 @@ impl fmt::Display for Expr {
             Expr::Struct(ref s) => write!(f, "str...({:?})",s), // FIXME: recur
             Expr::KeyRoot => write!(f, "@Root"),
             Expr::KeySys => write!(f, "@Sys"),
+            Expr::KeyLib => write!(f, "@Lib"),
             Expr::KeyUp => write!(f, "@Up"),
             Expr::KeyMy => write!(f, "@My"),
             Expr::From(ref v) => write!(f, "from({:?})", v), // FIXME: recur / no Debug
             Expr::Call{function: ref ftn, arguments: ref a} => write!(f, "call {:?} ( {:?} )", *ftn, a), // FIXME
+            Expr::Import(ref s) => write!(f, "import({})", s),
             Expr::Error(ref s) => write!(f, "err({})", s),
         }
     }
@@ -36,8 +41,8 @@ impl fmt::Display for Expr {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Bind { // KeyBind Literal LCurl Expr RCurl
-    name: String,
-    value: Expr,
+    pub name: String,
+    pub value: Expr,
 }
 
 impl fmt::Display for Bind {
@@ -46,33 +51,191 @@ impl fmt::Display for Bind {
     }
 }
 
+// where a @bind sits in the source, by its path from the root
+#[derive(Debug, PartialEq, Clone)]
+pub struct Site {
+    pub path: Vec<String>,
+    pub name: lex::Span,
+    pub whole: lex::Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    Path(Vec<String>), // a @from with a fixed path
+    Import(String),
+}
+
+// a piece of source that points somewhere else
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ref {
+    pub span: lex::Span,
+    pub target: Target,
+}
+
+// everything learned from parsing a source
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parsed {
+    pub expr: Expr,
+    pub errors: Vec<(lex::Span, String)>,
+    pub sites: Vec<Site>,
+    pub refs: Vec<Ref>,
+}
+
+// token stream for the parser, remembering where things happened
+pub struct Toks {
+    it: vec::IntoIter<(lex::Span, lex::Tok)>,
+    last: lex::Span,
+    errors: Vec<(lex::Span, String)>,
+    path: Vec<String>, // of the value being parsed
+    scope: Vec<String>, // of the struct around it, for @my
+    hidden: usize, // inside a @call or @from, binds have no path
+    sites: Vec<Site>,
+    refs: Vec<Ref>,
+    depth: usize, // expressions open around the one being parsed
+    max_depth: usize,
+    stopped: bool, // nested too deep; the rest reads as the end
+}
+
+impl Toks {
+    fn new(toks: Vec<(lex::Span, lex::Tok)>, max_depth: usize) -> Toks {
+        Toks{it: toks.into_iter(), last: lex::Span::default(), errors: vec![],
+            path: vec![], scope: vec![], hidden: 0, sites: vec![], refs: vec![],
+            depth: 0, max_depth, stopped: false}
+    }
+
+    fn next(&mut self) -> Option<lex::Tok> {
+        if self.stopped {
+            return None
+        }
+        let (span, tok) = self.it.next()?;
+        self.last = span;
+        Some(tok)
+    }
+
+    // record an error at the last token read
+    fn fail(&mut self, msg: String) -> Expr {
+        if !self.stopped {
+            self.errors.push((self.last, msg.clone()));
+        }
+        Expr::Error(msg)
+    }
+
+    fn since(&self, lo: usize) -> lex::Span {
+        lex::Span{lo, hi: self.last.hi}
+    }
 
+    // where a @from with only literal keys leads, if anywhere fixed
+    fn target(&self, xs: &[Expr]) -> Option<Vec<String>> {
+        let mut path = match xs.first() {
+            Some(&Expr::KeyRoot) => vec![],
+            Some(&Expr::KeyMy) => self.scope.clone(),
+            Some(&Expr::KeyUp) if !self.scope.is_empty() => self.scope[..self.scope.len() - 1].to_vec(),
+            _ => return None,
+        };
+        for x in &xs[1..] {
+            match *x {
+                Expr::Literal(ref k) => path.push(k.clone()),
+                _ => return None,
+            }
+        }
+        Some(path)
+    }
+}
+
+#[cfg(test)]
 pub fn parse(toks: Vec<lex::Tok>) -> Expr {
-    let mut it = toks.into_iter();
+    let spanned = toks.into_iter().map(|t| (lex::Span::default(), t)).collect();
+    let mut it = Toks::new(spanned, usize::MAX);
     parse_expr(None, &mut it)
 }
 
+// parse a whole source: the expression, every lexer and parser error,
+// and where binds and references are; nesting past max_depth stops it
+pub fn parse_spans(toks: Vec<(lex::Span, lex::Tok)>, max_depth: usize) -> Parsed {
+    let mut it = Toks::new(toks, max_depth);
+    let expr = parse_expr(None, &mut it);
+    if non_gray(&mut it).is_some() {
+        it.fail("Unexpected token after expression".to_string());
+    }
+    Parsed{expr, errors: it.errors, sites: it.sites, refs: it.refs}
+}
+
+// the expression at a path, as evaluation would find it
+pub fn find<'a>(x: &'a Expr, path: &[String]) -> Option<&'a Expr> {
+    match path.split_first() {
+        None => Some(x),
+        Some((k, rest)) => match *x {
+            Expr::Struct(ref binds) => binds.iter().rev().find(|b| &b.name == k)
+                .and_then(|b| find(&b.value, rest)),
+            Expr::Column(ref xs) => k.parse::<usize>().ok().and_then(|i| xs.get(i))
+                .and_then(|x| find(x, rest)),
+            _ => None,
+        },
+    }
+}
+
+// the expression written back as source on one line; layout::format lays
+// it out
+pub fn source(x: &Expr) -> String {
+    fn binds(bs: &[Bind]) -> String {
+        let bs: Vec<String> = bs.iter()
+            .map(|b| format!("@bind {} {{ {} }}", lex::quote_name(&b.name), source(&b.value)))
+            .collect();
+        braces(&bs)
+    }
+    fn braces(xs: &[String]) -> String {
+        if xs.is_empty() { "{}".to_string() } else { format!("{{ {} }}", xs.join(" ")) }
+    }
+    match *x {
+        Expr::Literal(ref s) => lex::quote(s),
+        Expr::Column(ref xs) => format!("@column {}", braces(&xs.iter().map(source).collect::<Vec<String>>())),
+        Expr::Struct(ref bs) => format!("@struct {}", binds(bs)),
+        Expr::KeyRoot => "@root".to_string(),
+        Expr::KeySys => "@sys".to_string(),
+        Expr::KeyLib => "@lib".to_string(),
+        Expr::KeyUp => "@up".to_string(),
+        Expr::KeyMy => "@my".to_string(),
+        Expr::From(ref xs) => format!("@from {} {}", source(&xs[0]),
+            braces(&xs[1..].iter().map(source).collect::<Vec<String>>())),
+        Expr::Call{ref function, ref arguments} => format!("@call {} {}", source(function), binds(arguments)),
+        Expr::Import(ref s) => format!("@import {}", lex::quote(s)),
+        Expr::Error(ref s) => format!("<error: {}>", s),
+    }
+}
+
 // skip whitespace and similar
-pub fn non_gray(it: &mut vec::IntoIter<lex::Tok>) -> Option<lex::Tok> {
-    let ot = it.next();
-    match ot {
-        Some(lex::Tok::Whitespace(_))
-        | Some(lex::Tok::Comment(_))
-        | Some(lex::Tok::Error(_))
-            => non_gray(it),
-        _ => ot
+pub fn non_gray(it: &mut Toks) -> Option<lex::Tok> {
+    loop {
+        match it.next() {
+            Some(lex::Tok::Whitespace(_))
+            | Some(lex::Tok::Comment(_))
+                => {},
+            Some(lex::Tok::Error(e)) => it.errors.push((it.last, e)),
+            ot => return ot
+        }
     }
 }
 
-fn parse_bind(it: &mut vec::IntoIter<lex::Tok>) -> Result<Bind, String> {
+fn parse_bind(it: &mut Toks) -> Result<Bind, String> {
+    let lo = it.last.lo;
     let ltok = non_gray(it);
+    let name_span = it.last;
     match ltok {
         Some(lex::Tok::Literal(name)) =>
             match non_gray(it) {
                 Some(lex::Tok::CurlL) => {
+                    it.path.push(name.clone());
                     let expr = parse_expr(None, it);
+                    let path = it.path.clone();
+                    it.path.pop();
                     match non_gray(it) {
-                        Some(lex::Tok::CurlR) => Ok(Bind{name:name, value:expr}),
+                        Some(lex::Tok::CurlR) => {
+                            if it.hidden == 0 {
+                                let whole = it.since(lo);
+                                it.sites.push(Site{path, name: name_span, whole});
+                            }
+                            Ok(Bind{name, value:expr})
+                        },
                         _ => Err("@bind must end with '}'".to_string()),
                     }
                 },
@@ -82,7 +245,7 @@ fn parse_bind(it: &mut vec::IntoIter<lex::Tok>) -> Result<Bind, String> {
     }
 }
 
-fn parse_binds(it: &mut vec::IntoIter<lex::Tok>) -> Result<(Vec<Bind>, Option<lex::Tok>), String> {
+fn parse_binds(it: &mut Toks) -> Result<(Vec<Bind>, Option<lex::Tok>), String> {
     let mut binds = vec![];
     let mut next_tok = non_gray(it);
     while let Some(lex::Tok::Key(lex::Key::Bind)) = next_tok {
@@ -97,7 +260,7 @@ fn parse_binds(it: &mut vec::IntoIter<lex::Tok>) -> Result<(Vec<Bind>, Option<le
     Ok((binds, next_tok))
 }
 
-fn parse_bind_list(it: &mut vec::IntoIter<lex::Tok>) -> Result<Vec<Bind>, String> {
+fn parse_bind_list(it: &mut Toks) -> Result<Vec<Bind>, String> {
     match non_gray(it) {
         Some(lex::Tok::CurlL) => {
             match parse_binds(it) {
@@ -113,70 +276,112 @@ fn parse_bind_list(it: &mut vec::IntoIter<lex::Tok>) -> Result<Vec<Bind>, String
     }
 }
 
-fn parse_expr(ofirst_tok: Option<lex::Tok>, it: &mut vec::IntoIter<lex::Tok>) -> Expr {
+fn parse_expr(ofirst_tok: Option<lex::Tok>, it: &mut Toks) -> Expr {
+    if it.depth >= it.max_depth {
+        let x = it.fail(format!("Nested deeper than {} levels", it.max_depth));
+        it.stopped = true;
+        return x
+    }
+    it.depth += 1;
+    let x = parse_nested(ofirst_tok, it);
+    it.depth -= 1;
+    x
+}
+
+fn parse_nested(ofirst_tok: Option<lex::Tok>, it: &mut Toks) -> Expr {
     let first_tok = match ofirst_tok {
         Some(ft) => ft,
         None => match non_gray(it) {
             Some(ft) => ft,
-            None => return Expr::Error("Expected Expr, got EOF".to_string())
+            None => return it.fail("Expected Expr, got EOF".to_string())
         }
     };
     match first_tok {
-        lex::Tok::Key(lex::Key::Struct) =>
-            match parse_bind_list(it) {
+        lex::Tok::Key(lex::Key::Struct) => {
+            let scope = mem::replace(&mut it.scope, it.path.clone());
+            let rbinds = parse_bind_list(it);
+            it.scope = scope;
+            match rbinds {
                 Ok(binds) => Expr::Struct(binds),
-                Err(e) => Expr::Error(e),
-            },
+                Err(e) => it.fail(e),
+            }
+        },
         lex::Tok::Key(lex::Key::Column) => {
             let otok = non_gray(it);
             match otok {
                 Some(lex::Tok::CurlL) => {
-                    let (exprs, ttok) = parse_exprs(it);
+                    let (exprs, ttok) = parse_exprs(it, true);
                     match ttok {
                         Some(lex::Tok::CurlR) => Expr::Column(exprs),
-                        _ => Expr::Error("Column must end with '}'".to_string())
+                        _ => it.fail("Column must end with '}'".to_string())
                     }
                 },
-                _ => Expr::Error("@Column must be followed by '{'".to_string())
+                _ => it.fail("@Column must be followed by '{'".to_string())
             }
         },
         lex::Tok::Key(lex::Key::Root) => Expr::KeyRoot,
         lex::Tok::Key(lex::Key::Up) => Expr::KeyUp,
         lex::Tok::Key(lex::Key::Sys) => Expr::KeySys,
+        lex::Tok::Key(lex::Key::Lib) => Expr::KeyLib,
         lex::Tok::Key(lex::Key::My) => Expr::KeyMy,
         lex::Tok::Key(lex::Key::From) => {
+            let lo = it.last.lo;
+            it.hidden += 1;
             let otok1 = non_gray(it);
             let head_expr = parse_expr(otok1, it);
             let otok2 = non_gray(it);
-            match otok2 {
-                Some(lex::Tok::CurlL) => {
-                    let (tail_exprs, ttok) = parse_exprs(it);
+            let tail = match otok2 {
+                Some(lex::Tok::CurlL) => Some(parse_exprs(it, false)),
+                _ => None,
+            };
+            it.hidden -= 1;
+            match tail {
+                Some((tail_exprs, ttok)) => {
                     match ttok {
                         Some(lex::Tok::CurlR) => {
                             let mut all_exprs = Vec::with_capacity(1+tail_exprs.len());
                             all_exprs.push(head_expr);
                             all_exprs.extend(tail_exprs);
+                            if let Some(path) = it.target(&all_exprs) {
+                                let span = it.since(lo);
+                                it.refs.push(Ref{span, target: Target::Path(path)});
+                            }
                             Expr::From(all_exprs)
                         },
-                        _ => Expr::Error("@from must end with '}'".to_string()),
+                        _ => it.fail("@from must end with '}'".to_string()),
                     }
                 },
-                _ => Expr::Error("@from must have '{' after root struct".to_string()),
+                None => it.fail("@from must have '{' after root struct".to_string()),
             }
         },
         lex::Tok::Key(lex::Key::Call) => {
+            it.hidden += 1;
             let ftn = parse_expr(None, it);
-            match parse_bind_list(it) {
+            let rbinds = parse_bind_list(it);
+            it.hidden -= 1;
+            match rbinds {
                 Ok(binds) => Expr::Call{function:Box::new(ftn), arguments:binds},
-                Err(e) => Expr::Error(e),
+                Err(e) => it.fail(e),
+            }
+        },
+        lex::Tok::Key(lex::Key::Import) => {
+            let lo = it.last.lo;
+            match non_gray(it) {
+                Some(lex::Tok::Literal(s)) => {
+                    let span = it.since(lo);
+                    it.refs.push(Ref{span, target: Target::Import(s.clone())});
+                    Expr::Import(s)
+                },
+                _ => it.fail("@import must be followed by literal".to_string()),
             }
         },
         lex::Tok::Literal(s) => Expr::Literal(s),
-        _ => Expr::Error("Unexpected token".to_string())
+        _ => it.fail("Unexpected token".to_string())
     }
 }
 
-fn parse_exprs(it: &mut vec::IntoIter<lex::Tok>) -> (Vec<Expr>, Option<lex::Tok>) {
+// a column's elements get their index as a path; @from keys get none
+fn parse_exprs(it: &mut Toks, column: bool) -> (Vec<Expr>, Option<lex::Tok>) {
     let mut exprs: Vec<Expr> = vec![];
     loop {
         let otok = non_gray(it);
@@ -186,10 +391,22 @@ fn parse_exprs(it: &mut vec::IntoIter<lex::Tok>) -> (Vec<Expr>, Option<lex::Tok>
                     lex::Tok::Key(lex::Key::Struct)
                     | lex::Tok::Key(lex::Key::From)
                     | lex::Tok::Key(lex::Key::Column)
+                    | lex::Tok::Key(lex::Key::Call)
                     | lex::Tok::Key(lex::Key::My)
+                    | lex::Tok::Key(lex::Key::Up)
+                    | lex::Tok::Key(lex::Key::Root)
+                    | lex::Tok::Key(lex::Key::Sys)
+                    | lex::Tok::Key(lex::Key::Lib)
+                    | lex::Tok::Key(lex::Key::Import)
                     | lex::Tok::Literal(_) => {
                         //println!("parse_exprs literal/key: {:?}", t);
+                        if column {
+                            it.path.push(exprs.len().to_string());
+                        }
                         exprs.push(parse_expr(Some(t), it));
+                        if column {
+                            it.path.pop();
+                        }
                     },
                     _ => {
                         //println!("parse_exprs other: {:?}", t);
@@ -198,7 +415,7 @@ fn parse_exprs(it: &mut vec::IntoIter<lex::Tok>) -> (Vec<Expr>, Option<lex::Tok>
                 }
             },
             None => {
-                exprs.push(Expr::Error("Expected Expr, got EOF".to_string()));
+                exprs.push(it.fail("Expected Expr, got EOF".to_string()));
                 return (exprs, None);
             }
         }
@@ -206,21 +423,17 @@ fn parse_exprs(it: &mut vec::IntoIter<lex::Tok>) -> (Vec<Expr>, Option<lex::Tok>
 }
 
 #[cfg(test)]
-fn assert_no_lex_errors(toks: &Vec<lex::Tok>) {
+fn assert_no_lex_errors(toks: &[lex::Tok]) {
     for tok in toks {
-        match *tok {
-            lex::Tok::Error(_) => assert!(false),
-            _ => {}
+        if let lex::Tok::Error(ref e) = *tok {
+            panic!("lex error: {}", e)
         }
     }
 }
 
 #[cfg(test)]
 fn is_error(expr: &Expr) -> bool {
-    match expr {
-        &Expr::Error(_) => true,
-        _ => false,
-    }
+    matches!(*expr, Expr::Error(_))
 }
 
 #[cfg(test)]
@@ -251,6 +464,13 @@ fn test_column_some() {
         Expr::Column(vec![Expr::KeyMy, Expr::KeyMy, Expr::KeyMy]));
 }
 
+#[test]
+fn test_column_keys() {
+    assert_eq!(sparse("@column { @root @up @sys @lib @call @my {} }"),
+        Expr::Column(vec![Expr::KeyRoot, Expr::KeyUp, Expr::KeySys, Expr::KeyLib,
+            Expr::Call{function: Box::new(Expr::KeyMy), arguments: vec![]}]));
+}
+
 #[test]
 fn test_from_end() {
     let a = sparse("@from");
@@ -316,3 +536,96 @@ fn test_call_args() {
             ]
         })
 }
+
+#[test]
+fn test_spans_errors() {
+    let Parsed{expr, errors: errs, ..} = parse_spans(lex::lex_spans("@column { 'a' @bind }"), 64);
+    assert!(is_error(&expr));
+    assert_eq!(errs, vec![
+        (lex::Span{lo: 14, hi: 19}, "Column must end with '}'".to_string()),
+        (lex::Span{lo: 20, hi: 21}, "Unexpected token after expression".to_string()),
+    ]);
+}
+
+#[test]
+fn test_spans_lex_error() {
+    let Parsed{expr, errors: errs, ..} = parse_spans(lex::lex_spans("@column { 'a' _ }"), 64);
+    assert_eq!(expr, Expr::Column(vec![Expr::Literal("a".to_string())]));
+    assert_eq!(errs, vec![(lex::Span{lo: 14, hi: 15}, "Bad char: _".to_string())]);
+}
+
+#[test]
+fn test_spans_trailing() {
+    let errs = parse_spans(lex::lex_spans("'a' 'b'"), 64).errors;
+    assert_eq!(errs, vec![(lex::Span{lo: 4, hi: 7}, "Unexpected token after expression".to_string())]);
+}
+
+#[cfg(test)]
+fn spath(p: &[&str]) -> Vec<String> {
+    p.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_import() {
+    assert_eq!(sparse("@import 'a.bv'"), Expr::Import("a.bv".to_string()));
+    assert!(is_error(&sparse("@import @my")));
+}
+
+#[test]
+fn test_sites() {
+    let src = "@struct { @bind a { @column { @struct { @bind b { 'x' } } } }
+        @bind c { @call @my { @bind d { 'y' } } } }";
+    let sites: Vec<(Vec<String>, &str, &str)> = parse_spans(lex::lex_spans(src), 64).sites.iter()
+        .map(|s| (s.path.clone(), &src[s.name.lo..s.name.hi], &src[s.whole.lo..s.whole.hi]))
+        .collect();
+    assert_eq!(sites, vec![
+        (spath(&["a", "0", "b"]), "b", "@bind b { 'x' }"),
+        (spath(&["a"]), "a", "@bind a { @column { @struct { @bind b { 'x' } } } }"),
+        (spath(&["c"]), "c", "@bind c { @call @my { @bind d { 'y' } } }"),
+    ]);
+}
+
+#[test]
+fn test_refs() {
+    let src = "@struct { @bind s { @struct {
+        @bind a { @from @my { 'b' } }
+        @bind b { @from @up { 'x' '0' } }
+        @bind c { @from @root { @my } }
+        @bind d { @import 'd.bv' } } } }";
+    let refs: Vec<(&str, Target)> = parse_spans(lex::lex_spans(src), 64).refs.into_iter()
+        .map(|r| (&src[r.span.lo..r.span.hi], r.target))
+        .collect();
+    assert_eq!(refs, vec![
+        ("@from @my { 'b' }", Target::Path(spath(&["s", "b"]))),
+        ("@from @up { 'x' '0' }", Target::Path(spath(&["x", "0"]))),
+        ("@import 'd.bv'", Target::Import("d.bv".to_string())),
+    ]);
+}
+
+#[test]
+fn test_find() {
+    let x = sparse("@struct { @bind a { @column { 'p' 'q' } } @bind a { @column { 'r' } } }");
+    assert_eq!(find(&x, &spath(&["a", "0"])), Some(&Expr::Literal("r".to_string())));
+    assert_eq!(find(&x, &spath(&["a", "1"])), None);
+    assert_eq!(find(&x, &[]), Some(&x));
+}
+
+#[test]
+fn test_depth_limit() {
+    let deep = format!("{}'x'{}", "@column { ".repeat(100_000), " }".repeat(100_000));
+    let Parsed{expr, errors, ..} = parse_spans(lex::lex_spans(&deep), 200);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].1, "Nested deeper than 200 levels");
+    assert_eq!(errors[0].0, lex::Span{lo: 2000, hi: 2007});
+    assert!(is_error(&expr));
+}
+
+#[test]
+fn test_source_round_trip() {
+    let src = "@struct { @bind a { @column { 'x' n\"it's\" } } @bind 'b c' { @from @my { 'a' '0' } }
+        @bind d { @call @from @sys { 'text' 'reverse' } { @bind a { 'x' } } } @bind e { @import 'e.bv' }
+        @bind f { @struct {} } }";
+    let x = sparse(src);
+    assert_eq!(source(&Expr::Column(vec![])), "@column {}");
+    assert_eq!(sparse(&source(&x)), x);
+}
@@ -0,0 +1,249 @@
+// Cell values kept on disk between runs, a file for each document, itself
+// a .bv file. A cell is kept if it called a native; it is used again when
+// its expression is the same and every path it read has the value it had.
+// Values are told apart by a hash of how they print, FNV-1a so that it
+// stays the same from one build to the next.
+
+use ast;
+use fig::{Cell, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use {parse_file_with, Limits};
+
+const VERSION: &str = "2";
+
+// what a cell read, and the value it came to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub expr: String, // fingerprint of its expression
+    pub reads: Vec<(Vec<String>, String)>, // paths from the root, with fingerprints of their values
+    pub value: Value,
+}
+
+// a cell computed this run, before what it read is known to be computed
+#[derive(Debug, PartialEq)]
+pub struct Fresh {
+    pub expr: String,
+    pub reads: Vec<Vec<String>>,
+    pub value: Value,
+}
+
+// what a cell does while it is computed
+#[derive(Debug, Default, PartialEq)]
+pub struct Frame {
+    pub reads: Vec<Vec<String>>,
+    pub calls: bool, // called a native
+    pub imports: bool, // read another file, which no path here says
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Store {
+    pub dir: PathBuf,
+    file: PathBuf, // the document's, canonical
+    limits: Limits,
+    old: HashMap<Vec<String>, Entry>,
+    pub frames: Vec<Frame>, // for the cells being computed, innermost last
+    pub fresh: HashMap<Vec<String>, Fresh>,
+    pub used: HashSet<Vec<String>>, // kept values given back this time
+}
+
+fn hash(bytes: &[u8]) -> String {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h = (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", h)
+}
+
+// the fingerprint of an expression, by its source
+pub fn expr_print(x: &Option<ast::Expr>) -> String {
+    match *x {
+        Some(ref x) => hash(ast::source(x).as_bytes()),
+        None => hash(b""),
+    }
+}
+
+// the fingerprint of a value, once every cell in it is computed
+pub fn value_print(v: &Value) -> Option<String> {
+    if complete(v) { Some(hash(v.to_string().as_bytes())) } else { None }
+}
+
+fn complete(v: &Value) -> bool {
+    match *v {
+        Value::Sheet(ref s) => s.values().all(|c| c.value().as_ref().is_some_and(complete)),
+        Value::Column(ref xs) => xs.iter().all(complete),
+        _ => true,
+    }
+}
+
+// text, or columns of it: what a cell can be given back without its cells
+// going stale
+pub fn keeps(v: &Value) -> bool {
+    match *v {
+        Value::Text(_) => true,
+        Value::Column(ref xs) => xs.iter().all(keeps),
+        _ => false,
+    }
+}
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+fn path_value(p: &[String]) -> Value {
+    Value::Column(p.iter().map(|k| text(k)).collect())
+}
+
+fn record(fields: Vec<(&str, Value)>) -> Value {
+    Value::Sheet(fields.into_iter().map(|(k, v)| (k.to_string(), Cell::new(v))).collect::<HashMap<_, _>>().into())
+}
+
+fn field(v: &Value, name: &str) -> Option<Value> {
+    match *v {
+        Value::Sheet(ref s) => s.get(name)?.value(),
+        _ => None,
+    }
+}
+
+fn as_text(v: Option<Value>) -> Option<String> {
+    match v {
+        Some(Value::Text(t)) => Some(t),
+        _ => None,
+    }
+}
+
+fn as_column(v: Option<Value>) -> Option<Vec<Value>> {
+    match v {
+        Some(Value::Column(xs)) => Some(xs.to_vec()),
+        _ => None,
+    }
+}
+
+fn as_path(v: Option<Value>) -> Option<Vec<String>> {
+    as_column(v)?.into_iter().map(|k| as_text(Some(k))).collect()
+}
+
+fn entry(v: &Value) -> Option<(Vec<String>, Entry)> {
+    let reads = as_column(field(v, "reads"))?.iter()
+        .map(|r| Some((as_path(field(r, "path"))?, as_text(field(r, "value"))?)))
+        .collect::<Option<Vec<_>>>()?;
+    let value = field(v, "value")?;
+    if !keeps(&value) {
+        return None
+    }
+    Some((as_path(field(v, "path"))?, Entry{expr: as_text(field(v, "expr"))?, reads, value}))
+}
+
+impl Store {
+    // what was kept for file in dir; an unreadable or out of date file keeps nothing
+    pub fn open(dir: &Path, file: &Path, limits: &Limits) -> Store {
+        let limits = Limits{depth: limits.depth + 8, steps: usize::MAX, ..limits.clone()};
+        let mut s = Store{dir: dir.to_path_buf(), file: file.to_path_buf(), limits, old: HashMap::new(),
+            frames: vec![], fresh: HashMap::new(), used: HashSet::new()};
+        s.old = s.load().unwrap_or_default();
+        s
+    }
+
+    fn name(&self) -> PathBuf {
+        self.dir.join(format!("{}.bv", hash(self.file.to_string_lossy().as_bytes())))
+    }
+
+    fn load(&self) -> Option<HashMap<Vec<String>, Entry>> {
+        let doc = parse_file_with(self.name(), &self.limits).ok()?;
+        if !doc.diagnostics().is_empty() {
+            return None
+        }
+        let v = doc.env().eval();
+        if as_text(field(&v, "version"))? != VERSION || as_text(field(&v, "file"))? != self.file.to_string_lossy() {
+            return None
+        }
+        as_column(field(&v, "cells"))?.iter().map(entry).collect()
+    }
+
+    pub fn get(&self, path: &[String]) -> Option<&Entry> {
+        self.old.get(path)
+    }
+
+    // write entries, and what was kept before for cells this run used or
+    // did not reach; the others were computed again and changed
+    pub fn save<F: Fn(&[String]) -> bool>(&self, entries: Vec<(Vec<String>, Entry)>, reached: F) -> io::Result<()> {
+        let mut all: HashMap<Vec<String>, Entry> = self.old.iter()
+            .filter(|(p, _)| self.used.contains(*p) || !reached(p))
+            .map(|(p, e)| (p.clone(), e.clone()))
+            .collect();
+        all.extend(entries);
+        let mut paths: Vec<&Vec<String>> = all.keys().collect();
+        paths.sort();
+        let cells = paths.into_iter().map(|p| {
+            let e = &all[p];
+            let reads = e.reads.iter()
+                .map(|(r, print)| record(vec![("path", path_value(r)), ("value", text(print))]))
+                .collect();
+            record(vec![("path", path_value(p)), ("expr", text(&e.expr)), ("reads", Value::Column(reads)),
+                ("value", e.value.clone())])
+        }).collect();
+        let v = record(vec![("version", text(VERSION)), ("file", text(&self.file.to_string_lossy())),
+            ("cells", Value::Column(cells))]);
+        fs::create_dir_all(&self.dir)?;
+        let name = self.name();
+        let part = name.with_extension("part");
+        fs::write(&part, format!("{}\n", v))?;
+        fs::rename(part, name)
+    }
+}
+
+#[cfg(test)]
+fn cached_run(file: &Path, dir: &Path) -> (String, usize) {
+    let env = ::parse_file(file).unwrap().env();
+    env.cache(dir);
+    let v = env.eval().to_string();
+    env.save_cache().unwrap();
+    (v, env.steps())
+}
+
+#[test]
+fn test_cache_hash() {
+    // written to disk, so it must not change
+    assert_eq!(hash(b""), "cbf29ce484222325");
+    assert_eq!(hash(b"a"), "af63dc4c8601ec8c");
+    assert_eq!(hash(b"foobar"), "85944171f73967e8");
+}
+
+#[test]
+fn test_cache_reuse() {
+    let dir = ::std::env::temp_dir().join(format!("val-cache-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let rev = "@call @from @sys { 'text' 'reverse' }";
+    let lib = |name: &str| format!("@struct {{ @bind name {{ '{}' }} @bind rev {{ {} {{ @bind a {{ @from @my {{ 'name' }} }} }} }} }}",
+        name, rev);
+    fs::write(dir.join("lib.bv"), lib("web")).unwrap();
+    fs::write(dir.join("main.bv"), format!("@struct {{ @bind lib {{ @import 'lib.bv' }}
+        @bind id {{ {} {{ @bind a {{ @from @root {{ 'lib' 'rev' }} }} }} }}
+        @bind same {{ {} {{ @bind a {{ 'abc' }} }} }} }}", rev, rev)).unwrap();
+    let main = dir.join("main.bv");
+    let cache = dir.join("cache");
+    let (first, steps) = cached_run(&main, &cache);
+    assert!(first.contains("@bind id { 'web' }"), "{}", first);
+    assert_eq!(steps, 7);
+    // id, same and lib's rev are not computed again
+    assert_eq!(cached_run(&main, &cache), (first.clone(), 4));
+    // an edit to the imported file recomputes what read it, and only that
+    fs::write(dir.join("lib.bv"), lib("db")).unwrap();
+    let (third, steps) = cached_run(&main, &cache);
+    assert!(third.contains("@bind id { 'db' }"), "{}", third);
+    assert_eq!(steps, 6);
+    // a changed expression is computed again
+    fs::write(dir.join("main.bv"), format!("@struct {{ @bind same {{ {} {{ @bind a {{ 'abcd' }} }} }} }}", rev)).unwrap();
+    assert_eq!(cached_run(&main, &cache), ("@struct {\n  @bind same { 'dcba' }\n}".to_string(), 2));
+    // what same was before is kept no longer
+    let kept = fs::read_dir(&cache).unwrap().map(|f| fs::read_to_string(f.unwrap().path()).unwrap()).collect::<String>();
+    assert!(kept.contains("'dcba'") && !kept.contains("'cba'"), "{}", kept);
+    // and a cache it cannot read is no cache
+    for f in fs::read_dir(&cache).unwrap() {
+        fs::write(f.unwrap().path(), "@struct { _").unwrap();
+    }
+    assert_eq!(cached_run(&main, &cache).1, 2);
+}
@@ -0,0 +1,147 @@
+// Generated documents of a chosen shape and size, for benchmarks and for
+// trying the tools on more than the samples.
+
+use std::fmt::Write;
+use fig::Value;
+use Limits;
+
+pub const SHAPES: &[&str] = &["deep", "wide", "long", "escaped", "mixed", "shared"];
+
+// the document of a shape with n of what the shape has many of
+pub fn generate(shape: &str, n: usize) -> Option<String> {
+    match shape {
+        "deep" => Some(deep(n)),
+        "wide" => Some(wide(n)),
+        "long" => Some(long(n)),
+        "escaped" => Some(escaped(n)),
+        "mixed" => Some(mixed(n)),
+        "shared" => Some(shared(n)),
+        _ => None,
+    }
+}
+
+// the sizes benchmarks use; deep documents stay within what the recursive
+// parser's stack allows
+pub fn sizes(shape: &str) -> [usize; 2] {
+    match shape {
+        "deep" => [16, 64],
+        "mixed" => [100, 1_000],
+        _ => [1_000, 10_000],
+    }
+}
+
+// room for the documents of those sizes, the longest chain of reads
+// included, so what they compute is values and not errors
+pub fn limits() -> Limits {
+    Limits{depth: 1_000, steps: 100_000_000, calls: 100_000, ..Limits::default()}
+}
+
+// whether an error is anywhere in v
+pub fn has_error(v: &Value) -> bool {
+    match *v {
+        Value::Err(_) => true,
+        Value::Column(ref xs) => xs.iter().any(has_error),
+        Value::Sheet(ref s) => s.values().any(|c| c.value().is_none_or(|v| has_error(&v))),
+        _ => false,
+    }
+}
+
+// structs n deep, each level below the top reading the one above
+pub fn deep(n: usize) -> String {
+    let mut s = String::new();
+    for i in 0..n {
+        let _ = write!(s, "@struct {{\n{:w$}@bind v {{ 'level{}' }}\n", "", i, w = i + 1);
+        if i > 0 {
+            let _ = writeln!(s, "{:w$}@bind up {{ @from @up {{ 'v' }} }}", "", w = i + 1);
+        }
+        let _ = write!(s, "{:w$}@bind d {{ ", "", w = i + 1);
+    }
+    s.push_str("'bottom'");
+    for i in (0..n).rev() {
+        let _ = write!(s, " }}\n{:w$}}}", "", w = i);
+    }
+    s.push('\n');
+    s
+}
+
+// one struct of n binds, each but the first reading the one before
+pub fn wide(n: usize) -> String {
+    let mut s = String::from("@struct {\n  @bind k0 { 'first' }\n");
+    for i in 1..n {
+        let _ = writeln!(s, "  @bind k{} {{ @from @my {{ 'k{}' }} }}", i, i - 1);
+    }
+    s.push_str("}\n");
+    s
+}
+
+// a column of n literals
+pub fn long(n: usize) -> String {
+    let mut s = String::from("@column {\n");
+    for i in 0..n {
+        let _ = writeln!(s, "  'item{}'", i);
+    }
+    s.push_str("}\n");
+    s
+}
+
+// a column of n literals thick with escapes
+pub fn escaped(n: usize) -> String {
+    let mut s = String::from("@column {\n");
+    for i in 0..n {
+        let _ = writeln!(s, "  'a\\tb\\\\c\\nd{}\\0' n\"it's \\\"{}\\\"\"", i, i);
+    }
+    s.push_str("}\n");
+    s
+}
+
+// n hosts as a config might list them, with comments and @sys calls
+pub fn mixed(n: usize) -> String {
+    let mut s = String::from("# generated\n@struct {\n  @bind domain { 'example.com' }\n  @bind hosts { @column {\n");
+    for i in 0..n {
+        let _ = writeln!(s, "    @struct {{ # host {}\n      @bind name {{ 'web{}' }}\n      \
+            @bind port {{ '{}' }}\n      @bind domain {{ @from @root {{ 'domain' }} }}\n      \
+            @bind id {{ @call @from @sys {{ 'text' 'reverse' }} {{ @bind a {{ @from @my {{ 'name' }} }} }} }}\n    }}",
+            i, i, 8000 + i % 1000);
+    }
+    s.push_str("  } }\n}\n");
+    s
+}
+
+// a column of n structs and a hundred cells that each read all of it
+pub fn shared(n: usize) -> String {
+    let mut s = String::from("@struct {\n  @bind data { @column {\n");
+    for i in 0..n {
+        let _ = writeln!(s, "    @struct {{ @bind id {{ '{}' }} @bind tags {{ @column {{ 'a' 'b' }} }} }}", i);
+    }
+    s.push_str("  } }\n");
+    for i in 0..100 {
+        let _ = writeln!(s, "  @bind copy{} {{ @from @my {{ 'data' }} }}", i);
+    }
+    s.push_str("}\n");
+    s
+}
+
+#[test]
+fn test_corpus_parses() {
+    for shape in SHAPES {
+        let text = generate(shape, 20).unwrap();
+        let doc = ::parse_str(&text);
+        assert_eq!(doc.diagnostics(), &[], "{}", shape);
+        let v = doc.env().eval();
+        assert!(!format!("{}", v).contains("<error"), "{}: {}", shape, v);
+    }
+    assert_eq!(generate("round", 1), None);
+}
+
+#[test]
+fn test_corpus_bench_sizes() {
+    // a long chain of reads is computed a cell inside another, and unoptimized
+    // frames are big
+    let run = || for shape in SHAPES {
+        let n = sizes(shape)[1];
+        let doc = ::parse_str_with(&generate(shape, n).unwrap(), &limits());
+        assert_eq!(doc.diagnostics(), &[], "{} {}", shape, n);
+        assert!(!has_error(&doc.env().eval()), "{} {}", shape, n);
+    };
+    ::std::thread::Builder::new().stack_size(256 << 20).spawn(run).unwrap().join().unwrap();
+}
@@ -0,0 +1,153 @@
+// Stepping through an evaluation: it stops where a cell with a given @bind
+// name starts, or at every step, and shows what is computed so far.
+
+use fig::{path_name, Env, Trace};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use Document;
+
+const HELP: &str = "step, s         run to the next step
+continue, c     run to the next breakpoint
+break NAME      stop where a cell bound to NAME starts
+delete NAME     stop there no longer
+where, w        the cells being computed, innermost last
+print [PATH]    the value at PATH so far, e.g. hosts.0.name
+help            this text
+quit, q         run to the end without stopping";
+
+// what the tracer needs between stops
+struct Session<R, W> {
+    input: R,
+    out: W,
+    prompt: bool,
+    breaks: HashSet<String>,
+    stepping: bool, // stop at the next step, breakpoint or not
+    done: bool, // quit, or out of input
+    error: Option<io::Error>,
+}
+
+// evaluate doc, stopping where a cell bound to one of breaks starts, or at
+// the first step if there are none; the value is written last
+pub fn run<R: BufRead + 'static, W: Write + 'static>(doc: &Document, breaks: &[String], input: R, out: W,
+        prompt: bool) -> io::Result<W> {
+    let s = Rc::new(RefCell::new(Session{input, out, prompt, breaks: breaks.iter().cloned().collect(),
+        stepping: breaks.is_empty(), done: false, error: None}));
+    let v = {
+        let env = doc.env();
+        let t = s.clone();
+        env.trace(move |step, e| t.borrow_mut().stop(step, e));
+        env.eval()
+    };
+    let mut s = match Rc::try_unwrap(s) {
+        Ok(s) => s.into_inner(),
+        Err(_) => return Err(io::Error::other("evaluation still traced")),
+    };
+    if let Some(err) = s.error.take() {
+        return Err(err)
+    }
+    writeln!(s.out, "{}", v)?;
+    Ok(s.out)
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    fn stop(&mut self, step: &Trace, e: &Env) {
+        if self.done || self.error.is_some() {
+            return
+        }
+        let hit = match *step {
+            Trace::Start(ref p) => p.last().is_some_and(|k| self.breaks.contains(k)),
+            _ => false,
+        };
+        if self.stepping || hit {
+            if let Err(err) = self.commands(step, e) {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    // show the step and take commands until one runs on
+    fn commands(&mut self, step: &Trace, e: &Env) -> io::Result<()> {
+        writeln!(self.out, "{}{}", "  ".repeat(e.computing().len()), step)?;
+        loop {
+            if self.prompt {
+                write!(self.out, "(debug) ")?;
+                self.out.flush()?;
+            }
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                self.done = true;
+                return Ok(())
+            }
+            let line = line.trim();
+            let (cmd, arg) = match line.find(char::is_whitespace) {
+                Some(i) => (&line[..i], line[i..].trim()),
+                None => (line, ""),
+            };
+            match cmd {
+                "" => {},
+                "step" | "s" => {
+                    self.stepping = true;
+                    return Ok(())
+                },
+                "continue" | "c" => {
+                    self.stepping = false;
+                    return Ok(())
+                },
+                "break" | "b" if !arg.is_empty() => {
+                    self.breaks.insert(arg.to_string());
+                },
+                "delete" | "d" if !arg.is_empty() => {
+                    self.breaks.remove(arg);
+                },
+                "where" | "w" => for p in e.computing() {
+                    writeln!(self.out, "  {}", path_name(&p))?;
+                },
+                "print" | "p" => {
+                    let keys: Vec<&str> = if arg.is_empty() { vec![] } else { arg.split('.').collect() };
+                    match e.computed(&keys) {
+                        Some(v) => writeln!(self.out, "{}", v)?,
+                        None => {
+                            let path: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+                            writeln!(self.out, "{} not computed yet", path_name(&path))?
+                        },
+                    }
+                },
+                "help" | "h" => writeln!(self.out, "{}", HELP)?,
+                "quit" | "q" => {
+                    self.done = true;
+                    return Ok(())
+                },
+                _ => writeln!(self.out, "unknown command {} (try help)", line)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn session(doc: &str, breaks: &[&str], input: &'static str) -> String {
+    let breaks: Vec<String> = breaks.iter().map(|b| b.to_string()).collect();
+    let out = run(&::parse_str(doc), &breaks, input.as_bytes(), vec![], false).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_debug_break() {
+    let doc = "@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } }";
+    assert_eq!(session(doc, &["b"], "where\nprint a\nstep\nstep\nprint\nc\n"),
+        "start root.b\n'x'\n  read root.a\ndone root.b 'x'\n\
+        @struct {\n  @bind a { 'x' }\n  @bind b { <in progress> }\n}\n\
+        @struct {\n  @bind a { 'x' }\n  @bind b { 'x' }\n}\n");
+}
+
+#[test]
+fn test_debug_steps() {
+    let doc = "@column { 'x' }";
+    assert_eq!(session(doc, &[], "s\nnope\nbreak\nq\n"),
+        "start root\ndone root column of 1\nunknown command nope (try help)\nunknown command break (try help)\n\
+        @column {\n  'x'\n}\n");
+    // out of input runs to the end
+    assert_eq!(session(doc, &[], ""), "start root\n@column {\n  'x'\n}\n");
+}
@@ -0,0 +1,416 @@
+// Which cells read which, found from the syntax tree alone.
+
+use ast::Expr;
+use fig::path_name;
+use json::{self, Json};
+use lex;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Where a reference leads.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Dest {
+    // a place in the document, e.g. root.hosts.0
+    Path(Vec<String>),
+    // a place that is not there, or cannot be known before evaluation, and why
+    Missing(Vec<String>, String),
+    // something outside the document: @sys.x, @lib.x, or @import 'f'
+    Outside(String),
+}
+
+// One reference made while computing a cell.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edge {
+    pub from: Vec<String>,
+    pub to: Dest,
+}
+
+// The cells of a document, with what each one reads.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Graph {
+    // every @bind, and the root, in document order
+    pub cells: Vec<Vec<String>>,
+    pub edges: Vec<Edge>,
+    // cells that read each other in a ring, each in document order
+    pub cycles: Vec<Vec<Vec<String>>>,
+}
+
+struct Walk {
+    places: HashMap<Vec<String>, bool>, // every path ast::find reaches, and whether only evaluation knows what is in it
+    cells: Vec<Vec<String>>,
+    edges: Vec<Edge>,
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+fn parent(path: &[String]) -> &[String] {
+    &path[..path.len().saturating_sub(1)]
+}
+
+// the paths ast::find reaches below path, where x is
+fn places(x: &Expr, path: &mut Vec<String>, found: &mut HashMap<Vec<String>, bool>) {
+    found.insert(path.clone(), matches!(*x, Expr::From(_) | Expr::Call{..} | Expr::Import(_)));
+    match *x {
+        Expr::Struct(ref binds) => {
+            // the last bind of a name is the one found
+            let last: HashMap<&str, &Expr> = binds.iter().map(|b| (&b.name[..], &b.value)).collect();
+            for (k, v) in last {
+                path.push(k.to_string());
+                places(v, path, found);
+                path.pop();
+            }
+        },
+        Expr::Column(ref xs) => for (i, x) in xs.iter().enumerate() {
+            path.push(i.to_string());
+            places(x, path, found);
+            path.pop();
+        },
+        _ => {},
+    }
+}
+
+impl Walk {
+    fn edge(&mut self, from: &[String], to: Dest) {
+        self.edges.push(Edge{from: from.to_vec(), to});
+    }
+
+    // as the evaluator would: `cell` is being computed, `here` is where x's
+    // value lives and `my` the struct around it
+    fn expr(&mut self, x: &Expr, cell: &[String], here: &[String], my: &[String]) {
+        match *x {
+            Expr::Literal(_) | Expr::Error(_) => {},
+            Expr::Column(ref xs) => for (i, x) in xs.iter().enumerate() {
+                self.expr(x, cell, &child(here, &i.to_string()), my)
+            },
+            Expr::Struct(ref binds) => for b in binds {
+                let p = child(here, &b.name);
+                self.cells.push(p.clone());
+                self.expr(&b.value, &p, &p, here)
+            },
+            Expr::KeyRoot | Expr::KeySys | Expr::KeyLib | Expr::KeyUp | Expr::KeyMy =>
+                self.from(x, &[], cell, my),
+            Expr::From(ref xs) => {
+                let mut keys = vec![];
+                for k in &xs[1..] {
+                    match *k {
+                        Expr::Literal(ref t) => keys.push(Some(t.clone())),
+                        _ => {
+                            self.expr(k, cell, here, my);
+                            keys.push(None)
+                        },
+                    }
+                }
+                self.from(&xs[0], &keys, cell, my)
+            },
+            Expr::Call{ref function, ref arguments} => {
+                for b in arguments {
+                    self.expr(&b.value, cell, &child(here, &b.name), my)
+                }
+                self.expr(function, cell, here, my)
+            },
+            Expr::Import(ref name) => self.edge(cell, Dest::Outside(format!("@import {}", lex::quote(name)))),
+        }
+    }
+
+    // a @from, with None for keys only known once evaluated
+    fn from(&mut self, head: &Expr, keys: &[Option<String>], cell: &[String], my: &[String]) {
+        let known: Vec<String> = keys.iter().take_while(|k| k.is_some()).flat_map(|k| k.clone()).collect();
+        let base = match *head {
+            Expr::KeyRoot => vec![],
+            Expr::KeyMy => my.to_vec(),
+            Expr::KeyUp if my.is_empty() =>
+                return self.edge(cell, Dest::Missing(vec![], "@up has no struct above the root".to_string())),
+            Expr::KeyUp => parent(my).to_vec(),
+            Expr::KeySys | Expr::KeyLib => {
+                let mut name = if *head == Expr::KeySys { "@sys" } else { "@lib" }.to_string();
+                for k in &known {
+                    name.push('.');
+                    name.push_str(&lex::quote_name(k));
+                }
+                return self.edge(cell, Dest::Outside(name))
+            },
+            _ => return self.expr(head, cell, my, my),
+        };
+        let dynamic = known.len() < keys.len();
+        let path = [base, known].concat();
+        let to = if dynamic {
+            Dest::Missing(path, "computed key".to_string())
+        } else if self.places.contains_key(&path) {
+            Dest::Path(path)
+        } else {
+            // past a cell computed from elsewhere, e.g. a copy of a struct,
+            // it is the cell that is read
+            match (0..path.len()).rev().find(|&n| self.places.contains_key(&path[..n])) {
+                Some(n) if self.places[&path[..n]] => Dest::Path(path[..n].to_vec()),
+                _ => Dest::Missing(path, "not found".to_string()),
+            }
+        };
+        self.edge(cell, to)
+    }
+}
+
+// the cell holding a path: reading root.c.0 computes root.c
+fn owner(cells: &HashMap<&[String], usize>, path: &[String]) -> Option<usize> {
+    (0..=path.len()).rev().find_map(|n| cells.get(&path[..n]).cloned())
+}
+
+// strongly connected components, Tarjan's way
+struct Rings<'a> {
+    next: &'a [Vec<usize>],
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    count: usize,
+    found: Vec<Vec<usize>>,
+}
+
+impl<'a> Rings<'a> {
+    // depth first from v, with a stack of its own: a chain of cells may be
+    // longer than the thread's stack would allow
+    fn visit(&mut self, v: usize) {
+        self.open(v);
+        let mut calls = vec![(v, 0)];
+        while let Some(&(v, i)) = calls.last() {
+            match self.next[v].get(i) {
+                Some(&w) => {
+                    let n = calls.len();
+                    calls[n - 1].1 += 1;
+                    match self.index[w] {
+                        None => {
+                            self.open(w);
+                            calls.push((w, 0));
+                        },
+                        Some(j) if self.on_stack[w] => self.low[v] = self.low[v].min(j),
+                        Some(_) => {},
+                    }
+                },
+                None => {
+                    calls.pop();
+                    if let Some(&(u, _)) = calls.last() {
+                        self.low[u] = self.low[u].min(self.low[v]);
+                    }
+                    self.close(v);
+                },
+            }
+        }
+    }
+
+    fn open(&mut self, v: usize) {
+        self.index[v] = Some(self.count);
+        self.low[v] = self.count;
+        self.count += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+    }
+
+    // all v reaches is done: v may be the first of a ring
+    fn close(&mut self, v: usize) {
+        if Some(self.low[v]) == self.index[v] {
+            let mut ring = vec![];
+            while let Some(w) = self.stack.pop() {
+                self.on_stack[w] = false;
+                ring.push(w);
+                if w == v {
+                    break
+                }
+            }
+            if ring.len() > 1 || self.next[v].contains(&v) {
+                ring.sort();
+                self.found.push(ring);
+            }
+        }
+    }
+}
+
+fn cycles(cells: &[Vec<String>], edges: &[Edge]) -> Vec<Vec<Vec<String>>> {
+    let at: HashMap<&[String], usize> = cells.iter().enumerate().map(|(i, c)| (&c[..], i)).collect();
+    let mut next = vec![vec![]; cells.len()];
+    for e in edges {
+        if let Dest::Path(ref p) = e.to {
+            if let (Some(&a), Some(b)) = (at.get(&e.from[..]), owner(&at, p)) {
+                next[a].push(b);
+            }
+        }
+    }
+    let n = cells.len();
+    let mut r = Rings{next: &next, index: vec![None; n], low: vec![0; n], stack: vec![],
+        on_stack: vec![false; n], count: 0, found: vec![]};
+    for v in 0..n {
+        if r.index[v].is_none() {
+            r.visit(v);
+        }
+    }
+    let mut found = r.found;
+    found.sort();
+    found.into_iter().map(|ring| ring.into_iter().map(|i| cells[i].clone()).collect()).collect()
+}
+
+// The dependency graph of a parsed document.
+pub fn graph(x: &Expr) -> Graph {
+    let mut found = HashMap::new();
+    places(x, &mut vec![], &mut found);
+    let mut w = Walk{places: found, cells: vec![vec![]], edges: vec![]};
+    w.expr(x, &[], &[], &[]);
+    let cycles = cycles(&w.cells, &w.edges);
+    Graph{cells: w.cells, edges: w.edges, cycles}
+}
+
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Dest {
+    pub fn name(&self) -> String {
+        match *self {
+            Dest::Path(ref p) | Dest::Missing(ref p, _) => path_name(p),
+            Dest::Outside(ref s) => s.clone(),
+        }
+    }
+}
+
+impl Graph {
+    // Graphviz; unresolved references and cycles are drawn in red.
+    pub fn dot(&self) -> String {
+        let ring: HashMap<&[String], usize> = self.cycles.iter().enumerate()
+            .flat_map(|(i, r)| r.iter().map(move |c| (&c[..], i)))
+            .collect();
+        // b is in a's ring, or is inside a cell that is
+        let same_cycle = |a: &[String], b: &[String]| match ring.get(a) {
+            Some(i) => (0..=b.len()).any(|n| ring.get(&b[..n]) == Some(i)),
+            None => false,
+        };
+        let mut out = "digraph deps {\n".to_string();
+        for c in &self.cells {
+            let style = if ring.contains_key(&c[..]) { " [color=red]" } else { "" };
+            let _ = writeln!(out, "  {}{};", dot_id(&path_name(c)), style);
+        }
+        let mut outside: Vec<String> = vec![];
+        for e in &self.edges {
+            let to = dot_id(&e.to.name());
+            let style = match e.to {
+                Dest::Path(ref p) if same_cycle(&e.from, p) => " [color=red]".to_string(),
+                Dest::Path(_) => "".to_string(),
+                Dest::Missing(_, ref why) => {
+                    outside.push(format!("{} [color=red, style=dashed]", to));
+                    format!(" [color=red, style=dashed, label={}]", dot_id(why))
+                },
+                Dest::Outside(_) => {
+                    outside.push(format!("{} [shape=box]", to));
+                    "".to_string()
+                },
+            };
+            let _ = writeln!(out, "  {} -> {}{};", dot_id(&path_name(&e.from)), to, style);
+        }
+        outside.sort();
+        outside.dedup();
+        for o in outside {
+            let _ = writeln!(out, "  {};", o);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // {"cells": [...], "edges": [{"from", "to"}], "unresolved": [{"from", "to", "reason"}], "cycles": [[...]]}
+    pub fn json(&self) -> String {
+        let names = |ps: &[Vec<String>]| Json::Arr(ps.iter().map(|p| json::str(&path_name(p))).collect());
+        let mut edges = vec![];
+        let mut unresolved = vec![];
+        for e in &self.edges {
+            let from = json::str(&path_name(&e.from));
+            match e.to {
+                Dest::Missing(_, ref why) =>
+                    unresolved.push(json::obj(vec![("from", from), ("to", json::str(&e.to.name())), ("reason", json::str(why))])),
+                _ => edges.push(json::obj(vec![("from", from), ("to", json::str(&e.to.name()))])),
+            }
+        }
+        json::obj(vec![
+            ("cells", names(&self.cells)),
+            ("edges", Json::Arr(edges)),
+            ("unresolved", Json::Arr(unresolved)),
+            ("cycles", Json::Arr(self.cycles.iter().map(|r| names(r)).collect())),
+        ]).to_string()
+    }
+}
+
+#[cfg(test)]
+fn deps(s: &str) -> Graph {
+    graph(::parse_str(s).expr())
+}
+
+#[cfg(test)]
+fn names(g: &Graph) -> Vec<String> {
+    g.edges.iter().map(|e| format!("{} -> {}", path_name(&e.from), e.to.name())).collect()
+}
+
+#[test]
+fn test_deps_paths() {
+    let g = deps("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } }
+        @bind s { @struct { @bind c { @from @up { 'b' } } @bind d { @from @root { 's' 'c' } } } } }");
+    assert_eq!(names(&g), vec!["root.b -> root.a", "root.s.c -> root.b", "root.s.d -> root.s.c"]);
+    assert_eq!(g.cells.len(), 6);
+    assert!(g.cycles.is_empty());
+}
+
+#[test]
+fn test_deps_outside() {
+    let g = deps("@struct { @bind a { @call @from @sys { 'text' 'reverse' } { @bind a { @from @my { 'b' } } } }
+        @bind b { @import 'other.bv' } @bind c { @from @lib { 'x' } } }");
+    assert_eq!(names(&g), vec!["root.a -> root.b", "root.a -> @sys.text.reverse", "root.b -> @import 'other.bv'",
+        "root.c -> @lib.x"]);
+}
+
+#[test]
+fn test_deps_unresolved() {
+    let g = deps("@struct { @bind a { @from @my { 'nope' } } @bind b { @from @my { @from @my { 'k' } 'x' } }
+        @bind k { 'a' } @bind c { @up } }");
+    assert_eq!(g.edges[0].to, Dest::Missing(vec!["nope".to_string()], "not found".to_string()));
+    assert_eq!(g.edges[1].to, Dest::Path(vec!["k".to_string()]));
+    assert_eq!(g.edges[2].to, Dest::Missing(vec![], "computed key".to_string()));
+    assert_eq!(g.edges[3].to, Dest::Missing(vec![], "@up has no struct above the root".to_string()));
+}
+
+#[test]
+fn test_deps_cycles() {
+    let g = deps("@struct { @bind a { @from @my { 'b' 'x' } } @bind b { @struct { @bind x { @from @up { 'a' } } } }
+        @bind c { @from @my { 'c' } } @bind d { @column { @from @my { 'a' } } } }");
+    let ring = |ps: &[&[&str]]| ps.iter().map(|p| p.iter().map(|k| k.to_string()).collect()).collect::<Vec<Vec<String>>>();
+    assert_eq!(g.cycles, vec![ring(&[&["a"], &["b", "x"]]), ring(&[&["c"]])]);
+    assert!(g.dot().contains("\"root.a\" -> \"root.b.x\" [color=red];"));
+    assert!(g.dot().contains("\"root.d\" -> \"root.a\";"));
+}
+
+#[test]
+fn test_deps_aliases() {
+    let g = deps("@struct { @bind a { @struct { @bind x { '1' } } } @bind b { @from @my { 'a' } }
+        @bind c { @from @my { 'b' 'x' } } @bind d { @from @my { 'b' 'nope' 'y' } } @bind e { @from @my { 'a' 'nope' } } }");
+    assert_eq!(names(&g), vec!["root.b -> root.a", "root.c -> root.b", "root.d -> root.b", "root.e -> root.a.nope"]);
+    assert_eq!(g.edges[3].to, Dest::Missing(vec!["a".to_string(), "nope".to_string()], "not found".to_string()));
+    // a ring through a copy
+    let g = deps("@struct { @bind b { @from @my { 'c' } } @bind c { @from @my { 'b' 'x' } } }");
+    assert_eq!(names(&g), vec!["root.b -> root.c", "root.c -> root.b"]);
+    assert_eq!(g.cycles.len(), 1);
+}
+
+#[test]
+fn test_deps_long_chain() {
+    // each bind reads the one before, and the first the last: one ring
+    let mut text = ::corpus::wide(20_000);
+    text.insert_str(text.len() - 2, "  @bind k0 { @from @my { 'k19999' } }\n");
+    let g = deps(&text);
+    assert_eq!(g.edges.len(), 20_000);
+    assert!(g.edges.iter().all(|e| matches!(e.to, Dest::Path(_))));
+    assert_eq!(g.cycles.len(), 1);
+    assert_eq!(g.cycles[0].len(), 20_000);
+    assert!(g.dot().contains("\"root.k1\" -> \"root.k0\" [color=red];"));
+}
+
+#[test]
+fn test_deps_json() {
+    let g = deps("@struct { @bind a { @from @my { 'b' } } @bind b { @from @my { 'z' } } }");
+    assert_eq!(g.json(), r#"{"cells":["root","root.a","root.b"],"edges":[{"from":"root.a","to":"root.b"}],"unresolved":[{"from":"root.b","to":"root.z","reason":"not found"}],"cycles":[]}"#);
+}
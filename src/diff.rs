@@ -0,0 +1,215 @@
+// What changed between two documents, by path, from their syntax trees:
+// layout and comments make no difference.
+
+use ast::{self, Bind, Expr};
+use fig::path_name;
+use json::{self, Json};
+use std::fmt::Write;
+
+// One change. Column indices are as the changes apply in order, so each
+// path is good for the document as the changes before it left it.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    Added(Vec<String>, Expr),
+    Removed(Vec<String>, Expr),
+    Changed(Vec<String>, Expr, Expr),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+// the binds as evaluation sees them: the last of each name, in order
+fn last_binds(bs: &[Bind]) -> Vec<&Bind> {
+    bs.iter().enumerate()
+        .filter(|&(i, b)| !bs[i + 1..].iter().any(|c| c.name == b.name))
+        .map(|(_, b)| b)
+        .collect()
+}
+
+// past this many element pairs, columns are compared by position
+const MAX_ALIGN: usize = 1 << 22;
+
+enum Edit {
+    Keep,
+    Insert(usize), // from b
+    Delete(usize), // from a
+}
+
+// a longest common subsequence alignment of a onto b
+fn align(a: &[Expr], b: &[Expr]) -> Vec<Edit> {
+    let (n, m) = (a.len(), b.len());
+    let mut edits = vec![];
+    if n * m > MAX_ALIGN {
+        for i in 0..n.max(m) {
+            match (a.get(i), b.get(i)) {
+                (Some(x), Some(y)) if x == y => edits.push(Edit::Keep),
+                (Some(_), Some(_)) => {
+                    edits.push(Edit::Delete(i));
+                    edits.push(Edit::Insert(i));
+                },
+                (Some(_), None) => edits.push(Edit::Delete(i)),
+                _ => edits.push(Edit::Insert(i)),
+            }
+        }
+        return edits
+    }
+    // lcs[i][j]: the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            edits.push(Edit::Keep);
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            // deleting first pairs it with the insertion after, as a change
+            edits.push(Edit::Delete(i));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+fn walk(a: &Expr, b: &Expr, path: &[String], out: &mut Vec<Change>) {
+    match (a, b) {
+        _ if a == b => {},
+        (Expr::Struct(xs), Expr::Struct(ys)) => {
+            let (xs, ys) = (last_binds(xs), last_binds(ys));
+            for x in &xs {
+                match ys.iter().find(|y| y.name == x.name) {
+                    Some(y) => walk(&x.value, &y.value, &child(path, &x.name), out),
+                    None => out.push(Change::Removed(child(path, &x.name), x.value.clone())),
+                }
+            }
+            for y in ys.iter().filter(|y| !xs.iter().any(|x| x.name == y.name)) {
+                out.push(Change::Added(child(path, &y.name), y.value.clone()));
+            }
+        },
+        (Expr::Column(xs), Expr::Column(ys)) => {
+            let edits = align(xs, ys);
+            // where the next element stands as the changes so far leave it
+            let mut at = 0;
+            let mut k = 0;
+            while k < edits.len() {
+                match edits[k] {
+                    Edit::Keep => at += 1,
+                    // a deletion just before an insertion is the element changing
+                    Edit::Delete(i) => match edits.get(k + 1) {
+                        Some(&Edit::Insert(j)) => {
+                            walk(&xs[i], &ys[j], &child(path, &at.to_string()), out);
+                            at += 1;
+                            k += 1;
+                        },
+                        _ => out.push(Change::Removed(child(path, &at.to_string()), xs[i].clone())),
+                    },
+                    Edit::Insert(j) => {
+                        out.push(Change::Added(child(path, &at.to_string()), ys[j].clone()));
+                        at += 1;
+                    },
+                }
+                k += 1;
+            }
+        },
+        _ => out.push(Change::Changed(path.to_vec(), a.clone(), b.clone())),
+    }
+}
+
+pub fn diff(a: &Expr, b: &Expr) -> Diff {
+    let mut changes = vec![];
+    walk(a, b, &[], &mut changes);
+    Diff{changes}
+}
+
+// literals as strings, columns as arrays and structs as objects; other
+// expressions as their source
+fn to_json(x: &Expr) -> Json {
+    match *x {
+        Expr::Literal(ref s) => json::str(s),
+        Expr::Column(ref xs) => Json::Arr(xs.iter().map(to_json).collect()),
+        Expr::Struct(ref bs) => Json::Obj(last_binds(bs).iter().map(|b| (b.name.clone(), to_json(&b.value))).collect()),
+        _ => json::str(&ast::source(x)),
+    }
+}
+
+// e.g. /hosts/0/name, with ~ and / escaped as ~0 and ~1
+fn pointer(path: &[String]) -> String {
+    path.iter().map(|k| format!("/{}", k.replace('~', "~0").replace('/', "~1"))).collect()
+}
+
+impl Diff {
+    // one line per change: + added, - removed, ~ changed
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        for c in &self.changes {
+            let _ = match *c {
+                Change::Added(ref p, ref x) => writeln!(out, "+ {}: {}", path_name(p), ast::source(x)),
+                Change::Removed(ref p, ref x) => writeln!(out, "- {}: {}", path_name(p), ast::source(x)),
+                Change::Changed(ref p, ref x, ref y) =>
+                    writeln!(out, "~ {}: {} -> {}", path_name(p), ast::source(x), ast::source(y)),
+            };
+        }
+        out
+    }
+
+    // as a JSON patch (RFC 6902) on the documents as JSON
+    pub fn json(&self) -> String {
+        Json::Arr(self.changes.iter().map(|c| match *c {
+            Change::Added(ref p, ref x) =>
+                json::obj(vec![("op", json::str("add")), ("path", json::str(&pointer(p))), ("value", to_json(x))]),
+            Change::Removed(ref p, _) =>
+                json::obj(vec![("op", json::str("remove")), ("path", json::str(&pointer(p)))]),
+            Change::Changed(ref p, _, ref y) =>
+                json::obj(vec![("op", json::str("replace")), ("path", json::str(&pointer(p))), ("value", to_json(y))]),
+        }).collect()).to_string()
+    }
+}
+
+#[cfg(test)]
+fn sdiff(a: &str, b: &str) -> Diff {
+    diff(::parse_str(a).expr(), ::parse_str(b).expr())
+}
+
+#[test]
+fn test_diff_binds() {
+    let d = sdiff("@struct { @bind a { 'x' } @bind b { 'y' } # old
+        @bind s { @struct { @bind p { '1' } } } }",
+        "@struct {
+            @bind a { 'x' }
+            @bind s { @struct { @bind p { '2' } } }
+            @bind c { @from @my { 'a' } } }");
+    assert_eq!(d.text(), "- root.b: 'y'\n~ root.s.p: '1' -> '2'\n+ root.c: @from @my { 'a' }\n");
+    assert!(sdiff("@struct { @bind a { 'x' } }", "@struct{@bind a{'x'}}").changes.is_empty());
+}
+
+#[test]
+fn test_diff_columns() {
+    let d = sdiff("@column { 'a' 'b' 'c' 'd' }", "@column { 'z' 'a' 'c' 'e' 'd' 'f' }");
+    assert_eq!(d.text(), "+ root.0: 'z'\n- root.2: 'b'\n+ root.3: 'e'\n+ root.5: 'f'\n");
+    let d = sdiff("@column { @struct { @bind n { 'a' } } 'k' }", "@column { @struct { @bind n { 'b' } } 'k' }");
+    assert_eq!(d.text(), "~ root.0.n: 'a' -> 'b'\n");
+}
+
+#[test]
+fn test_diff_json() {
+    let d = sdiff("@struct { @bind a { @column { 'x' 'y' } } @bind b { 'q' } }",
+        "@struct { @bind a { @column { 'y' } } @bind c { @struct { @bind d { @column { 'e' } } } } }");
+    assert_eq!(d.json(), "[{\"op\":\"remove\",\"path\":\"/a/0\"},{\"op\":\"remove\",\"path\":\"/b\"},\
+        {\"op\":\"add\",\"path\":\"/c\",\"value\":{\"d\":[\"e\"]}}]");
+    assert_eq!(pointer(&["a/b".to_string(), "~".to_string()]), "/a~1b/~0");
+}
@@ -0,0 +1,192 @@
+use fig::path_name;
+use lex::Span;
+use std::fmt;
+use std::sync::Arc;
+
+// what went wrong, by a number that stays the same between releases
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Code {
+    Syntax,
+    NoField,
+    WrongArgumentType,
+    MissingArgument,
+    UnexpectedArgument,
+    NoElement,
+    NotContainer,
+    NotFunction,
+    BadKey,
+    NoParent,
+    Circular,
+    Import,
+    CircularImport,
+    Raised,
+    Native,
+    StepLimit,
+    DepthLimit,
+    SizeLimit,
+    Timeout,
+    Internal,
+}
+
+impl Code {
+    pub fn all() -> Vec<Code> {
+        vec![Code::Syntax, Code::NoField, Code::WrongArgumentType, Code::MissingArgument,
+            Code::UnexpectedArgument, Code::NoElement, Code::NotContainer, Code::NotFunction,
+            Code::BadKey, Code::NoParent, Code::Circular, Code::Import, Code::CircularImport,
+            Code::Raised, Code::Native, Code::StepLimit, Code::DepthLimit, Code::SizeLimit, Code::Timeout,
+            Code::Internal]
+    }
+
+    // e.g. E0102
+    pub fn id(self) -> &'static str {
+        match self {
+            Code::Syntax => "E0001",
+            Code::NoField => "E0101",
+            Code::WrongArgumentType => "E0102",
+            Code::MissingArgument => "E0103",
+            Code::UnexpectedArgument => "E0104",
+            Code::NoElement => "E0105",
+            Code::NotContainer => "E0106",
+            Code::NotFunction => "E0107",
+            Code::BadKey => "E0108",
+            Code::NoParent => "E0109",
+            Code::Circular => "E0110",
+            Code::Import => "E0111",
+            Code::CircularImport => "E0112",
+            Code::Raised => "E0201",
+            Code::Native => "E0202",
+            Code::StepLimit => "E0301",
+            Code::DepthLimit => "E0302",
+            Code::SizeLimit => "E0303",
+            Code::Timeout => "E0304",
+            Code::Internal => "E0900",
+        }
+    }
+
+    // e.g. wrong-argument-type
+    pub fn name(self) -> &'static str {
+        match self {
+            Code::Syntax => "syntax-error",
+            Code::NoField => "no-field",
+            Code::WrongArgumentType => "wrong-argument-type",
+            Code::MissingArgument => "missing-argument",
+            Code::UnexpectedArgument => "unexpected-argument",
+            Code::NoElement => "no-element",
+            Code::NotContainer => "not-a-container",
+            Code::NotFunction => "not-a-function",
+            Code::BadKey => "bad-key",
+            Code::NoParent => "no-parent",
+            Code::Circular => "circular-reference",
+            Code::Import => "import-failed",
+            Code::CircularImport => "circular-import",
+            Code::Raised => "raised",
+            Code::Native => "native-error",
+            Code::StepLimit => "step-limit",
+            Code::DepthLimit => "depth-limit",
+            Code::SizeLimit => "size-limit",
+            Code::Timeout => "timeout",
+            Code::Internal => "internal",
+        }
+    }
+
+    // by id or by name
+    pub fn find(s: &str) -> Option<Code> {
+        Code::all().into_iter().find(|c| c.id() == s || c.name() == s)
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.id(), self.name())
+    }
+}
+
+// a failed value: what went wrong, the cell and source it happened in,
+// and the error it came from when it was passed on by a @from or @call
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub code: Code,
+    pub message: String,
+    pub path: Option<Vec<String>>,
+    pub span: Option<Span>,
+    pub line_col: Option<(usize, usize)>,
+    pub cause: Option<Arc<Error>>,
+}
+
+// errors compare by what went wrong, not by where
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        self.code == other.code && self.message == other.message
+    }
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(code: Code, message: S) -> Error {
+        Error{code, message: message.into(), path: None, span: None, line_col: None, cause: None}
+    }
+
+    // the same failure, seen from another cell
+    pub fn through(self, path: &[String], span: Option<Span>, line_col: Option<(usize, usize)>) -> Error {
+        Error{code: self.code, message: self.message.clone(), path: Some(path.to_vec()), span, line_col,
+            cause: Some(Arc::new(self))}
+    }
+
+    // this error, then its cause, and so on
+    pub fn chain(&self) -> Vec<&Error> {
+        let mut chain = vec![self];
+        while let Some(c) = chain[chain.len() - 1].cause.as_ref() {
+            chain.push(c);
+        }
+        chain
+    }
+}
+
+// natives can fail with plain text
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::new(Code::Native, message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        Error::new(Code::Native, message)
+    }
+}
+
+// the code and message, then each cell it passed through
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)?;
+        for e in self.chain() {
+            if let Some(ref p) = e.path {
+                write!(f, "\n  at {}", path_name(p))?;
+                if let Some((line, col)) = e.line_col {
+                    write!(f, " ({}:{})", line, col)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_code_names() {
+    assert_eq!(Code::WrongArgumentType.to_string(), "E0102 wrong-argument-type");
+    assert_eq!(Code::find("E0101"), Some(Code::NoField));
+    assert_eq!(Code::find("raised"), Some(Code::Raised));
+    let mut ids: Vec<&str> = Code::all().iter().map(|c| c.id()).collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), Code::all().len());
+}
+
+#[test]
+fn test_error_chain() {
+    let e = Error::new(Code::NoField, "No field x")
+        .through(&["b".to_string()], None, Some((3, 13)))
+        .through(&["a".to_string()], None, Some((2, 13)));
+    assert_eq!(e.chain().len(), 3);
+    assert_eq!(e, Error::new(Code::NoField, "No field x"));
+    assert_eq!(e.to_string(), "E0101 no-field: No field x\n  at root.a (2:13)\n  at root.b (3:13)");
+}
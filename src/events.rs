@@ -0,0 +1,325 @@
+// A pull parser: the document as a stream of events instead of a tree, so
+// a column of any length is read in constant memory. Memory grows only
+// with nesting, which is bounded.
+
+use ast::{Bind, Expr};
+use lex::{Key, Span, Tok};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    StartStruct,
+    // the next value is bound to the name, in a @struct or a @call
+    Bind(String),
+    StartColumn,
+    Literal(String),
+    // @root, @my, @up, @sys or @lib
+    Key(Key),
+    // its head value, then its keys, then End
+    StartFrom,
+    // its function value, then its arguments as binds, then End
+    StartCall,
+    Import(String),
+    // of the innermost struct, column, @from or @call
+    End,
+    // the last event: the source goes wrong here
+    Error(Span, String),
+}
+
+// what the parser is waiting for, innermost last
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Frame {
+    Top,       // the document's value
+    Done,      // nothing more
+    Binds,     // @bind or }, in a @struct or a @call
+    BindValue, // the value of a @bind
+    BindEnd,   // the } after it
+    Column,    // a value or }
+    FromHead,  // the value a @from starts from
+    FromOpen,  // the { of its keys
+    FromKeys,  // a value or }
+    CallFn,    // the function of a @call
+    CallOpen,  // the { of its arguments
+}
+
+pub struct Events<I> {
+    toks: I,
+    stack: Vec<Frame>,
+    max_depth: usize,
+    last: Span,
+    start: usize, // where the last event's tokens start
+    failed: bool,
+}
+
+// events from spanned tokens, e.g. lex::lex_spans or Lexer::spanned;
+// nesting past max_depth is an error
+pub fn events<I: Iterator<Item = (Span, Tok)>>(toks: I, max_depth: usize) -> Events<I> {
+    Events{toks, stack: vec![Frame::Top], max_depth, last: Span::default(), start: 0, failed: false}
+}
+
+impl<I: Iterator<Item = (Span, Tok)>> Events<I> {
+    // the next token that is not whitespace or a comment
+    fn token(&mut self) -> Option<Tok> {
+        for (span, tok) in self.toks.by_ref() {
+            self.last = span;
+            match tok {
+                Tok::Whitespace(_) | Tok::Comment(_) => {},
+                t => return Some(t),
+            }
+        }
+        self.last = Span{lo: self.last.hi, hi: self.last.hi};
+        None
+    }
+
+    fn fail(&mut self, msg: &str) -> Option<Event> {
+        self.failed = true;
+        Some(Event::Error(self.last, msg.to_string()))
+    }
+
+    fn top(&mut self) -> &mut Frame {
+        self.stack.last_mut().expect("Events: empty stack")
+    }
+
+    // a value has ended; what its container waits for next
+    fn ended(&mut self) {
+        let next = match *self.top() {
+            Frame::Top => Frame::Done,
+            Frame::BindValue => Frame::BindEnd,
+            Frame::FromHead => Frame::FromOpen,
+            Frame::CallFn => Frame::CallOpen,
+            f => f,
+        };
+        *self.top() = next;
+    }
+
+    fn open(&mut self, frame: Frame, event: Event) -> Option<Event> {
+        if self.stack.len() > self.max_depth {
+            return self.fail(&format!("Nested deeper than {} levels", self.max_depth))
+        }
+        self.stack.push(frame);
+        Some(event)
+    }
+
+    fn close(&mut self) -> Option<Event> {
+        self.start = self.last.lo;
+        self.stack.pop();
+        self.ended();
+        Some(Event::End)
+    }
+
+    // the start of a value, its first token read
+    fn value(&mut self, tok: Tok) -> Option<Event> {
+        self.start = self.last.lo;
+        match tok {
+            Tok::Literal(s) => {
+                self.ended();
+                Some(Event::Literal(s))
+            },
+            Tok::Key(Key::Struct) => match self.token() {
+                Some(Tok::CurlL) => self.open(Frame::Binds, Event::StartStruct),
+                _ => self.fail("bind list must start with '{'"),
+            },
+            Tok::Key(Key::Column) => match self.token() {
+                Some(Tok::CurlL) => self.open(Frame::Column, Event::StartColumn),
+                _ => self.fail("@Column must be followed by '{'"),
+            },
+            Tok::Key(Key::From) => self.open(Frame::FromHead, Event::StartFrom),
+            Tok::Key(Key::Call) => self.open(Frame::CallFn, Event::StartCall),
+            Tok::Key(Key::Import) => match self.token() {
+                Some(Tok::Literal(s)) => {
+                    self.ended();
+                    Some(Event::Import(s))
+                },
+                _ => self.fail("@import must be followed by literal"),
+            },
+            Tok::Key(Key::Bind) => self.fail("Unexpected token"),
+            Tok::Key(k) => {
+                self.ended();
+                Some(Event::Key(k))
+            },
+            Tok::Error(e) => self.fail(&e),
+            _ => self.fail("Unexpected token"),
+        }
+    }
+
+    // `@bind name {`, its @bind read
+    fn bind(&mut self) -> Option<Event> {
+        self.start = self.last.lo;
+        let name = match self.token() {
+            Some(Tok::Literal(name)) => name,
+            _ => return self.fail("@bind must be followed by literal"),
+        };
+        match self.token() {
+            Some(Tok::CurlL) => self.open(Frame::BindValue, Event::Bind(name)),
+            _ => self.fail("@bind <name> must be followed by '{'"),
+        }
+    }
+}
+
+impl<I> Events<I> {
+    // the source of the last event: e.g. `@bind name {` for a Bind, `}` for
+    // an End
+    pub fn span(&self) -> Span {
+        Span{lo: self.start, hi: self.last.hi}
+    }
+}
+
+impl<I: Iterator<Item = (Span, Tok)>> Iterator for Events<I> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if self.failed {
+            return None
+        }
+        loop {
+            let frame = *self.top();
+            let tok = self.token();
+            return match (frame, tok) {
+                (Frame::Done, None) => None,
+                (Frame::Done, Some(_)) => self.fail("Unexpected token after expression"),
+                (_, None) => self.fail("Unexpected end of input"),
+                (_, Some(Tok::Error(e))) => self.fail(&e),
+                (Frame::Binds, Some(Tok::Key(Key::Bind))) => self.bind(),
+                (Frame::Binds, Some(Tok::CurlR)) | (Frame::Column, Some(Tok::CurlR))
+                    | (Frame::FromKeys, Some(Tok::CurlR)) => self.close(),
+                (Frame::Binds, Some(_)) => self.fail("bind list must end with '}'"),
+                (Frame::BindEnd, Some(Tok::CurlR)) => {
+                    self.stack.pop();
+                    continue
+                },
+                (Frame::BindEnd, Some(_)) => self.fail("@bind must end with '}'"),
+                (Frame::FromOpen, Some(Tok::CurlL)) => {
+                    *self.top() = Frame::FromKeys;
+                    continue
+                },
+                (Frame::FromOpen, Some(_)) => self.fail("@from must have '{' after root struct"),
+                // the arguments read as binds, then End ends the @call
+                (Frame::CallOpen, Some(Tok::CurlL)) => {
+                    *self.top() = Frame::Binds;
+                    continue
+                },
+                (Frame::CallOpen, Some(_)) => self.fail("bind list must start with '{'"),
+                (_, Some(t)) => self.value(t),
+            }
+        }
+    }
+}
+
+// the syntax tree the events describe, as ast::parse would build it
+pub fn build<E: Iterator<Item = Event>>(events: E) -> Result<Expr, (Span, String)> {
+    // each open value, with what it has so far
+    enum Open {
+        Struct(Vec<Bind>, Option<String>),
+        Column(Vec<Expr>),
+        From(Vec<Expr>),
+        Call(Option<Expr>, Vec<Bind>, Option<String>),
+    }
+    fn add(stack: &mut [Open], x: Expr) -> Option<Expr> {
+        match stack.last_mut() {
+            None => return Some(x),
+            Some(&mut Open::Struct(ref mut bs, ref mut name)) | Some(&mut Open::Call(Some(_), ref mut bs, ref mut name)) =>
+                bs.push(Bind{name: name.take().unwrap_or_default(), value: x}),
+            Some(&mut Open::Call(ref mut f, _, _)) => *f = Some(x),
+            Some(&mut Open::Column(ref mut xs)) | Some(&mut Open::From(ref mut xs)) => xs.push(x),
+        }
+        None
+    }
+    let mut stack: Vec<Open> = vec![];
+    let mut done = None;
+    for e in events {
+        let x = match e {
+            Event::StartStruct => {
+                stack.push(Open::Struct(vec![], None));
+                continue
+            },
+            Event::StartColumn => {
+                stack.push(Open::Column(vec![]));
+                continue
+            },
+            Event::StartFrom => {
+                stack.push(Open::From(vec![]));
+                continue
+            },
+            Event::StartCall => {
+                stack.push(Open::Call(None, vec![], None));
+                continue
+            },
+            Event::Bind(n) => {
+                match stack.last_mut() {
+                    Some(&mut Open::Struct(_, ref mut name)) | Some(&mut Open::Call(_, _, ref mut name)) => *name = Some(n),
+                    _ => return Err((Span::default(), "@bind outside a struct".to_string())),
+                }
+                continue
+            },
+            Event::Literal(s) => Expr::Literal(s),
+            Event::Key(Key::Root) => Expr::KeyRoot,
+            Event::Key(Key::My) => Expr::KeyMy,
+            Event::Key(Key::Up) => Expr::KeyUp,
+            Event::Key(Key::Sys) => Expr::KeySys,
+            Event::Key(Key::Lib) => Expr::KeyLib,
+            Event::Key(_) => return Err((Span::default(), "Unexpected token".to_string())),
+            Event::Import(s) => Expr::Import(s),
+            Event::End => match stack.pop() {
+                Some(Open::Struct(bs, _)) => Expr::Struct(bs),
+                Some(Open::Column(xs)) => Expr::Column(xs),
+                Some(Open::From(xs)) => Expr::From(xs),
+                Some(Open::Call(f, bs, _)) => Expr::Call{function: Box::new(f.unwrap_or(Expr::Error(String::new()))),
+                    arguments: bs},
+                None => return Err((Span::default(), "End with nothing open".to_string())),
+            },
+            Event::Error(span, msg) => return Err((span, msg)),
+        };
+        if let Some(x) = add(&mut stack, x) {
+            done = Some(x);
+        }
+    }
+    match (done, stack.is_empty()) {
+        (Some(x), true) => Ok(x),
+        _ => Err((Span::default(), "Unexpected end of events".to_string())),
+    }
+}
+
+#[cfg(test)]
+fn sevents(s: &str) -> Vec<Event> {
+    events(::lex::lex_spans(s).into_iter(), 64).collect()
+}
+
+#[test]
+fn test_events_struct() {
+    let es = sevents("@struct { @bind a { 'x' } # c
+        @bind b { @column { 'y' @my } } }");
+    assert_eq!(es, vec![Event::StartStruct, Event::Bind("a".to_string()), Event::Literal("x".to_string()),
+        Event::Bind("b".to_string()), Event::StartColumn, Event::Literal("y".to_string()), Event::Key(Key::My),
+        Event::End, Event::End]);
+}
+
+#[test]
+fn test_events_build() {
+    for src in &["'x'", "@column {}", "@struct {}", "@from @my { 'a' '0' }", "@from @root {}",
+            "@struct { @bind a { @call @from @sys { 'text' 'reverse' } { @bind a { 'x' } } } @bind b { @import 'b.bv' } }",
+            "@column { @struct { @bind n { @from @up { @from @my { 'k' } } } } @call @lib {} }"] {
+        let expected = ::parse_str(src).expr().clone();
+        assert_eq!(build(events(::lex::lex_spans(src).into_iter(), 64)), Ok(expected), "{}", src);
+    }
+}
+
+#[test]
+fn test_events_errors() {
+    let last = |s: &str| sevents(s).pop().unwrap();
+    assert_eq!(last("@struct { 'x' }"), Event::Error(Span{lo: 10, hi: 13}, "bind list must end with '}'".to_string()));
+    assert_eq!(last("@column { 'x'"), Event::Error(Span{lo: 13, hi: 13}, "Unexpected end of input".to_string()));
+    assert_eq!(last("'x' 'y'"), Event::Error(Span{lo: 4, hi: 7}, "Unexpected token after expression".to_string()));
+    assert_eq!(last("@column { @column { @column { } } }"), Event::End);
+    let deep = "@column { ".repeat(10);
+    assert_eq!(events(::lex::lex_spans(&deep).into_iter(), 4).last(),
+        Some(Event::Error(Span{lo: 48, hi: 49}, "Nested deeper than 4 levels".to_string())));
+}
+
+#[test]
+fn test_events_stream() {
+    // a long column, read and counted a token at a time
+    let n = 50_000;
+    let text = format!("@column {{{} }}", " 'x'".repeat(n));
+    let lexer = ::lex::Lexer::new(::std::io::BufReader::with_capacity(256, text.as_bytes()));
+    let lits = events(lexer.spanned(), 64).filter(|e| matches!(*e, Event::Literal(_))).count();
+    assert_eq!(lits, n);
+}
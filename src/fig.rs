@@ -1,14 +1,117 @@
+use arena::{Ast, Node, NodeId};
 use ast;
+use cache;
+use diff::{self, Change};
+use error::{Code, Error};
+use lex;
+use parallel;
+use std::cell;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::Instant;
+use {parse_file_with, Document, Limits};
 
-#[derive(Debug, PartialEq, Clone)]
-enum Value {
-    Err(String),
+#[derive(Debug, Clone)]
+pub enum Value {
+    Err(Error),
     Text(String),
-    Column(Vec<Value>),
-    Sheet(Struct),
-    Ftn(fn(Struct)->Value),
+    // shared: reading a value copies a pointer, not the value
+    Column(Rc<[Value]>),
+    Sheet(Rc<Struct>),
+    Ftn(Native),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Err(a), Value::Err(b)) => a == b,
+            (Value::Text(a), Value::Text(b)) => a == b,
+            (Value::Column(a), Value::Column(b)) => a == b,
+            (Value::Sheet(a), Value::Sheet(b)) => a == b,
+            (Value::Ftn(a), Value::Ftn(b)) => Arc::ptr_eq(&a.f, &b.f),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_value(f, self, 0)
+    }
+}
+
+fn write_value(f: &mut fmt::Formatter, v: &Value, depth: usize) -> fmt::Result {
+    let pad = "  ".repeat(depth + 1);
+    match *v {
+        Value::Err(ref e) => write!(f, "<error {}: {}>", e.code.id(), e.message),
+        Value::Text(ref t) => write!(f, "{}", lex::quote(t)),
+        Value::Ftn(ref n) => write!(f, "<function {}>", n.name),
+        Value::Column(ref xs) => {
+            if xs.is_empty() {
+                return write!(f, "@column {{}}")
+            }
+            writeln!(f, "@column {{")?;
+            for x in xs.iter() {
+                write!(f, "{}", pad)?;
+                write_value(f, x, depth + 1)?;
+                writeln!(f)?;
+            }
+            write!(f, "{}}}", "  ".repeat(depth))
+        },
+        Value::Sheet(ref s) => {
+            if s.is_empty() {
+                return write!(f, "@struct {{}}")
+            }
+            writeln!(f, "@struct {{")?;
+            let mut names: Vec<&String> = s.keys().collect();
+            names.sort();
+            for name in names {
+                write!(f, "{}@bind {} {{ ", pad, lex::quote_name(name))?;
+                match *s[name].val.borrow() {
+                    Progress::Green(ref v) => write_value(f, v, depth + 1)?,
+                    Progress::Yellow => write!(f, "<in progress>")?,
+                    Progress::Red => write!(f, "<unevaluated>")?,
+                }
+                writeln!(f, " }}")?;
+            }
+            write!(f, "{}}}", "  ".repeat(depth))
+        },
+    }
+}
+
+impl Value {
+    // a one-line summary of the value's shape
+    pub fn kind(&self) -> String {
+        match *self {
+            Value::Err(_) => "error".to_string(),
+            Value::Text(_) => "text".to_string(),
+            Value::Column(ref xs) => format!("column of {}", xs.len()),
+            Value::Sheet(ref s) => {
+                let mut names: Vec<String> = s.keys().map(|k| lex::quote_name(k)).collect();
+                names.sort();
+                format!("struct {}", braces(&names))
+            },
+            Value::Ftn(ref n) => {
+                let params: Vec<String> = n.params.iter().map(|p| lex::quote_name(p)).collect();
+                format!("function {} {}", n.name, braces(&params))
+            },
+        }
+    }
+}
+
+fn braces(names: &[String]) -> String {
+    if names.is_empty() {
+        "{}".to_string()
+    } else {
+        format!("{{ {} }}", names.join(" "))
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -18,90 +121,1612 @@ enum Progress<T> {
     Green(T), // has value
 }
 
-#[derive(Debug, PartialEq, Clone)]
-struct Cell {
-    expr: Option<ast::Expr>,
-    //val: Option<Value>,
+#[derive(Debug, Clone)]
+pub struct Cell {
+    path: Vec<String>, // where the cell lives, from the root
+    node: RefCell<Option<Syntax>>, // replaced by Env::set
     val: RefCell<Progress<Value>>,
+    home: Home,
 }
 
-type Struct = HashMap<String, Cell>;
+// a cell's expression: a node in the tree of the document it came from
+#[derive(Clone)]
+struct Syntax(Rc<Ast>, NodeId);
+
+// the node, not the whole tree
+impl fmt::Debug for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.1)
+    }
+}
+
+// the env of the imported file a cell belongs to; none for the importer's own
+#[derive(Debug, Clone, Default)]
+struct Home(Option<Weak<Env>>);
+
+// as cells, envs compare by what they hold
+impl PartialEq for Home {
+    fn eq(&self, _: &Home) -> bool {
+        true
+    }
+}
+
+// cells compare by what they hold, not by where they came from
+impl PartialEq for Cell {
+    fn eq(&self, other: &Cell) -> bool {
+        self.val == other.val
+    }
+}
+
+impl Cell {
+    // a cell that already holds its value
+    pub fn new(v: Value) -> Cell {
+        Cell{path: vec![], node: RefCell::new(None), val: RefCell::new(Progress::Green(v)), home: Home::default()}
+    }
+
+    // a cell whose value was never computed
+    pub(crate) fn pending() -> Cell {
+        Cell{path: vec![], node: RefCell::new(None), val: RefCell::new(Progress::Red), home: Home::default()}
+    }
+
+    // a cell caught while its value was being computed
+    pub(crate) fn in_progress() -> Cell {
+        Cell{path: vec![], node: RefCell::new(None), val: RefCell::new(Progress::Yellow), home: Home::default()}
+    }
+
+    // the value, if it has been computed
+    pub fn value(&self) -> Option<Value> {
+        match *self.val.borrow() {
+            Progress::Green(ref v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    // its expression as a tree of its own; none for natives
+    fn expr(&self) -> Option<ast::Expr> {
+        self.node.borrow().as_ref().map(|x| x.0.expr(x.1))
+    }
+}
+
+pub type Struct = HashMap<String, Cell>;
 
 #[derive(Debug, PartialEq)]
 pub struct Env {
     sys: Struct, // built-in
     lib: Struct,  // external
     top: Cell,  // user  // nb: should rename Root
+    file: Option<PathBuf>, // imports are relative to it
+    importing: Vec<PathBuf>, // files whose import led here
+    imports: RefCell<HashMap<PathBuf, Rc<Env>>>, // computed only as far as read
+    unread: RefCell<Vec<PathBuf>>, // imports that could not be read or parsed
+    importers: RefCell<HashMap<PathBuf, HashSet<Vec<String>>>>, // file -> cells that imported it
+    me: Home, // for an imported file, this env
+    computing: RefCell<Vec<Vec<String>>>, // cells being computed, innermost last
+    readers: RefCell<HashMap<Vec<String>, HashSet<Vec<String>>>>, // path -> cells that read it
+    steps: cell::Cell<usize>, // cells computed so far
+    broken: RefCell<HashMap<Vec<String>, String>>, // cells found on a cycle, with the chain
+    source: RefCell<Option<Source>>, // as of the last edit
+    prompts: RefCell<Vec<Value>>, // what evaluate was given, at ["<repl>", n]
+    limits: Limits,
+    started: cell::Cell<Option<(Instant, usize)>>, // when this evaluation began, and steps before it
+    tracer: RefCell<Option<Tracer>>,
+    cache: RefCell<Option<cache::Store>>,
+}
+
+// where the cells of what evaluate was given live, apart from the document's
+const PROMPT: &str = "<repl>";
+
+// one step of an evaluation, as a tracer sees it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trace {
+    Start(Vec<String>), // a cell goes from red to yellow
+    Done(Vec<String>, Value), // and from yellow to green
+    Read(Vec<String>), // a @from reaches the cell at this path
+    Call(String, Vec<(String, Value)>), // a native, with its arguments in name order
+    Kept(Vec<String>, Value), // a cell given the value a cache kept for it
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trace::Start(ref p) => write!(f, "start {}", path_name(p)),
+            Trace::Done(ref p, ref v) => write!(f, "done {} {}", path_name(p), brief(v)),
+            Trace::Read(ref p) => write!(f, "read {}", path_name(p)),
+            Trace::Kept(ref p, ref v) => write!(f, "kept {} {}", path_name(p), brief(v)),
+            Trace::Call(ref name, ref args) => {
+                write!(f, "call {}", name)?;
+                for (k, v) in args {
+                    write!(f, " {}={}", lex::quote_name(k), brief(v))?;
+                }
+                Ok(())
+            },
+        }
+    }
 }
 
+// a value on one line: text and errors as they are, the rest by kind
+fn brief(v: &Value) -> String {
+    match *v {
+        Value::Text(_) | Value::Err(_) => v.to_string(),
+        _ => v.kind(),
+    }
+}
+
+type TraceFn = dyn Fn(&Trace, &Env);
+
+#[derive(Clone)]
+struct Tracer(Rc<TraceFn>);
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+// who watches an env is no part of what it holds
+impl PartialEq for Tracer {
+    fn eq(&self, _: &Tracer) -> bool {
+        true
+    }
+}
+
+// where the document's binds and references are, for error messages
+#[derive(Debug, PartialEq)]
+struct Source {
+    text: String,
+    sites: HashMap<Vec<String>, ast::Site>,
+    refs: Vec<ast::Ref>, // in source order
+    lines: Vec<(usize, bool)>, // where each line starts, and whether it is all ascii
+}
+
+impl Source {
+    fn new(doc: &Document) -> Source {
+        // a later @bind of the same name is the one that counts
+        let sites = doc.sites.iter().map(|s| (s.path.clone(), s.clone())).collect();
+        let mut refs = doc.refs.clone();
+        refs.sort_by_key(|r| r.span.lo);
+        let mut lines = vec![];
+        let mut start = 0;
+        for line in doc.text.split('\n') {
+            lines.push((start, line.is_ascii()));
+            start += line.len() + 1;
+        }
+        Source{text: doc.text.clone(), sites, refs, lines}
+    }
+
+    // the name of the cell's @bind
+    fn name(&self, path: &[String]) -> Option<lex::Span> {
+        self.sites.get(path).map(|s| s.name)
+    }
+
+    // where cell `from` reads `to`: its @from if there is a fixed one, else its name
+    fn hop(&self, from: &[String], to: &[String]) -> Option<lex::Span> {
+        let site = self.sites.get(from);
+        let refs = match site {
+            Some(s) => {
+                let lo = self.refs.partition_point(|r| r.span.lo < s.whole.lo);
+                let hi = self.refs.partition_point(|r| r.span.lo < s.whole.hi);
+                &self.refs[lo..hi]
+            },
+            None => &self.refs[..],
+        };
+        refs.iter()
+            .find(|r| matches!(r.target, ast::Target::Path(ref p) if p.starts_with(to)))
+            .map(|r| r.span)
+            .or(site.map(|s| s.name))
+    }
+
+    // as lex::line_col, without reading the text up to there each time
+    fn line_col(&self, span: Option<lex::Span>) -> Option<(usize, usize)> {
+        let lo = span?.lo.min(self.text.len());
+        let i = self.lines.partition_point(|l| l.0 <= lo).saturating_sub(1);
+        let (start, ascii) = self.lines[i];
+        let col = if ascii { lo - start } else { self.text[start..lo].chars().count() };
+        Some((i + 1, col + 1))
+    }
+}
+
+impl Env {
+    // starts with the built-in @sys functions
+    pub fn builder() -> EnvBuilder {
+        create_sys(EnvBuilder::default())
+    }
+
+    // evaluate the whole document, every cell included
+    pub fn eval(&self) -> Value {
+        self.begin();
+        eval_env(self)
+    }
 
-fn reverse(i: Struct) -> Value {
-    let en = i.get(& "a".to_string());
-    match en {
-        Some(& Cell{ val: ref rc, ..}) => {
-            let gv = rc.borrow();
-            match *gv {
-                Progress::Green(ref v) =>
-                    match v {
-                        & Value::Text(ref t) => Value::Text(t.chars().rev().collect()),
-                        & Value::Err(ref e) => Value::Err(e.clone()),
-                        _ => Value::Err("@sys.text.reverse expects text argument".to_string()),
-                    },
-                _ => Value::Err("@sys.text.reverse is malformed".to_string()),
+    // evaluate an expression standing at the root, e.g. typed at a prompt
+    pub fn evaluate(&self, x: &ast::Expr) -> Value {
+        self.begin();
+        let n = self.prompts.borrow().len();
+        let ast = Rc::new(Ast::from_expr(x));
+        let v = eval(&ast, ast.root(), self, &[PROMPT.to_string(), n.to_string()]);
+        self.prompts.borrow_mut().push(v.clone());
+        force_value(&v, self, 0);
+        v
+    }
+
+    // the value at a path from the root, every cell in it included; only
+    // the cells it reads are computed, so errors elsewhere do not show
+    pub fn get(&self, path: &[&str]) -> Value {
+        self.at(&path.iter().map(|k| k.to_string()).collect::<Vec<String>>())
+    }
+
+    // the value at a path from the root, computing only what it needs
+    pub(crate) fn at(&self, path: &[String]) -> Value {
+        self.begin();
+        let v = lookup(&self.top, self, path);
+        force_value(&v, self, 0);
+        v
+    }
+
+    // the value at a path from the root, leaving the cells of any struct in
+    // it uncomputed
+    pub(crate) fn peek(&self, path: &[String]) -> Value {
+        self.begin();
+        lookup(&self.top, self, path)
+    }
+
+    // give the cell at path a new expression; what read it is computed again
+    // when next asked for, and nothing else is
+    pub fn set(&self, path: &[&str], x: ast::Expr) -> Result<(), String> {
+        let ast = Rc::new(Ast::from_expr(&x));
+        let root = ast.root();
+        self.reset(path.iter().map(|k| k.to_string()).collect(), Some(Syntax(ast, root)))
+    }
+
+    // compute the cell at path again when next asked for, with a new
+    // expression if given, and what read it
+    fn reset(&self, path: Vec<String>, x: Option<Syntax>) -> Result<(), String> {
+        self.begin();
+        find_cell(&self.top, self, &path, true, |c| {
+            if x.is_some() {
+                *c.node.borrow_mut() = x;
             }
-        },
-        _ => Value::Err("@sys.text.reverse expects argument 'a'".to_string()),
+            *c.val.borrow_mut() = Progress::Red;
+        })?;
+        forget(self, &path);
+        invalidate(self, path);
+        Ok(())
     }
+
+    // the document this env was built from, from, is now to: each cell that
+    // changed, or the nearest cell around it, gets its new expression, and
+    // only what those reach is computed again
+    pub fn edit(&self, from: &Document, to: &Document) {
+        for change in diff::diff(from.expr(), to.expr()).changes {
+            let mut path = match change {
+                Change::Added(p, _) | Change::Removed(p, _) | Change::Changed(p, _, _) => p,
+            };
+            // a column's elements are not cells, and a bind added or removed
+            // has none on one side
+            loop {
+                let done = to.arena.at(&path).is_some_and(|id| self.reset(path.clone(), Some(Syntax(to.arena.clone(), id))).is_ok());
+                if done || path.pop().is_none() {
+                    break
+                }
+            }
+        }
+        *self.source.borrow_mut() = Some(Source::new(to));
+    }
+
+    // file, imported here or further in, has changed: it is read again, and
+    // what came from it computed again, when next asked for; false if
+    // nothing here came from it
+    pub fn reload(&self, file: &Path) -> bool {
+        let stale: Vec<PathBuf> = self.imports.borrow().iter()
+            .filter(|(_, sub)| sub.file.as_deref() == Some(file) || sub.reload(file))
+            .map(|(k, _)| k.clone())
+            .collect();
+        let unread = self.unread.borrow().iter().any(|f| f == file);
+        if stale.is_empty() && !unread {
+            return false
+        }
+        self.unread.borrow_mut().retain(|f| f != file);
+        let mut cells = HashSet::new();
+        let mut names: Vec<PathBuf> = stale.iter()
+            .filter_map(|k| self.imports.borrow_mut().remove(k).and_then(|sub| sub.file.clone()))
+            .collect();
+        names.push(file.to_path_buf());
+        for f in names {
+            cells.extend(self.importers.borrow_mut().remove(&f).unwrap_or_default());
+        }
+        for c in cells {
+            let _ = self.reset(c, None);
+        }
+        true
+    }
+
+    // call f at each step of evaluation from now on, in imported files too
+    pub fn trace<F: Fn(&Trace, &Env) + 'static>(&self, f: F) {
+        self.set_tracer(Some(Tracer(Rc::new(f))));
+    }
+
+    fn set_tracer(&self, t: Option<Tracer>) {
+        for sub in self.imports.borrow().values() {
+            sub.set_tracer(t.clone());
+        }
+        *self.tracer.borrow_mut() = t;
+    }
+
+    // the cells being computed, innermost last
+    pub fn computing(&self) -> Vec<Vec<String>> {
+        self.computing.borrow().clone()
+    }
+
+    // the value at a path if it has been computed; computes nothing
+    pub fn computed(&self, path: &[&str]) -> Option<Value> {
+        let path: Vec<String> = path.iter().map(|k| k.to_string()).collect();
+        computed_in(self.top.value()?, &path)
+    }
+
+    // use the values kept in dir by an earlier run where they still hold,
+    // for this file and those it imports
+    pub fn cache<P: AsRef<Path>>(&self, dir: P) {
+        let store = self.file.as_ref().map(|f| cache::Store::open(dir.as_ref(), &canonical(f), &self.limits));
+        for sub in self.imports.borrow().values() {
+            sub.cache(dir.as_ref());
+        }
+        *self.cache.borrow_mut() = store;
+    }
+
+    // keep what this run computed for the next, if there is a cache
+    pub fn save_cache(&self) -> io::Result<()> {
+        for sub in self.imports.borrow().values() {
+            sub.save_cache()?;
+        }
+        let store = self.cache.borrow();
+        let store = match *store {
+            Some(ref s) => s,
+            None => return Ok(()),
+        };
+        // what a cell read must be computed through, or it cannot be checked
+        let top = self.top.value();
+        let entries = store.fresh.iter().filter_map(|(path, f)| {
+            let reads = f.reads.iter()
+                .map(|p| Some((p.clone(), cache::value_print(&computed_in(top.clone()?, p)?)?)))
+                .collect::<Option<Vec<_>>>()?;
+            Some((path.clone(), cache::Entry{expr: f.expr.clone(), reads, value: f.value.clone()}))
+        }).collect();
+        store.save(entries, |p| top.clone().and_then(|t| computed_in(t, p)).is_some())
+    }
+
+    // limits count from here, in imported files too
+    fn begin(&self) {
+        self.started.set(Some((Instant::now(), self.steps.get())));
+        for sub in self.imports.borrow().values() {
+            sub.begin();
+        }
+    }
+
+    // how many cells have been computed, counting recomputation and
+    // imported files
+    pub fn steps(&self) -> usize {
+        self.steps.get() + self.imports.borrow().values().map(|sub| sub.steps()).sum::<usize>()
+    }
+
+    // this env's file and those it imported so far, as they were named,
+    // including any it could not read
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.file.iter().cloned().collect();
+        files.extend(self.unread.borrow().iter().cloned());
+        for sub in self.imports.borrow().values() {
+            files.extend(sub.files());
+        }
+        files
+    }
+}
+
+// e.g. root.hosts.0.name
+pub fn path_name(path: &[String]) -> String {
+    let mut name = "root".to_string();
+    for k in path {
+        name.push('.');
+        name.push_str(&lex::quote_name(k));
+    }
+    name
 }
 
-fn create_sys() -> Struct {
-    let mut sys = HashMap::new();
-    let mut text = HashMap::new();
-    text.insert("reverse".to_string(), Cell{expr:None, val:RefCell::new(Progress::Green(Value::Ftn(reverse)))});
-    sys.insert("text".to_string(), Cell{expr:None,val:RefCell::new(Progress::Green(Value::Sheet(text)))});
-    //...
-    sys
+
+type NativeFn = dyn Fn(&Args) -> Result<Value, Error> + Send + Sync;
+
+// a function supplied by rust code, called with its arguments evaluated
+#[derive(Clone)]
+pub struct Native {
+    name: String, // full path, e.g. @sys.text.reverse
+    params: Vec<String>,
+    f: Arc<NativeFn>,
 }
 
-fn new_env(top: Cell) -> Env {
-    Env{sys:create_sys(), lib: HashMap::new(), top: top }
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
 }
 
-fn new_cell(expr: ast::Expr) -> Cell {
-    Cell{expr: Some(expr), val: RefCell::new(Progress::Red) }
+impl Native {
+    pub(crate) fn call(&self, vals: HashMap<String, Value>) -> Value {
+        for p in &self.params {
+            if !vals.contains_key(p) {
+                return fail(Code::MissingArgument, format!("{} expects argument {}", self.name, lex::quote(p)))
+            }
+        }
+        let mut extra: Vec<&String> = vals.keys().filter(|k| !self.params.contains(k)).collect();
+        extra.sort();
+        if let Some(k) = extra.first() {
+            return fail(Code::UnexpectedArgument, format!("{} takes no argument {}", self.name, lex::quote(k)))
+        }
+        match (self.f)(&Args{name: &self.name, vals}) {
+            Ok(v) => v,
+            Err(e) => Value::Err(e),
+        }
+    }
 }
 
-fn figify(expr: ast::Expr) -> Cell {
-    new_cell(expr) // should be more complicated?
+// the arguments of a native call, with typed access
+pub struct Args<'a> {
+    name: &'a str,
+    vals: HashMap<String, Value>,
 }
 
+impl<'a> Args<'a> {
+    // the function being called
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    // an argument of any kind; an error argument is passed on as it is
+    pub fn get(&self, arg: &str) -> Result<&Value, Error> {
+        match self.raw(arg)? {
+            Value::Err(e) => Err(e.clone()),
+            v => Ok(v),
+        }
+    }
+
+    // an argument, even an error
+    pub fn raw(&self, arg: &str) -> Result<&Value, Error> {
+        self.vals.get(arg)
+            .ok_or_else(|| Error::new(Code::MissingArgument, format!("{} expects argument {}", self.name, lex::quote(arg))))
+    }
+
+    fn wrong(&self, kind: &str, arg: &str) -> Error {
+        Error::new(Code::WrongArgumentType, format!("{} expects {} argument {}", self.name, kind, lex::quote(arg)))
+    }
+
+    pub fn text(&self, arg: &str) -> Result<&str, Error> {
+        match *self.get(arg)? {
+            Value::Text(ref t) => Ok(t),
+            _ => Err(self.wrong("text", arg)),
+        }
+    }
+
+    pub fn column(&self, arg: &str) -> Result<&[Value], Error> {
+        match *self.get(arg)? {
+            Value::Column(ref xs) => Ok(xs),
+            _ => Err(self.wrong("column", arg)),
+        }
+    }
+
+    pub fn sheet(&self, arg: &str) -> Result<&Struct, Error> {
+        match *self.get(arg)? {
+            Value::Sheet(ref s) => Ok(s),
+            _ => Err(self.wrong("struct", arg)),
+        }
+    }
+}
+
+// collects natives before the document is turned into an Env
+#[derive(Default)]
+pub struct EnvBuilder {
+    sys: Struct,
+    lib: Struct,
+    file: Option<PathBuf>,
+    source: Option<Source>,
+    limits: Limits,
+}
+
+// put v at a dotted path, making structs along the way; later wins
+fn insert_path(s: &mut Struct, path: &str, v: Value) {
+    let names: Vec<&str> = path.split('.').collect();
+    let (last, init) = match names.split_last() {
+        Some(p) => p,
+        None => return,
+    };
+    let mut here = s;
+    for name in init {
+        let cell = here.entry(name.to_string()).or_insert_with(|| Cell::new(Value::Sheet(Rc::new(HashMap::new()))));
+        let val = cell.val.get_mut();
+        if !matches!(*val, Progress::Green(Value::Sheet(_))) {
+            *val = Progress::Green(Value::Sheet(Rc::new(HashMap::new())));
+        }
+        here = match *val {
+            Progress::Green(Value::Sheet(ref mut inner)) => Rc::make_mut(inner),
+            _ => return,
+        };
+    }
+    here.insert(last.to_string(), Cell::new(v));
+}
+
+fn native<F>(name: String, params: &[&str], f: F) -> Value
+        where F: Fn(&Args) -> Result<Value, Error> + Send + Sync + 'static {
+    let params = params.iter().map(|p| p.to_string()).collect();
+    Value::Ftn(Native{name, params, f: Arc::new(f)})
+}
+
+impl EnvBuilder {
+    // a function reachable as @lib.<path>, e.g. "host.lookup"
+    pub fn register<F>(mut self, path: &str, params: &[&str], f: F) -> EnvBuilder
+            where F: Fn(&Args) -> Result<Value, Error> + Send + Sync + 'static {
+        insert_path(&mut self.lib, path, native(format!("@lib.{}", path), params, f));
+        self
+    }
+
+    // a function reachable as @sys.<path>
+    pub fn register_sys<F>(mut self, path: &str, params: &[&str], f: F) -> EnvBuilder
+            where F: Fn(&Args) -> Result<Value, Error> + Send + Sync + 'static {
+        insert_path(&mut self.sys, path, native(format!("@sys.{}", path), params, f));
+        self
+    }
+
+    // the file the document came from, for resolving @import
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> EnvBuilder {
+        self.file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    // the document's text and sites, so errors can say where they happened
+    pub(crate) fn source(mut self, doc: &Document) -> EnvBuilder {
+        self.source = Some(Source::new(doc));
+        self
+    }
+
+    // bounds on evaluation; the defaults otherwise
+    pub fn limits(mut self, limits: Limits) -> EnvBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn build(self, expr: &ast::Expr) -> Env {
+        self.build_arena(&Rc::new(Ast::from_expr(expr)))
+    }
+
+    // the same, its cells pointing into a tree already built
+    pub(crate) fn build_arena(self, ast: &Rc<Ast>) -> Env {
+        let importing = self.file.iter().map(|f| canonical(f)).collect();
+        new_env(self.sys, self.lib, figify(ast, Home::default()), self.file, importing, self.source, self.limits)
+    }
+
+    // the same, evaluated on up to `threads` threads; its errors say which
+    // cell but not where in the source
+    pub fn build_parallel(self, expr: &ast::Expr, threads: usize) -> parallel::Env {
+        let importing = self.file.iter().map(|f| canonical(f)).collect();
+        parallel::Env::new(&self.sys, &self.lib, expr, self.file, importing, self.limits, threads)
+    }
+}
+
+fn fail<S: Into<String>>(code: Code, message: S) -> Value {
+    Value::Err(Error::new(code, message))
+}
+
+// a struct of values that are already known
+fn record(fields: Vec<(&str, Value)>) -> Value {
+    Value::Sheet(Rc::new(fields.into_iter().map(|(n, v)| (n.to_string(), Cell::new(v))).collect()))
+}
+
+fn reverse(a: &Args) -> Result<Value, Error> {
+    Ok(Value::Text(a.text("a")?.chars().rev().collect()))
+}
+
+fn raise(a: &Args) -> Result<Value, Error> {
+    Err(Error::new(Code::Raised, a.text("message")?))
+}
+
+// @struct { @bind ok { value } }, or the error as
+// @struct { @bind error { @struct { code name message path } } }
+fn catch(a: &Args) -> Result<Value, Error> {
+    Ok(match *a.raw("value")? {
+        Value::Err(ref e) => record(vec![("error", record(vec![
+            ("code", Value::Text(e.code.id().to_string())),
+            ("name", Value::Text(e.code.name().to_string())),
+            ("message", Value::Text(e.message.clone())),
+            ("path", Value::Text(e.path.as_ref().map(|p| path_name(p)).unwrap_or_default())),
+        ]))]),
+        ref v => record(vec![("ok", v.clone())]),
+    })
+}
+
+// the value, or the default if it failed
+fn default(a: &Args) -> Result<Value, Error> {
+    match *a.raw("value")? {
+        Value::Err(_) => Ok(a.raw("default")?.clone()),
+        ref v => Ok(v.clone()),
+    }
+}
+
+fn create_sys(b: EnvBuilder) -> EnvBuilder {
+    b.register_sys("text.reverse", &["a"], reverse)
+        .register_sys("error.raise", &["message"], raise)
+        .register_sys("error.catch", &["value"], catch)
+        .register_sys("error.default", &["value", "default"], default)
+        .register_sys("try", &["value", "default"], default)
+}
+
+fn new_env(sys: Struct, lib: Struct, top: Cell, file: Option<PathBuf>, importing: Vec<PathBuf>,
+        source: Option<Source>, limits: Limits) -> Env {
+    Env{sys, lib, me: top.home.clone(), top, file, importing, imports: RefCell::new(HashMap::new()),
+        unread: RefCell::new(vec![]), importers: RefCell::new(HashMap::new()), computing: RefCell::new(vec![]), readers: RefCell::new(HashMap::new()), steps: cell::Cell::new(0),
+        broken: RefCell::new(HashMap::new()), source: RefCell::new(source), prompts: RefCell::new(vec![]), limits, started: cell::Cell::new(None),
+        tracer: RefCell::new(None), cache: RefCell::new(None)}
+}
+
+fn new_cell(path: Vec<String>, node: Syntax, home: Home) -> Cell {
+    Cell{path, node: RefCell::new(Some(node)), val: RefCell::new(Progress::Red), home }
+}
+
+fn figify(ast: &Rc<Ast>, home: Home) -> Cell {
+    new_cell(vec![], Syntax(ast.clone(), ast.root()), home)
+}
+
+#[cfg(test)]
 pub fn create_env(expr: ast::Expr) -> Env {
-    new_env(figify(expr))
+    Env::builder().build(&expr)
+}
+
+fn eval_env(e: & Env) -> Value {
+    force(& e.top, e, 0);
+    with_cell(& e.top, e, |v| v.clone())
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+// path of the struct holding this path
+fn parent(path: &[String]) -> &[String] {
+    &path[..path.len().saturating_sub(1)]
+}
+
+// give the cell a value, unless it is already being computed
+fn fill_cell(c: & Cell, e: & Env) -> Result<(), Value> {
+    if let Some(home) = elsewhere(c, e) {
+        return match home.upgrade() {
+            Some(home) => fill_cell(c, & home),
+            None => Err(fail(Code::Internal, "Imported file no longer loaded")),
+        }
+    }
+    match *c.val.borrow() {
+        Progress::Green(_) => return Ok(()),
+        Progress::Yellow => return Err(fail(Code::Circular, cycle(c, e))),
+        Progress::Red => {},
+    }
+    if let Some(err) = over_limit(e) {
+        return Err(err)
+    }
+    *c.val.borrow_mut() = Progress::Yellow;
+    if let Some(v) = reuse(c, e) {
+        trace(e, || Trace::Kept(c.path.clone(), v.clone()));
+        *c.val.borrow_mut() = Progress::Green(v);
+        return Ok(())
+    }
+    e.steps.set(e.steps.get() + 1);
+    trace(e, || Trace::Start(c.path.clone()));
+    e.computing.borrow_mut().push(c.path.clone());
+    with_cache(e, |s| s.frames.push(cache::Frame::default()));
+    let mut new_val = eval_node(& c.node.borrow(), e, & c.path);
+    e.computing.borrow_mut().pop();
+    let frame = e.cache.borrow_mut().as_mut().and_then(|s| s.frames.pop());
+    if let Some(chain) = e.broken.borrow_mut().remove(& c.path) {
+        new_val = fail(Code::Circular, chain);
+    }
+    match frame {
+        Some(f) if f.calls && !f.imports && cache::keeps(& new_val) => with_cache(e, |s| {
+            let expr = cache::expr_print(&c.expr());
+            s.fresh.insert(c.path.clone(), cache::Fresh{expr, reads: f.reads, value: new_val.clone()});
+        }),
+        _ => {},
+    }
+    let new_val = locate(new_val, c, e);
+    trace(e, || Trace::Done(c.path.clone(), new_val.clone()));
+    *c.val.borrow_mut() = Progress::Green(new_val);
+    Ok(())
+}
+
+// the value an earlier run kept for c, if its expression is the same and
+// what it read still has the values it had; c reads them again here
+fn reuse(c: & Cell, e: & Env) -> Option<Value> {
+    let entry = e.cache.borrow().as_ref()?.get(& c.path)?.clone();
+    if entry.expr != cache::expr_print(&c.expr()) {
+        return None
+    }
+    e.computing.borrow_mut().push(c.path.clone());
+    let same = entry.reads.iter().all(|(p, print)| {
+        let v = lookup_path(e, p);
+        force_value(& v, e, 0);
+        cache::value_print(& v).as_ref() == Some(print)
+    });
+    e.computing.borrow_mut().pop();
+    if !same {
+        return None
+    }
+    with_cache(e, |s| { s.used.insert(c.path.clone()); });
+    Some(entry.value)
+}
+
+// note something about the cell being computed, if there is a cache
+fn with_cache<F: FnOnce(&mut cache::Store)>(e: & Env, f: F) {
+    if let Some(s) = e.cache.borrow_mut().as_mut() {
+        f(s)
+    }
+}
+
+// tell the tracer, if there is one; the step is only made if there is
+fn trace<F: FnOnce() -> Trace>(e: & Env, step: F) {
+    let t = e.tracer.borrow().clone();
+    if let Some(Tracer(f)) = t {
+        f(& step(), e);
+    }
 }
 
-fn eval_env(e: & Env) {
-    let mut v = vec![ast::Expr::KeyRoot];
-    eval_cell(& e.top, e, &mut v);
+// what is at keys in v, as far as it is computed
+fn computed_in(v: Value, keys: &[String]) -> Option<Value> {
+    let (k, rest) = match keys.split_first() {
+        None => return Some(v),
+        Some(p) => p,
+    };
+    let next = match v {
+        Value::Sheet(ref s) => s.get(k)?.value()?,
+        Value::Column(ref xs) => xs.get(k.parse::<usize>().ok()?)?.clone(),
+        _ => return None,
+    };
+    computed_in(next, rest)
 }
 
-fn eval_cell(c: & Cell, e: & Env, my_path: &mut Vec<ast::Expr>) -> Value {
-    let mut cached_val = c.val.borrow_mut();
-    match cached_val.clone() {
-        Progress::Red => {
-            *cached_val = Progress::Yellow;
-            let new_val = eval_expr(& c.expr, e, my_path);
-            *cached_val = Progress::Green(new_val.clone());
-            new_val
+// the env of an imported file, for one of its cells read from outside it
+fn elsewhere<'a>(c: &'a Cell, e: & Env) -> Option<&'a Weak<Env>> {
+    c.home.0.as_ref().filter(|h| !ptr::eq(h.as_ptr(), e))
+}
+
+// computing one more cell would go past a limit
+fn over_limit(e: & Env) -> Option<Value> {
+    let (start, base) = e.started.get().unwrap_or_else(|| (Instant::now(), 0));
+    if e.computing.borrow().len() >= e.limits.calls {
+        return Some(fail(Code::DepthLimit, format!("More than {} cells computing at once", e.limits.calls)))
+    }
+    if e.steps.get() - base >= e.limits.steps {
+        return Some(fail(Code::StepLimit, format!("More than {} cells computed", e.limits.steps)))
+    }
+    match e.limits.timeout {
+        Some(t) if start.elapsed() > t => Some(fail(Code::Timeout, format!("Evaluation took longer than {:?}", t))),
+        _ => None,
+    }
+}
+
+// text and columns past the size limit are errors
+fn sized(v: Value, e: & Env) -> Value {
+    let (what, n) = match v {
+        Value::Text(ref t) => ("Text", t.len()),
+        Value::Column(ref xs) => ("Column", xs.len()),
+        _ => return v,
+    };
+    if n > e.limits.size {
+        return fail(Code::SizeLimit, format!("{} larger than {}", what, e.limits.size))
+    }
+    v
+}
+
+// an error a cell ends up with says where: the cell it started in, or the
+// cell it passed through on its way from another
+fn locate(v: Value, c: & Cell, e: & Env) -> Value {
+    let err = match v {
+        Value::Err(err) => err,
+        v => return v,
+    };
+    let src = e.source.borrow();
+    let src = src.as_ref();
+    Value::Err(match err.path.clone() {
+        None => {
+            let span = src.and_then(|s| s.name(& c.path));
+            let line_col = src.and_then(|s| s.line_col(span));
+            Error{path: Some(c.path.clone()), span, line_col, ..err}
+        },
+        Some(ref p) if *p != c.path => {
+            let span = src.and_then(|s| s.hop(& c.path, p));
+            let line_col = src.and_then(|s| s.line_col(span));
+            err.through(& c.path, span, line_col)
+        },
+        Some(_) => err,
+    })
+}
+
+// c is being computed further down the stack: name the chain of reads back
+// to it, and make every cell on the way an error too
+fn cycle(c: & Cell, e: & Env) -> String {
+    let computing = e.computing.borrow();
+    let start = computing.iter().rposition(|p| *p == c.path).unwrap_or(computing.len());
+    let ring = & computing[start..];
+    let mut hops = vec![];
+    for (i, p) in ring.iter().enumerate() {
+        let next = ring.get(i + 1).unwrap_or(& c.path);
+        hops.push(match e.source.borrow().as_ref().and_then(|s| s.line_col(s.hop(p, next))) {
+            Some((line, col)) => format!("{} ({}:{})", path_name(p), line, col),
+            None => path_name(p),
+        });
+    }
+    hops.push(path_name(& c.path));
+    let chain = format!("Circular reference: {}", hops.join(" -> "));
+    let mut broken = e.broken.borrow_mut();
+    for p in ring {
+        broken.insert(p.clone(), chain.clone());
+    }
+    chain
+}
+
+// look at the cell's value without copying it
+fn with_cell<R, F: FnOnce(& Value) -> R>(c: & Cell, e: & Env, f: F) -> R {
+    if let Err(err) = fill_cell(c, e) {
+        return f(& err)
+    }
+    match *c.val.borrow() {
+        Progress::Green(ref v) => f(v),
+        _ => f(& fail(Code::Internal, "Cell lost its value")),
+    }
+}
+
+fn eval_cell(c: & Cell, e: & Env) -> Value {
+    with_cell(c, e, |v| copy(v, e))
+}
+
+// whether v has values nested more than n deep, looking no further
+fn deeper_than(v: & Value, n: usize) -> bool {
+    let inside = |v: & Value| n == 0 || deeper_than(v, n - 1);
+    match *v {
+        Value::Sheet(ref s) => s.values().any(|c| match *c.val.borrow() {
+            Progress::Green(ref v) => inside(v),
+            _ => false,
+        }),
+        Value::Column(ref xs) => xs.iter().any(inside),
+        _ => false,
+    }
+}
+
+// copies nest values inside each other, so stop them growing past the limit
+fn copy(v: & Value, e: & Env) -> Value {
+    if deeper_than(v, e.limits.depth) {
+        return fail(Code::DepthLimit, format!("Value nested deeper than {} levels", e.limits.depth))
+    }
+    if in_progress(v, e.limits.depth) {
+        return settled(v)
+    }
+    v.clone()
+}
+
+// whether v holds a cell still being computed, looking no deeper than n
+fn in_progress(v: & Value, n: usize) -> bool {
+    let inside = |v: & Value| n > 0 && in_progress(v, n - 1);
+    match *v {
+        Value::Sheet(ref s) => s.values().any(|c| match *c.val.borrow() {
+            Progress::Yellow => true,
+            Progress::Green(ref v) => inside(v),
+            Progress::Red => false,
+        }),
+        Value::Column(ref xs) => xs.iter().any(inside),
+        _ => false,
+    }
+}
+
+// a copy of v that does not share the structs holding a cell being computed,
+// so a cell that reads its own struct cannot end up holding itself
+fn settled(v: & Value) -> Value {
+    match *v {
+        Value::Sheet(ref s) => Value::Sheet(Rc::new(s.iter().map(|(k, c)| {
+            let val = match *c.val.borrow() {
+                Progress::Green(ref v) => Progress::Green(settled(v)),
+                ref p => p.clone(),
+            };
+            (k.clone(), Cell { path: c.path.clone(), node: c.node.clone(), val: RefCell::new(val), home: c.home.clone() })
+        }).collect())),
+        Value::Column(ref xs) => Value::Column(xs.iter().map(settled).collect()),
+        _ => v.clone(),
+    }
+}
+
+// values nested past the depth limit are left as they are
+fn force(c: & Cell, e: & Env, depth: usize) {
+    with_cell(c, e, |v| force_value(v, e, depth))
+}
+
+fn force_value(v: & Value, e: & Env, depth: usize) {
+    if depth >= e.limits.depth {
+        return
+    }
+    match *v {
+        Value::Sheet(ref s) => {
+            // in name order, so a cycle is always reported from the same cell
+            let mut names: Vec<&String> = s.keys().collect();
+            names.sort();
+            for k in names { force(& s[k], e, depth + 1) }
+        },
+        Value::Column(ref xs) => for x in xs.iter() { force_value(x, e, depth + 1) },
+        _ => {},
+    }
+}
+
+// note that the cell being computed reads c
+fn read(c: & Cell, e: & Env) {
+    if c.node.borrow().is_none() || elsewhere(c, e).is_some() {
+        return // natives never change; a path into an imported file is noted as read
+    }
+    note_read(e, & c.path);
+}
+
+fn note_read(e: & Env, path: &[String]) {
+    if let Some(reader) = e.computing.borrow().last() {
+        e.readers.borrow_mut().entry(path.to_vec()).or_default().insert(reader.clone());
+    }
+}
+
+// a value read past a struct depends only on the cells inside it
+fn lookup(c: & Cell, e: & Env, keys: &[String]) -> Value {
+    match keys.split_first() {
+        None => {
+            read(c, e);
+            eval_cell(c, e)
+        },
+        Some((k, rest)) => with_cell(c, e, |v| {
+            if !matches!(*v, Value::Sheet(_)) {
+                read(c, e);
+            }
+            lookup_value(v, e, k, rest)
+        }),
+    }
+}
+
+// the cell at keys below c; only computes the structs on the way if asked
+fn find_cell<F: FnOnce(& Cell)>(c: & Cell, e: & Env, keys: &[String], compute: bool, f: F) -> Result<(), String> {
+    let (k, rest) = match keys.split_first() {
+        None => {
+            f(c);
+            return Ok(())
+        },
+        Some(p) => p,
+    };
+    if compute {
+        let _ = fill_cell(c, e);
+    }
+    match *c.val.borrow() {
+        Progress::Green(ref v) => find_in_value(v, e, k, rest, compute, f),
+        _ => Err(format!("No value holding {}", lex::quote_name(k))),
+    }
+}
+
+fn find_in_value<F: FnOnce(& Cell)>(v: & Value, e: & Env, k: &str, rest: &[String], compute: bool, f: F) -> Result<(), String> {
+    match *v {
+        Value::Sheet(ref s) => match s.get(k) {
+            Some(c) => find_cell(c, e, rest, compute, f),
+            None => Err(format!("No field {}", lex::quote_name(k))),
+        },
+        Value::Column(ref xs) => match (k.parse::<usize>().ok().and_then(|i| xs.get(i)), rest.split_first()) {
+            (Some(x), Some((k2, rest2))) => find_in_value(x, e, k2, rest2, compute, f),
+            (Some(_), None) => Err(format!("Element {} of a column is not a cell", lex::quote_name(k))),
+            (None, _) => Err(format!("No element {} in column", lex::quote_name(k))),
+        },
+        _ => Err(format!("Cannot look up {} outside a struct or column", lex::quote_name(k))),
+    }
+}
+
+// drop what the cells at or below path read; they will read again
+fn forget(e: & Env, path: &[String]) {
+    for rs in e.readers.borrow_mut().values_mut() {
+        rs.retain(|r| !r.starts_with(path));
+    }
+}
+
+// reset whatever read a changed path, or a struct around it, or a cell in it,
+// then whatever read those
+fn invalidate(e: & Env, changed: Vec<String>) {
+    let mut todo = vec![changed];
+    while let Some(p) = todo.pop() {
+        let hit: Vec<Vec<String>> = e.readers.borrow().iter()
+            .filter(|(k, _)| k.starts_with(&p) || p.starts_with(k))
+            .flat_map(|(_, rs)| rs.iter().cloned())
+            .collect();
+        for r in hit {
+            forget(e, &r);
+            let _ = find_cell(&e.top, e, &r, false, |c| *c.val.borrow_mut() = Progress::Red);
+            todo.push(r);
+        }
+    }
+}
+
+fn lookup_struct(s: & Struct, e: & Env, k: &str, rest: &[String]) -> Value {
+    match s.get(k) {
+        Some(c) => lookup(c, e, rest),
+        None => fail(Code::NoField, format!("No field {}", lex::quote_name(k))),
+    }
+}
+
+fn lookup_top(s: & Struct, e: & Env, keys: &[String]) -> Value {
+    match keys.split_first() {
+        None => Value::Sheet(Rc::new(s.clone())),
+        Some((k, rest)) => lookup_struct(s, e, k, rest),
+    }
+}
+
+fn lookup_value(v: & Value, e: & Env, k: &str, rest: &[String]) -> Value {
+    match *v {
+        Value::Sheet(ref s) => lookup_struct(s, e, k, rest),
+        Value::Column(ref xs) => match k.parse::<usize>().ok().and_then(|i| xs.get(i)) {
+            Some(x) => match rest.split_first() {
+                None => copy(x, e),
+                Some((k2, rest2)) => lookup_value(x, e, k2, rest2),
+            },
+            None => fail(Code::NoElement, format!("No element {} in column", lex::quote_name(k))),
+        },
+        Value::Err(_) => v.clone(),
+        _ => fail(Code::NotContainer, format!("Cannot look up {} outside a struct or column", lex::quote_name(k))),
+    }
+}
+
+fn eval_node(node: & Option<Syntax>, e: & Env, here: &[String]) -> Value {
+    match *node {
+        Some(Syntax(ref ast, id)) => eval(ast, id, e, here),
+        None => fail(Code::Internal, "Cell has no expression"),
+    }
+}
+
+// `here` is where the value will live
+fn eval(ast: & Rc<Ast>, id: NodeId, e: & Env, here: &[String]) -> Value {
+    match *ast.node(id) {
+        Node::Literal(ref s) => sized(Value::Text(s.clone()), e),
+        Node::Column(ref xs) => sized(Value::Column(xs.iter().enumerate()
+            .map(|(i, x)| eval(ast, *x, e, & child(here, & i.to_string())))
+            .collect()), e),
+        Node::Struct(ref binds) => Value::Sheet(Rc::new(binds.iter()
+            .map(|b| (b.0.clone(), new_cell(child(here, & b.0), Syntax(ast.clone(), b.1), e.me.clone())))
+            .collect())),
+        Node::Key(lex::Key::Root) | Node::Key(lex::Key::Sys) | Node::Key(lex::Key::Lib) | Node::Key(lex::Key::Up) | Node::Key(lex::Key::My) =>
+            eval_from(ast, id, &[], e, here),
+        Node::Key(k) => fail(Code::Syntax, format!("@{} is not a value", k)),
+        Node::From(ref xs) => {
+            let mut keys = Vec::with_capacity(xs.len());
+            for k in & xs[1..] {
+                match eval(ast, *k, e, here) {
+                    Value::Text(t) => keys.push(t),
+                    Value::Err(err) => return Value::Err(err),
+                    _ => return fail(Code::BadKey, "@from path must be text"),
+                }
+            }
+            if keys.len() > e.limits.depth {
+                return fail(Code::DepthLimit, format!("@from path longer than {} keys", e.limits.depth))
+            }
+            eval_from(ast, xs[0], & keys, e, here)
+        },
+        Node::Call{function, ref arguments} => {
+            let args: HashMap<String, Value> = arguments.iter()
+                .map(|b| (b.0.clone(), eval(ast, b.1, e, & child(here, & b.0))))
+                .collect();
+            match eval(ast, function, e, here) {
+                Value::Ftn(f) => {
+                    trace(e, || {
+                        let mut args: Vec<(String, Value)> = args.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                        args.sort_by(|a, b| a.0.cmp(&b.0));
+                        Trace::Call(f.name.clone(), args)
+                    });
+                    with_cache(e, |s| if let Some(f) = s.frames.last_mut() { f.calls = true });
+                    sized(f.call(args), e)
+                },
+                Value::Err(err) => Value::Err(err),
+                _ => fail(Code::NotFunction, "@call expects a function"),
+            }
+        },
+        Node::Import(ref name) => import(e, name),
+        Node::Error(ref s) => fail(Code::Syntax, s.clone()),
+    }
+}
+
+// where the struct @my stands for at a node is, given where the node is
+fn my_path(ast: & Ast, id: NodeId, here: &[String]) -> Vec<String> {
+    match ast.my(id) {
+        Some(s) => here[..here.len().saturating_sub(ast.keys_below(s, id))].to_vec(),
+        // outside any struct of its tree: the one around where the tree was put
+        None => above(& here[..here.len().saturating_sub(ast.keys_below(ast.root(), id))]).to_vec(),
+    }
+}
+
+// and where the one @up stands for is, if there is one
+fn up_path(ast: & Ast, id: NodeId, here: &[String]) -> Option<Vec<String>> {
+    match ast.up(id) {
+        Some(u) => Some(here[..here.len().saturating_sub(ast.keys_below(u, id))].to_vec()),
+        None => {
+            let my = my_path(ast, id, here);
+            if my.is_empty() { None } else { Some(above(& my).to_vec()) }
+        },
+    }
+}
+
+// path of the struct holding this path; what evaluate was given sits in
+// the root
+fn above(path: &[String]) -> &[String] {
+    match path {
+        [k, _] if k == PROMPT => &[],
+        _ => parent(path),
+    }
+}
+
+fn canonical(p: &Path) -> PathBuf {
+    fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
+}
+
+// the root of another file, sharing this env's natives; its cells are
+// computed in its own env, when read
+fn import(e: & Env, name: &str) -> Value {
+    with_cache(e, |s| if let Some(f) = s.frames.last_mut() { f.imports = true });
+    let path = match e.file.as_ref().and_then(|f| f.parent()) {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    };
+    if let Some(c) = e.computing.borrow().last() {
+        e.importers.borrow_mut().entry(path.clone()).or_default().insert(c.clone());
+    }
+    let key = canonical(& path);
+    let done = e.imports.borrow().get(& key).cloned();
+    if let Some(sub) = done {
+        return with_cell(& sub.top, & sub, |v| v.clone())
+    }
+    if e.importing.contains(& key) {
+        return fail(Code::CircularImport, format!("Circular @import {}", lex::quote(name)))
+    }
+    let read = match parse_file_with(& path, & e.limits) {
+        Ok(doc) => match doc.diagnostics().first() {
+            Some(d) => Err(d.to_string()),
+            None => Ok(doc),
+        },
+        Err(err) => Err(err.to_string()),
+    };
+    let doc = match read {
+        Ok(doc) => doc,
+        Err(err) => {
+            e.unread.borrow_mut().push(path);
+            return fail(Code::Import, format!("@import {}: {}", lex::quote(name), err))
+        },
+    };
+    let mut importing = e.importing.clone();
+    importing.push(key.clone());
+    let sub = Rc::new_cyclic(|me| new_env(e.sys.clone(), e.lib.clone(), figify(& doc.arena, Home(Some(me.clone()))),
+        Some(path), importing, Some(Source::new(&doc)), e.limits.clone()));
+    sub.begin();
+    sub.set_tracer(e.tracer.borrow().clone());
+    if let Some(dir) = e.cache.borrow().as_ref().map(|s| s.dir.clone()) {
+        sub.cache(dir);
+    }
+    e.imports.borrow_mut().insert(key, sub.clone());
+    with_cell(& sub.top, & sub, |v| v.clone())
+}
+
+// follow keys from the head of a @from
+fn eval_from(ast: & Rc<Ast>, head: NodeId, keys: &[String], e: & Env, here: &[String]) -> Value {
+    let base = match *ast.node(head) {
+        Node::Key(lex::Key::Root) => vec![],
+        Node::Key(lex::Key::My) => my_path(ast, head, here),
+        Node::Key(lex::Key::Up) => match up_path(ast, head, here) {
+            Some(p) => p,
+            None => return fail(Code::NoParent, "@up has no struct above the root"),
+        },
+        Node::Key(lex::Key::Sys) => return lookup_top(& e.sys, e, keys),
+        Node::Key(lex::Key::Lib) => return lookup_top(& e.lib, e, keys),
+        _ => {
+            let v = eval(ast, head, e, here);
+            return match keys.split_first() {
+                None => v,
+                Some((k, rest)) => lookup_value(& v, e, k, rest),
+            }
         },
-        Progress::Yellow => {
-            let err = Value::Err("Circular reference".to_string());
-            err
+    };
+    let path = [base, keys.to_vec()].concat();
+    trace(e, || Trace::Read(path.clone()));
+    // the cell it ends at may be in an imported file, which can be reloaded
+    note_read(e, & path);
+    with_cache(e, |s| if let Some(f) = s.frames.last_mut() { f.reads.push(path.clone()) });
+    lookup_path(e, & path)
+}
+
+// the value at a path from the root, or from what evaluate was given
+fn lookup_path(e: & Env, path: &[String]) -> Value {
+    match path {
+        [k, n, rest @ ..] if k == PROMPT => match n.parse::<usize>().ok().and_then(|i| e.prompts.borrow().get(i).cloned()) {
+            Some(v) => match rest.split_first() {
+                None => v,
+                Some((k, rest)) => lookup_value(& v, e, k, rest),
+            },
+            None => fail(Code::NoField, format!("No field {}", lex::quote_name(k))),
         },
-        Progress::Green(v) => v
+        _ => lookup(& e.top, e, path),
     }
-    // ,,,
 }
 
-fn eval_expr(expr: & Option<ast::Expr>, e: & Env, my_path: &mut Vec<ast::Expr>) -> Value {
-    Value::Err("...".to_string())
+#[cfg(test)]
+fn seval(s: &str) -> Value {
+    create_env(ast::parse(lex::slex(s))).eval()
+}
+
+#[cfg(test)]
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+#[cfg(test)]
+fn sheet(fields: Vec<(&str, Value)>) -> Value {
+    record(fields)
+}
+
+#[test]
+fn test_eval_literal() {
+    assert_eq!(seval("'abc'"), text("abc"));
+}
+
+#[test]
+fn test_eval_column() {
+    assert_eq!(seval("@column { 'a' 'b' }"), Value::Column(vec![text("a"), text("b")].into()));
+}
+
+#[test]
+fn test_eval_struct() {
+    assert_eq!(seval("@struct { @bind a { 'x' } @bind b { @column {} } }"),
+        sheet(vec![("a", text("x")), ("b", Value::Column(vec![].into()))]));
+}
+
+#[test]
+fn test_eval_from_my() {
+    assert_eq!(seval("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } }"),
+        sheet(vec![("a", text("x")), ("b", text("x"))]));
+}
+
+#[test]
+fn test_eval_from_root_up() {
+    let v = seval("@struct { @bind a { 'x' } @bind s { @struct {
+        @bind b { @from @root { 'a' } } @bind c { @from @up { 's' 'b' } } } } }");
+    assert_eq!(v, sheet(vec![("a", text("x")),
+        ("s", sheet(vec![("b", text("x")), ("c", text("x"))]))]));
+}
+
+#[test]
+fn test_eval_up_through_column() {
+    // @up from a struct in a column is the column
+    let v = seval("@struct { @bind hs { @column { @struct { @bind n { 'a' } } @struct { @bind m { @from @up { '0' 'n' } } } } } }");
+    assert_eq!(v.to_string(), "@struct {
+  @bind hs { @column {
+    @struct {
+      @bind n { 'a' }
+    }
+    @struct {
+      @bind m { 'a' }
+    }
+  } }
+}");
+}
+
+#[test]
+fn test_eval_shares_tree() {
+    // a document's cells point into its tree rather than holding copies
+    let doc = ::parse_str("@struct { @bind a { 'x' } @bind s { @struct { @bind b { @from @up { 'a' } } } } }");
+    let e = doc.env();
+    assert_eq!(e.get(&["s", "b"]), text("x"));
+    let shared = |v: &Value, k: &str| match *v {
+        Value::Sheet(ref s) => s[k].node.borrow().as_ref().is_some_and(|n| Rc::ptr_eq(&n.0, &doc.arena)),
+        _ => false,
+    };
+    let root = e.peek(&[]);
+    assert!(shared(&root, "a") && shared(&root, "s") && shared(&e.peek(&["s".to_string()]), "b"));
+}
+
+#[test]
+fn test_eval_from_column() {
+    assert_eq!(seval("@struct { @bind c { @column { 'x' 'y' } } @bind d { @from @my { 'c' '1' } } }"),
+        sheet(vec![("c", Value::Column(vec![text("x"), text("y")].into())), ("d", text("y"))]));
+}
+
+#[test]
+fn test_eval_call() {
+    assert_eq!(seval("@call @from @sys { 'text' 'reverse' } { @bind a { 'abc' } }"), text("cba"));
+}
+
+#[test]
+fn test_eval_circular() {
+    assert_eq!(seval("@struct { @bind a { @from @my { 'a' } } }"),
+        sheet(vec![("a", fail(Code::Circular, "Circular reference: root.a -> root.a"))]));
+}
+
+#[test]
+fn test_eval_missing() {
+    assert_eq!(seval("@from @root { 'a' }"), fail(Code::Circular, "Circular reference: root -> root"));
+    assert_eq!(seval("@struct { @bind b { @from @my { 'a' } } }"),
+        sheet(vec![("b", fail(Code::NoField, "No field a"))]));
+}
+
+#[test]
+fn test_display() {
+    let v = seval("@struct { @bind b { @column { 'x' } } @bind 'a b' { @struct {} } }");
+    assert_eq!(v.to_string(), "@struct {\n  @bind 'a b' { @struct {} }\n  @bind b { @column {\n    'x'\n  } }\n}");
+}
+
+#[cfg(test)]
+fn beval(b: EnvBuilder, s: &str) -> Value {
+    b.build(&ast::parse(lex::slex(s))).eval()
+}
+
+#[test]
+fn test_register_lib() {
+    let b = Env::builder().register("host.greet", &["who"], |a| {
+        Ok(Value::Text(format!("hi {}", a.text("who")?)))
+    });
+    assert_eq!(beval(b, "@call @from @lib { 'host' 'greet' } { @bind who { 'bob' } }"), text("hi bob"));
+}
+
+#[test]
+fn test_register_namespaces() {
+    let b = Env::builder()
+        .register("a.b.one", &[], |_| Ok(text("1")))
+        .register("a.two", &[], |_| Ok(text("2")))
+        .register_sys("text.same", &["a"], |a| Ok(a.get("a")?.clone()));
+    assert_eq!(beval(b, "@column {
+        @call @from @lib { 'a' 'b' 'one' } {}
+        @call @from @lib { 'a' 'two' } {}
+        @call @from @sys { 'text' 'same' } { @bind a { 'x' } }
+        @call @from @sys { 'text' 'reverse' } { @bind a { 'xy' } } }"),
+        Value::Column(vec![text("1"), text("2"), text("x"), text("yx")].into()));
+}
+
+#[test]
+fn test_native_arity() {
+    let rev = "@call @from @sys { 'text' 'reverse' }";
+    assert_eq!(seval(&format!("{} {{}}", rev)),
+        fail(Code::MissingArgument, "@sys.text.reverse expects argument 'a'"));
+    assert_eq!(seval(&format!("{} {{ @bind a {{ 'x' }} @bind b {{ 'y' }} }}", rev)),
+        fail(Code::UnexpectedArgument, "@sys.text.reverse takes no argument 'b'"));
+}
+
+#[test]
+fn test_native_types() {
+    let rev = "@call @from @sys { 'text' 'reverse' }";
+    assert_eq!(seval(&format!("{} {{ @bind a {{ @column {{}} }} }}", rev)),
+        fail(Code::WrongArgumentType, "@sys.text.reverse expects text argument 'a'"));
+    assert_eq!(seval(&format!("{} {{ @bind a {{ @from @my {{ 'x' }} }} }}", rev)),
+        fail(Code::Circular, "Circular reference: root -> root"));
+}
+
+#[cfg(test)]
+fn expr(s: &str) -> ast::Expr {
+    ast::parse(lex::slex(s))
+}
+
+#[test]
+fn test_set_recomputes_readers() {
+    let e = create_env(expr("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } @bind c { @from @my { 'b' } }
+        @bind d { 'y' } @bind f { @from @my { 'd' } } }"));
+    e.eval();
+    assert_eq!(e.steps(), 6);
+    e.set(&["a"], expr("'z'")).unwrap();
+    assert_eq!(e.eval(), sheet(vec![("a", text("z")), ("b", text("z")), ("c", text("z")), ("d", text("y")), ("f", text("y"))]));
+    assert_eq!(e.steps(), 9);
+    e.set(&["d"], expr("'w'")).unwrap();
+    assert_eq!(e.at(&["f".to_string()]), text("w"));
+    assert_eq!(e.steps(), 11);
+}
+
+#[test]
+fn test_set_inside_struct() {
+    let e = create_env(expr("@struct { @bind s { @struct { @bind x { 'x' } @bind y { 'y' } } }
+        @bind t { @from @my { 's' 'x' } } @bind u { @from @my { 's' } } @bind v { @from @my { 's' 'y' } } }"));
+    let at = |k: &str| e.at(&[k.to_string()]);
+    at("t");
+    at("v");
+    at("u");
+    assert_eq!(e.steps(), 7);
+    e.set(&["s", "x"], expr("'z'")).unwrap();
+    assert_eq!(at("t"), text("z"));
+    assert_eq!(at("u"), sheet(vec![("x", text("z")), ("y", text("y"))]));
+    assert_eq!(at("v"), text("y"));
+    // x, t, and u, which copied the struct around x
+    assert_eq!(e.steps(), 10);
+    e.set(&["s"], expr("@struct { @bind x { 'q' } }")).unwrap();
+    assert_eq!(at("v"), fail(Code::NoField, "No field y"));
+}
+
+#[test]
+fn test_set_chain_after_change() {
+    // b stops reading a, so changing a no longer touches it
+    let e = create_env(expr("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } @bind c { 'c' } }"));
+    e.eval();
+    e.set(&["b"], expr("@from @my { 'c' }")).unwrap();
+    e.eval();
+    let before = e.steps();
+    e.set(&["a"], expr("'y'")).unwrap();
+    e.eval();
+    assert_eq!(e.steps(), before + 1);
+}
+
+#[test]
+fn test_reads_share() {
+    let e = create_env(expr("@struct { @bind c { @column { 'x' } } @bind d { @from @my { 'c' } }
+        @bind s { @struct { @bind a { 'a' } } } @bind t { @from @my { 's' } } }"));
+    match (e.at(&["c".to_string()]), e.at(&["d".to_string()])) {
+        (Value::Column(a), Value::Column(b)) => assert!(Rc::ptr_eq(&a, &b)),
+        v => panic!("{:?}", v),
+    }
+    // t holds s itself, so s.a is computed once for both
+    e.at(&["t".to_string()]);
+    e.eval();
+    assert_eq!(e.steps(), 6);
+}
+
+#[test]
+fn test_get_computes_only_what_it_reads() {
+    let e = create_env(expr("@struct { @bind svc { @struct { @bind port { '80' } @bind host { @from @up { 'domain' } } } }
+        @bind domain { 'example.com' } @bind loop { @from @my { 'loop' } } @bind bad { @from @my { 'nope' } } }"));
+    assert_eq!(e.get(&["svc", "host"]), text("example.com"));
+    assert_eq!(e.steps(), 4); // the root, svc, host and domain
+    match e.get(&["svc"]) {
+        Value::Sheet(ref s) => assert_eq!(s["port"].value(), Some(text("80"))),
+        v => panic!("{}", v),
+    }
+    assert_eq!(e.steps(), 5);
+    assert_eq!(e.get(&["nope"]), fail(Code::NoField, "No field nope"));
+}
+
+#[test]
+fn test_trace() {
+    let e = create_env(expr("@struct { @bind a { 'x' }
+        @bind b { @call @from @sys { 'text' 'reverse' } { @bind a { @from @my { 'a' } } } } }"));
+    let steps = Rc::new(RefCell::new(vec![]));
+    let s = steps.clone();
+    e.trace(move |step, e| s.borrow_mut().push(format!("{} {}", e.computing().len(), step)));
+    e.get(&["b"]);
+    assert_eq!(*steps.borrow(), ["0 start root", "0 done root struct { a b }", "0 start root.b", "1 read root.a",
+        "1 start root.a", "1 done root.a 'x'", "1 call @sys.text.reverse a='x'", "0 done root.b 'x'"]);
+}
+
+#[test]
+fn test_reads_own_struct() {
+    // a reads the struct it is being computed into, so gets a copy, not itself
+    let e = create_env(expr("@struct { @bind a { @from @root {} } }"));
+    assert_eq!(e.eval().to_string(), "@struct {\n  @bind a { @struct {\n    @bind a { <in progress> }\n  } }\n}");
+}
+
+#[test]
+fn test_set_errors() {
+    let e = create_env(expr("@struct { @bind a { @column { 'x' } } }"));
+    assert_eq!(e.set(&["b"], expr("'y'")), Err("No field b".to_string()));
+    assert_eq!(e.set(&["a", "0"], expr("'y'")), Err("Element 0 of a column is not a cell".to_string()));
+    assert_eq!(e.set(&["a", "0", "k"], expr("'y'")), Err("Cannot look up k outside a struct or column".to_string()));
+}
+
+#[test]
+fn test_cycle_chain() {
+    let e = ::parse_str("@struct {\n  @bind a { @from @my { 'b' 'c' } }\n  @bind b { @struct { @bind c { @from @up { 'a' } } } }\n}").env();
+    let chain = "Circular reference: root.a (2:13) -> root.b.c (3:33) -> root.a";
+    assert_eq!(e.at(&["a".to_string()]), fail(Code::Circular, chain));
+    assert_eq!(e.at(&["b".to_string(), "c".to_string()]), fail(Code::Circular, chain));
+}
+
+#[test]
+fn test_cycle_marks_every_cell() {
+    // a would otherwise hold a column with the error inside
+    let e = create_env(expr("@struct { @bind a { @column { @from @my { 'b' } } } @bind b { @from @my { 'a' } } }"));
+    let chain = "Circular reference: root.a -> root.b -> root.a";
+    assert_eq!(e.at(&["a".to_string()]), fail(Code::Circular, chain));
+    assert_eq!(e.at(&["b".to_string()]), fail(Code::Circular, chain));
+    e.set(&["b"], expr("'x'")).unwrap();
+    assert_eq!(e.at(&["a".to_string()]), Value::Column(vec![text("x")].into()));
+}
+
+#[cfg(test)]
+fn error_at(v: Value) -> Error {
+    match v {
+        Value::Err(e) => e,
+        v => panic!("not an error: {}", v),
+    }
+}
+
+#[test]
+fn test_error_located() {
+    let e = ::parse_str("@struct {\n  @bind a { @from @my { 'b' } }\n  @bind b { @from @my { 'nope' } }\n}").env();
+    let err = error_at(e.at(&["a".to_string()]));
+    assert_eq!(err.code, Code::NoField);
+    assert_eq!(err.path, Some(vec!["a".to_string()]));
+    assert_eq!(err.line_col, Some((2, 13)));
+    assert_eq!(err.to_string(), "E0101 no-field: No field nope\n  at root.a (2:13)\n  at root.b (3:9)");
+}
+
+#[test]
+fn test_error_through_call() {
+    let e = ::parse_str("@struct { @bind a { @call @from @sys { 'text' 'reverse' } { @bind a { @from @my { 'b' } } } }
+        @bind b { @from @my { 'nope' } } }").env();
+    let err = error_at(e.at(&["a".to_string()]));
+    assert_eq!(err, Error::new(Code::NoField, "No field nope"));
+    let paths: Vec<_> = err.chain().iter().map(|e| e.path.clone().unwrap_or_default()).collect();
+    assert_eq!(paths, vec![vec!["a".to_string()], vec!["b".to_string()]]);
+    let err = error_at(e.evaluate(&expr("@from @root { 'a' }")));
+    assert_eq!(err.path, Some(vec!["a".to_string()]));
+}
+
+#[test]
+fn test_error_functions() {
+    let v = seval("@struct {
+        @bind r { @call @from @sys { 'error' 'raise' } { @bind message { 'no good' } } }
+        @bind c { @call @from @sys { 'error' 'catch' } { @bind value { @from @my { 'r' } } } }
+        @bind k { @call @from @sys { 'error' 'catch' } { @bind value { 'fine' } } }
+        @bind d { @call @from @sys { 'error' 'default' } { @bind value { @from @my { 'x' } } @bind default { 'dflt' } } }
+        @bind t { @call @from @sys { 'try' } { @bind value { 'v' } @bind default { 'dflt' } } } }");
+    assert_eq!(v, sheet(vec![
+        ("r", fail(Code::Raised, "no good")),
+        ("c", record(vec![("error", record(vec![("code", text("E0201")), ("name", text("raised")),
+            ("message", text("no good")), ("path", text("root.r"))]))])),
+        ("k", record(vec![("ok", text("fine"))])),
+        ("d", text("dflt")),
+        ("t", text("v")),
+    ]));
+}
+
+#[cfg(test)]
+fn chain(n: usize) -> String {
+    let binds: Vec<String> = (0..n).map(|i| format!("@bind a{} {{ @from @my {{ 'a{}' }} }}", i, i + 1)).collect();
+    format!("@struct {{ {} @bind a{} {{ 'end' }} }}", binds.join(" "), n)
+}
+
+#[test]
+fn test_limit_calls() {
+    let e = ::parse_str(&chain(20_000)).env();
+    assert_eq!(e.at(&["a0".to_string()]), fail(Code::DepthLimit, "More than 128 cells computing at once"));
+    let e = ::parse_str(&chain(100)).env();
+    assert_eq!(e.at(&["a0".to_string()]), text("end"));
+}
+
+#[test]
+fn test_limit_steps() {
+    let limits = Limits{steps: 50, ..Limits::default()};
+    let e = ::parse_str_with(&chain(100), &limits).env();
+    assert_eq!(e.at(&["a0".to_string()]), fail(Code::StepLimit, "More than 50 cells computed"));
+    // each evaluation has its own budget
+    assert_eq!(e.at(&["a60".to_string()]), text("end"));
+}
+
+#[test]
+fn test_limit_size() {
+    let limits = Limits{size: 3, ..Limits::default()};
+    let e = ::parse_str_with("@column { 'abc' 'abcd' @column { 'a' 'b' 'c' 'd' } }", &limits).env();
+    assert_eq!(e.eval(), Value::Column(vec![text("abc"), fail(Code::SizeLimit, "Text larger than 3"),
+        fail(Code::SizeLimit, "Column larger than 3")].into()));
+}
+
+#[test]
+fn test_limit_timeout() {
+    use std::time::Duration;
+    let limits = Limits{timeout: Some(Duration::from_millis(1)), ..Limits::default()};
+    // one cell reading tens of thousands of others, each calling a native
+    let n = 50_000;
+    let binds: Vec<String> = (0..n).map(|i| format!("@bind k{} {{ @call @from @sys {{ 'text' 'reverse' }} {{ @bind a {{ 'text' }} }} }}", i)).collect();
+    let reads: Vec<String> = (0..n).map(|i| format!("@from @my {{ 'k{}' }}", i)).collect();
+    let doc = format!("@struct {{ @bind all {{ @column {{ {} }} }} {} }}", reads.join(" "), binds.join(" "));
+    let e = ::parse_str_with(&doc, &limits).env();
+    assert_eq!(e.at(&["all".to_string()]), fail(Code::Timeout, "Evaluation took longer than 1ms"));
+    // while a short document is done in time
+    let e = ::parse_str_with("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } }", &limits).env();
+    assert_eq!(e.eval().to_string(), "@struct {\n  @bind a { 'x' }\n  @bind b { 'x' }\n}");
+}
+
+#[test]
+fn test_limit_deep_values() {
+    // each struct holds a copy of the one before, nested ever deeper
+    let binds: Vec<String> = (1..600).map(|i| format!("@bind s{} {{ @struct {{ @bind x {{ @from @up {{ 's{}' }} }} }} }}", i, i - 1)).collect();
+    let doc = ::parse_str(&format!("@struct {{ @bind s0 {{ 'x' }} {} }}", binds.join(" ")));
+    assert!(doc.diagnostics().is_empty());
+    match doc.env().eval() {
+        Value::Sheet(s) => assert_eq!(s.len(), 600),
+        v => panic!("not a struct: {}", v.kind()),
+    }
 }
@@ -0,0 +1,267 @@
+use std::fmt;
+use std::str::Chars;
+use std::iter::Peekable;
+
+// just enough JSON for talking to tools; objects keep their key order
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+pub fn obj(fields: Vec<(&str, Json)>) -> Json {
+    Json::Obj(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+pub fn str(s: &str) -> Json {
+    Json::Str(s.to_string())
+}
+
+pub fn num(n: usize) -> Json {
+    Json::Num(n as f64)
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Obj(ref fields) => fields.iter().find(|f| f.0 == key).map(|f| &f.1),
+            _ => None,
+        }
+    }
+
+    // follow object keys, e.g. at(&["params", "textDocument", "uri"])
+    pub fn at(&self, keys: &[&str]) -> Option<&Json> {
+        keys.iter().try_fold(self, |j, k| j.get(k))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Num(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(ref s) => write_str(f, s),
+            Json::Arr(ref xs) => {
+                write!(f, "[")?;
+                for (i, x) in xs.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { "," } else { "" }, x)?;
+                }
+                write!(f, "]")
+            },
+            Json::Obj(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    write!(f, "{}", if i > 0 { "," } else { "" })?;
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+type Src<'a> = Peekable<Chars<'a>>;
+
+fn skip_white(src: &mut Src) {
+    while let Some(&c) = src.peek() {
+        if !c.is_whitespace() {
+            return
+        }
+        src.next();
+    }
+}
+
+fn expect(src: &mut Src, word: &str, v: Json) -> Result<Json, String> {
+    for w in word.chars() {
+        if src.next() != Some(w) {
+            return Err(format!("expected {}", word))
+        }
+    }
+    Ok(v)
+}
+
+fn parse_hex(src: &mut Src) -> Result<u32, String> {
+    let hex: String = src.by_ref().take(4).collect();
+    u32::from_str_radix(&hex, 16).map_err(|_| format!("bad \\u escape {}", hex))
+}
+
+fn parse_str(src: &mut Src) -> Result<String, String> {
+    let mut s = String::new();
+    loop {
+        match src.next() {
+            None => return Err("unterminated string".to_string()),
+            Some('"') => return Ok(s),
+            Some('\\') => match src.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('b') => s.push('\u{8}'),
+                Some('f') => s.push('\u{c}'),
+                Some('u') => {
+                    let mut n = parse_hex(src)?;
+                    // a high surrogate pairs with a low one after it; alone it is U+FFFD
+                    let mut ahead = src.clone();
+                    if (0xd800..0xdc00).contains(&n) && ahead.next() == Some('\\') && ahead.next() == Some('u') {
+                        match parse_hex(&mut ahead) {
+                            Ok(low) if (0xdc00..0xe000).contains(&low) => {
+                                n = 0x10000 + ((n - 0xd800) << 10) + (low - 0xdc00);
+                                *src = ahead;
+                            },
+                            _ => {},
+                        }
+                    }
+                    s.push(::std::char::from_u32(n).unwrap_or('\u{fffd}'));
+                },
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            },
+            Some(c) => s.push(c),
+        }
+    }
+}
+
+fn parse_value(src: &mut Src) -> Result<Json, String> {
+    skip_white(src);
+    match src.peek().cloned() {
+        Some('n') => expect(src, "null", Json::Null),
+        Some('t') => expect(src, "true", Json::Bool(true)),
+        Some('f') => expect(src, "false", Json::Bool(false)),
+        Some('"') => {
+            src.next();
+            Ok(Json::Str(parse_str(src)?))
+        },
+        Some('[') => {
+            src.next();
+            let mut xs = vec![];
+            loop {
+                skip_white(src);
+                if src.peek() == Some(&']') && xs.is_empty() {
+                    src.next();
+                    return Ok(Json::Arr(xs))
+                }
+                xs.push(parse_value(src)?);
+                skip_white(src);
+                match src.next() {
+                    Some(',') => {},
+                    Some(']') => return Ok(Json::Arr(xs)),
+                    _ => return Err("expected , or ]".to_string()),
+                }
+            }
+        },
+        Some('{') => {
+            src.next();
+            let mut fields = vec![];
+            loop {
+                skip_white(src);
+                match src.next() {
+                    Some('}') if fields.is_empty() => return Ok(Json::Obj(fields)),
+                    Some('"') => {},
+                    _ => return Err("expected key".to_string()),
+                }
+                let k = parse_str(src)?;
+                skip_white(src);
+                if src.next() != Some(':') {
+                    return Err("expected :".to_string())
+                }
+                fields.push((k, parse_value(src)?));
+                skip_white(src);
+                match src.next() {
+                    Some(',') => {},
+                    Some('}') => return Ok(Json::Obj(fields)),
+                    _ => return Err("expected , or }".to_string()),
+                }
+            }
+        },
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut n = String::new();
+            while let Some(&c) = src.peek() {
+                if c.is_ascii_digit() || "+-.eE".contains(c) {
+                    n.push(c);
+                    src.next();
+                } else {
+                    break
+                }
+            }
+            n.parse::<f64>().map(Json::Num).map_err(|_| format!("bad number {}", n))
+        },
+        _ => Err("expected a value".to_string()),
+    }
+}
+
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut src = text.chars().peekable();
+    let v = parse_value(&mut src)?;
+    skip_white(&mut src);
+    match src.next() {
+        None => Ok(v),
+        Some(_) => Err("trailing characters".to_string()),
+    }
+}
+
+#[test]
+fn test_json_round_trip() {
+    let text = r#"{"a":[1,2.5,-3],"b":{"c":null,"d":true,"e":false},"f":"x\"y\\z\n","g":[],"h":{}}"#;
+    assert_eq!(parse(text).unwrap().to_string(), text);
+}
+
+#[test]
+fn test_json_unicode() {
+    assert_eq!(parse(r#" "\u00e9\ud83d\ude00" "#), Ok(str("é😀")));
+    assert_eq!(str("\u{1}").to_string(), "\"\\u0001\"");
+    // an unpaired surrogate is U+FFFD, and what follows it is kept
+    assert_eq!(parse(r#" "\ud800\u0041" "#), Ok(str("\u{fffd}A")));
+    assert_eq!(parse(r#" "\ud800" "#), Ok(str("\u{fffd}")));
+    assert_eq!(parse(r#" "\ud800x\"" "#), Ok(str("\u{fffd}x\"")));
+    assert_eq!(parse(r#" "\udc00\ud800\udc00" "#), Ok(str("\u{fffd}\u{10000}")));
+}
+
+#[test]
+fn test_json_at() {
+    let j = parse(r#"{"params":{"position":{"line":3}}}"#).unwrap();
+    assert_eq!(j.at(&["params", "position", "line"]).and_then(|l| l.as_usize()), Some(3));
+    assert_eq!(j.at(&["params", "nope"]), None);
+}
+
+#[test]
+fn test_json_errors() {
+    assert!(parse("{").is_err());
+    assert!(parse("[1,]").is_err());
+    assert!(parse("1 2").is_err());
+    assert!(parse("\"abc").is_err());
+}
@@ -0,0 +1,77 @@
+use lex;
+
+// Lay source out again: one space between tokens on a line, two spaces of
+// indent per open curl, at most one blank line.  Comments and the spelling
+// of literals are kept.  None if the source has lexer errors.
+pub fn format(text: &str) -> Option<String> {
+    let toks = lex::lex_spans(text);
+    let mut out = String::with_capacity(text.len());
+    let mut depth = 0usize;
+    let mut newlines = 0usize; // seen since the last token written
+    let mut after_curl = false;
+    for (span, tok) in toks {
+        let piece = match tok {
+            lex::Tok::Error(_) => return None,
+            lex::Tok::Whitespace(ref w) => {
+                newlines = newlines.max(w.matches('\n').count());
+                continue
+            },
+            _ => text[span.lo..span.hi].trim_end(),
+        };
+        if tok == lex::Tok::CurlR {
+            depth = depth.saturating_sub(1);
+        }
+        if !out.is_empty() {
+            if newlines > 0 {
+                out.push_str(if newlines > 1 { "\n\n" } else { "\n" });
+                out.push_str(&"  ".repeat(depth));
+            } else if !(after_curl && tok == lex::Tok::CurlR) {
+                out.push(' ');
+            }
+        }
+        out.push_str(piece);
+        after_curl = tok == lex::Tok::CurlL;
+        newlines = match tok {
+            lex::Tok::Comment(_) => 1, // it ate the newline
+            _ => 0,
+        };
+        if tok == lex::Tok::CurlL {
+            depth += 1;
+        }
+    }
+    out.push('\n');
+    Some(out)
+}
+
+#[test]
+fn test_format_line() {
+    assert_eq!(format("@struct{@bind a   {'x'}  @bind b{}}"),
+        Some("@struct { @bind a { 'x' } @bind b {} }\n".to_string()));
+}
+
+#[test]
+fn test_format_indent() {
+    let src = "@struct {\n@bind a {\n   @column { n'x'\nb\n\n\n\n  }}\n    }";
+    assert_eq!(format(src),
+        Some("@struct {\n  @bind a {\n    @column { n'x'\n      b\n\n    } }\n}\n".to_string()));
+}
+
+#[test]
+fn test_format_comments() {
+    let src = "@column { # first\n'a'   # second  \n  # own line\n}";
+    assert_eq!(format(src),
+        Some("@column { # first\n  'a' # second\n  # own line\n}\n".to_string()));
+}
+
+#[test]
+fn test_format_idempotent() {
+    for src in &["@struct{@bind a {@column{'x' 'y'}}}", "@column {\n# c\n\n'a' }", "'x' # end"] {
+        let once = format(src).unwrap();
+        assert_eq!(format(&once), Some(once.clone()));
+    }
+}
+
+#[test]
+fn test_format_lex_error() {
+    assert_eq!(format("@column { _ }"), None);
+}
@@ -1,13 +1,17 @@
 use std;
 use std::str::FromStr;
 use std::fmt;
+use std::io;
+use std::borrow::Cow;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Key {
     Bind,
     Call,
     Column,
     From,
+    Import,
+    Lib,
     My,
     Root,
     Struct,
@@ -15,6 +19,13 @@ pub enum Key {
     Up,
 }
 
+impl Key {
+    pub fn all() -> Vec<Key> {
+        vec![Key::Bind, Key::Call, Key::Column, Key::From, Key::Import, Key::Lib,
+            Key::My, Key::Root, Key::Struct, Key::Sys, Key::Up]
+    }
+}
+
 #[test]
 fn test_peq_bind() {
     assert!(Key::Bind == Key::Bind);
@@ -34,6 +45,8 @@ impl fmt::Display for Key {
             Key::Call => "call",
             Key::Column => "column",
             Key::From => "from",
+            Key::Import => "import",
+            Key::Lib => "lib",
             Key::My => "my",
             Key::Root => "root",
             Key::Struct => "struct",
@@ -101,7 +114,7 @@ fn empty_literal() -> String {
     String::with_capacity(20)
 }
 
-fn lex_white(first_char: char, chars: &mut std::str::Chars)
+fn lex_white<I: Iterator<Item = char>>(first_char: char, chars: &mut I)
     -> (Option<char>, Tok) {
   let (oc, word) = read_to(chars, |c| !c.is_whitespace());
   (oc, Tok::Whitespace(first_char.to_string()+&word))
@@ -115,6 +128,8 @@ impl std::str::FromStr for Key {
             "call" => Ok(Key::Call),
             "column" => Ok(Key::Column),
             "from" => Ok(Key::From),
+            "import" => Ok(Key::Import),
+            "lib" => Ok(Key::Lib),
             "my" => Ok(Key::My),
             "root" => Ok(Key::Root),
             "struct" => Ok(Key::Struct),
@@ -132,17 +147,17 @@ fn at_tok(word: String) -> Tok {
     }
 }
 
-fn lex_at(chars: &mut std::str::Chars) -> (Option<char>, Tok) {
+fn lex_at<I: Iterator<Item = char>>(chars: &mut I) -> (Option<char>, Tok) {
     let (oc, word) = read_to(chars, |c| !c.is_alphanumeric());
     (oc, at_tok(word))
 }
 
-fn lex_hash(chars: &mut std::str::Chars) -> Tok {
+fn lex_hash<I: Iterator<Item = char>>(chars: &mut I) -> Tok {
     let (_, word) = read_to(chars, |c| c == '\n');
-    return Tok::Comment(word);
+    Tok::Comment(word)
 }
 
-fn lex_esc(chars: &mut std::str::Chars, digit_esc: bool, quote_esc: bool,
+fn lex_esc<I: Iterator<Item = char>>(chars: &mut I, digit_esc: bool, quote_esc: bool,
         tab_esc: bool) -> Option<char> {
     let nc = chars.next();
     //println!("*lex_esc nc* {:?}",nc);
@@ -169,8 +184,8 @@ fn lex_esc(chars: &mut std::str::Chars, digit_esc: bool, quote_esc: bool,
     }
 }
 
-fn lex_lit(allow_newline: bool, allow_backslash: bool,
-        qc: char, chars: &mut std::str::Chars) -> Tok {
+fn lex_lit<I: Iterator<Item = char>>(allow_newline: bool, allow_backslash: bool,
+        qc: char, chars: &mut I) -> Tok {
     let mut lit = String::with_capacity(20);
     loop {
         let (oc, word) = read_to(chars, |c| {
@@ -199,18 +214,18 @@ fn lex_lit(allow_newline: bool, allow_backslash: bool,
     }
 }
 
-fn lex_lits(flags : String, qc: char, chars: &mut std::str::Chars) -> Tok {
+fn lex_lits<I: Iterator<Item = char>>(flags : String, qc: char, chars: &mut I) -> Tok {
     assert!(qc == '"' || qc == '\'');
     let allow_newline = flags == "n";
     let allow_backslash = qc == '"';
 
-    if flags != "n" && flags != "" {
+    if flags != "n" && !flags.is_empty() {
         return Tok::Error(format!("Invalid quote prefix: {}",flags))
     }
     lex_lit(allow_newline, allow_backslash, qc, chars)
 }
 
-fn lex_bare(first_char: char, chars: &mut std::str::Chars) -> (Option<char>, Tok) {
+fn lex_bare<I: Iterator<Item = char>>(first_char: char, chars: &mut I) -> (Option<char>, Tok) {
     let mut word = empty_literal();
     word.push(first_char);
     while let Some(c) = chars.next() {
@@ -226,11 +241,11 @@ fn lex_bare(first_char: char, chars: &mut std::str::Chars) -> (Option<char>, Tok
     (None, Tok::Literal(word))
 }
 
-fn read_to<F>(chars: &mut std::str::Chars, is_end: F)
+fn read_to<I: Iterator<Item = char>, F>(chars: &mut I, is_end: F)
     -> (Option<char>, String)
     where F: Fn(char) -> bool {
   let mut word = empty_literal();
-  while let Some(c) = chars.next() {
+  for c in chars.by_ref() {
       //println!("*read_to c* {:?}",c);
       if is_end(c) {
           return (Some(c), word)
@@ -246,7 +261,7 @@ fn some_tok(pair: (Option<char>, Tok)) -> (Option<char>, Option<Tok>) {
     (oc, Some(tok))
 }
 
-fn lex_tok(first_char: Option<char>, chars: &mut std::str::Chars)
+fn lex_tok<I: Iterator<Item = char>>(first_char: Option<char>, chars: &mut I)
         -> (Option<char>, Option<Tok>) {
     let c = match first_char {
         Some(fc) => fc,
@@ -275,14 +290,32 @@ fn lex_tok(first_char: Option<char>, chars: &mut std::str::Chars)
     }
 }
 
+#[cfg(test)]
 pub fn lex(text: String) -> Vec<Tok> {
-    let mut tokens : Vec<Tok> = vec![];
+    lex_spans(&text).into_iter().map(|(_, tok)| tok).collect()
+}
+
+// byte offsets into the source text, hi exclusive
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub lo: usize,
+    pub hi: usize,
+}
+
+// offset of the next unread char, counting the pushed-back one
+fn offset(text: &str, chars: &std::str::Chars, oc: Option<char>) -> usize {
+    text.len() - chars.as_str().len() - oc.map_or(0, |c| c.len_utf8())
+}
+
+pub fn lex_spans(text: &str) -> Vec<(Span, Tok)> {
+    let mut tokens : Vec<(Span, Tok)> = vec![];
     let mut chars = text.chars();
     let mut oc: Option<char> = None;
     loop {
+        let lo = offset(text, &chars, oc);
         let (noc, otok) = lex_tok(oc, &mut chars);
         match otok {
-            Some(tok) => tokens.push(tok),
+            Some(tok) => tokens.push((Span{lo, hi: offset(text, &chars, noc)}, tok)),
             None => break,
         }
         oc = noc;
@@ -290,11 +323,313 @@ pub fn lex(text: String) -> Vec<Tok> {
     tokens
 }
 
+// the chars of a reader, decoded as they come, whatever the chunks; ends at
+// the first read error or bad UTF-8, and keeps it
+struct ReadChars<R> {
+    r: R,
+    offset: usize, // bytes decoded
+    error: Option<String>,
+}
+
+impl<R: io::BufRead> Iterator for ReadChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let mut bytes = [0u8; 4];
+        let mut n = 0;
+        while self.error.is_none() {
+            let b = match self.r.fill_buf() {
+                Ok([]) => {
+                    if n > 0 {
+                        self.error = Some(format!("Invalid UTF-8 at byte {}", self.offset));
+                    }
+                    return None
+                },
+                Ok(buf) => buf[0],
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.error = Some(format!("Read error: {}", e));
+                    return None
+                },
+            };
+            self.r.consume(1);
+            bytes[n] = b;
+            n += 1;
+            match std::str::from_utf8(&bytes[..n]) {
+                Ok(s) => {
+                    self.offset += n;
+                    return s.chars().next()
+                },
+                // the rest of the char is still to come
+                Err(e) if e.error_len().is_none() => {},
+                Err(_) => self.error = Some(format!("Invalid UTF-8 at byte {}", self.offset)),
+            }
+        }
+        None
+    }
+}
+
+// Tokens read as they are needed from a reader, for sources too big to hold
+// in one String; lex_spans gives the same tokens and spans.
+pub struct Lexer<R> {
+    chars: ReadChars<R>,
+    pending: Option<char>, // read past the end of the last token
+    done: bool,
+}
+
+impl<R: io::Read> Lexer<io::BufReader<R>> {
+    pub fn from_read(r: R) -> Lexer<io::BufReader<R>> {
+        Lexer::new(io::BufReader::new(r))
+    }
+}
+
+impl<R: io::BufRead> Lexer<R> {
+    pub fn new(r: R) -> Lexer<R> {
+        Lexer{chars: ReadChars{r, offset: 0, error: None}, pending: None, done: false}
+    }
+
+    // byte offset of the next unread char
+    fn offset(&self) -> usize {
+        self.chars.offset - self.pending.map_or(0, |c| c.len_utf8())
+    }
+
+    // the next token with where it is; a read error or bad UTF-8 ends the
+    // tokens with an error
+    pub fn next_spanned(&mut self) -> Option<(Span, Tok)> {
+        if self.done {
+            return None
+        }
+        let lo = self.offset();
+        let (pending, otok) = lex_tok(self.pending.take(), &mut self.chars);
+        self.pending = pending;
+        let hi = self.offset();
+        if let Some(tok) = otok {
+            return Some((Span{lo, hi}, tok))
+        }
+        self.done = true;
+        self.chars.error.take().map(|e| (Span{lo, hi}, Tok::Error(e)))
+    }
+
+    // the tokens with where they are, as from lex_spans
+    pub fn spanned(self) -> Spanned<R> {
+        Spanned(self)
+    }
+}
+
+pub struct Spanned<R>(Lexer<R>);
+
+impl<R: io::BufRead> Iterator for Spanned<R> {
+    type Item = (Span, Tok);
+
+    fn next(&mut self) -> Option<(Span, Tok)> {
+        self.0.next_spanned()
+    }
+}
+
+impl<R: io::BufRead> Iterator for Lexer<R> {
+    type Item = Tok;
+
+    fn next(&mut self) -> Option<Tok> {
+        self.next_spanned().map(|(_, tok)| tok)
+    }
+}
+
+// A token whose text is borrowed from the source: only a literal with
+// escapes needs a String of its own.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Token<'a> {
+    CurlL,
+    CurlR,
+    Key(Key),
+    Literal(Cow<'a, str>),
+    Whitespace(&'a str),
+    Comment(&'a str),
+    Error(String),
+}
+
+impl<'a> From<Token<'a>> for Tok {
+    fn from(t: Token<'a>) -> Tok {
+        match t {
+            Token::CurlL => Tok::CurlL,
+            Token::CurlR => Tok::CurlR,
+            Token::Key(k) => Tok::Key(k),
+            Token::Literal(s) => Tok::Literal(s.into_owned()),
+            Token::Whitespace(s) => Tok::Whitespace(s.to_string()),
+            Token::Comment(s) => Tok::Comment(s.to_string()),
+            Token::Error(e) => Tok::Error(e),
+        }
+    }
+}
+
+// the tokens and spans of lex_spans, borrowing from the text
+pub struct Tokens<'a> {
+    text: &'a str,
+    at: usize, // byte offset of the next token
+}
+
+pub fn tokens<'a>(text: &'a str) -> Tokens<'a> {
+    Tokens{text, at: 0}
+}
+
+// offset of the first char from `from` that ends a word, or the end
+fn scan<F: Fn(char) -> bool>(s: &str, from: usize, is_end: F) -> usize {
+    s[from..].find(is_end).map_or(s.len(), |n| from + n)
+}
+
+// a literal from just after its opening quote, and its length with the quote
+fn borrow_lit<'a>(s: &'a str, from: usize, allow_newline: bool, allow_backslash: bool, qc: char)
+        -> (usize, Token<'a>) {
+    let mut owned: Option<String> = None;
+    let mut start = from;
+    loop {
+        let end = scan(s, start, |c| {
+            c == qc
+            || (!allow_newline && c == '\n')
+            || (allow_backslash && c == '\\')
+        });
+        let word = &s[start..end];
+        match s[end..].chars().next() {
+            Some('\n') if !allow_newline => return (end + 1, Token::Error("Newline in Literal".to_string())),
+            Some('\\') if allow_backslash => {
+                let mut chars = s[end + 1..].chars();
+                let xc = lex_esc(&mut chars, true, true, true);
+                let next = s.len() - chars.as_str().len();
+                match xc {
+                    Some(c) => {
+                        let lit = owned.get_or_insert_with(empty_literal);
+                        lit.push_str(word);
+                        lit.push(c);
+                    },
+                    None => return (next, Token::Error("Invalid backslash escape sequence in Literal".to_string())),
+                }
+                start = next;
+            },
+            Some(_) => {
+                let lit = match owned {
+                    None => Cow::Borrowed(word),
+                    Some(lit) => Cow::Owned(lit + word),
+                };
+                return (end + 1, Token::Literal(lit))
+            },
+            None => return (end, Token::Error("EOF in Literal".to_string())),
+        }
+    }
+}
+
+// the token at the start of s, and its length
+fn borrow_tok<'a>(s: &'a str, c: char) -> (usize, Token<'a>) {
+    if c.is_whitespace() {
+        let n = scan(s, c.len_utf8(), |c| !c.is_whitespace());
+        (n, Token::Whitespace(&s[..n]))
+    } else if c.is_alphanumeric() {
+        let n = scan(s, 0, |c| !c.is_alphanumeric());
+        match s[n..].chars().next() {
+            Some(qc) if qc == '"' || qc == '\'' => {
+                if &s[..n] != "n" {
+                    return (n + 1, Token::Error(format!("Invalid quote prefix: {}", &s[..n])))
+                }
+                borrow_lit(s, n + 1, true, qc == '"', qc)
+            },
+            _ => (n, Token::Literal(Cow::Borrowed(&s[..n]))),
+        }
+    } else if c == '{' {
+        (1, Token::CurlL)
+    } else if c == '}' {
+        (1, Token::CurlR)
+    } else if c == '\'' {
+        borrow_lit(s, 1, false, true, '\'')
+    } else if c == '@' {
+        let n = scan(s, 1, |c| !c.is_alphanumeric());
+        match Key::from_str(&s[1..n]) {
+            Ok(k) => (n, Token::Key(k)),
+            Err(_) => (n, Token::Error(format!("Invalid key: @{}", &s[1..n]))),
+        }
+    } else if c == '#' {
+        let n = scan(s, 1, |c| c == '\n');
+        (s.len().min(n + 1), Token::Comment(&s[1..n]))
+    } else {
+        (c.len_utf8(), Token::Error(format!("Bad char: {}", c)))
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (Span, Token<'a>);
+
+    fn next(&mut self) -> Option<(Span, Token<'a>)> {
+        let s = &self.text[self.at..];
+        let c = s.chars().next()?;
+        let (n, tok) = borrow_tok(s, c);
+        let lo = self.at;
+        self.at += n;
+        Some((Span{lo, hi: self.at}, tok))
+    }
+}
+
+// 1-based line and column (in chars) of a byte offset
+pub fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = match before.rfind('\n') {
+        Some(nl) => before[nl+1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+    (line, col)
+}
+
+// write text back as a literal the lexer reads as the same text
+pub fn quote(text: &str) -> String {
+    let mut q = String::with_capacity(text.len() + 2);
+    if text.contains('\'') {
+        q.push_str("n\"");
+        for c in text.chars() {
+            match c {
+                '\\' => q.push_str("\\\\"),
+                '"' => q.push_str("\\\""),
+                _ => q.push(c),
+            }
+        }
+        q.push('"');
+    } else {
+        q.push('\'');
+        for c in text.chars() {
+            match c {
+                '\\' => q.push_str("\\\\"),
+                '\n' => q.push_str("\\n"),
+                '\t' => q.push_str("\\t"),
+                '\0' => q.push_str("\\0"),
+                _ => q.push(c),
+            }
+        }
+        q.push('\'');
+    }
+    q
+}
+
+// bind names stay bare when the lexer allows it
+pub fn quote_name(name: &str) -> String {
+    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric()) {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
 ///////////////////////////////////////////////////////////// tests
 
 #[cfg(test)]
 pub fn slex(text: &str) -> Vec<Tok> {
-    lex(text.to_string())
+    // the streaming lexer must agree, even reading a byte at a time
+    let spans = lex_spans(text);
+    let mut streamed = vec![];
+    let mut l = Lexer::new(io::BufReader::with_capacity(1, text.as_bytes()));
+    while let Some(t) = l.next_spanned() {
+        streamed.push(t);
+    }
+    assert_eq!(streamed, spans);
+    // and the borrowing one
+    assert_eq!(tokens(text).map(|(span, t)| (span, Tok::from(t))).collect::<Vec<_>>(), spans);
+    assert_eq!(Lexer::from_read(text.as_bytes()).collect::<Vec<Tok>>(), lex(text.to_string()));
+    spans.into_iter().map(|(_, tok)| tok).collect()
 }
 
 #[test]
@@ -317,10 +652,7 @@ fn test_bare() {
 
 #[cfg(test)]
 fn is_error(t: &Tok) -> bool {
-    match t {
-        &Tok::Error(_) => true,
-        _ => false,
-    }
+    matches!(*t, Tok::Error(_))
 }
 
 #[cfg(test)]
@@ -389,6 +721,23 @@ fn test_key_from() {
     assert_eq!(slex("@from"), vec![Tok::Key(Key::From)]);
 }
 
+#[test]
+fn test_key_import() {
+    assert_eq!(slex("@import"), vec![Tok::Key(Key::Import)]);
+}
+
+#[test]
+fn test_key_all() {
+    for k in Key::all() {
+        assert_eq!(slex(&format!("@{}", k)), vec![Tok::Key(k)]);
+    }
+}
+
+#[test]
+fn test_key_lib() {
+    assert_eq!(slex("@lib"), vec![Tok::Key(Key::Lib)]);
+}
+
 #[test]
 fn test_key_my() {
     assert_eq!(slex("@my"), vec![Tok::Key(Key::My)]);
@@ -446,3 +795,81 @@ fn test_curl_l() {
 fn test_curl_r() {
     assert_eq!(slex("}"), vec![Tok::CurlR]);
 }
+
+#[test]
+fn test_spans() {
+    slex("@bind x {'y'}");
+    let a = lex_spans("@bind x {'y'}");
+    let spans: Vec<Span> = a.into_iter().map(|(s, _)| s).collect();
+    assert_eq!(spans, vec![
+        Span{lo: 0, hi: 5}, Span{lo: 5, hi: 6}, Span{lo: 6, hi: 7},
+        Span{lo: 7, hi: 8}, Span{lo: 8, hi: 9}, Span{lo: 9, hi: 12},
+        Span{lo: 12, hi: 13},
+    ]);
+}
+
+#[test]
+fn test_line_col() {
+    let t = "ab\n  é x";
+    assert_eq!(line_col(t, 0), (1, 1));
+    assert_eq!(line_col(t, 3), (2, 1));
+    assert_eq!(line_col(t, 8), (2, 5));
+}
+
+#[test]
+fn test_quote() {
+    for t in &["", "abc", "a b", "a\nb\t\\c", "it's \"so\"\n", "\0"] {
+        assert_eq!(slex(&quote(t)), vec![Tok::Literal(t.to_string())]);
+    }
+}
+
+#[test]
+fn test_quote_name() {
+    assert_eq!(quote_name("abc1"), "abc1");
+    assert_eq!(quote_name("a b"), "'a b'");
+    assert_eq!(quote_name(""), "''");
+}
+
+#[test]
+fn test_stream_utf8() {
+    let text = "@column { 'ab\u{e9}\u{20ac}\u{1f600}' x\u{3b1}\u{3b2} } # \u{fc}ber";
+    assert_eq!(slex(text).len(), 11);
+    // a char split across reads, then bad bytes
+    let bytes = [b'\'', 0xe2, 0x82, 0xac, b'\'', b' ', 0xff, b'x'];
+    let toks: Vec<Tok> = Lexer::new(io::BufReader::with_capacity(2, &bytes[..])).collect();
+    assert_eq!(toks, vec![Tok::Literal("\u{20ac}".to_string()), Tok::Whitespace(" ".to_string()),
+        Tok::Error("Invalid UTF-8 at byte 6".to_string())]);
+    let cut = [b'\'', b'a', 0xe2, 0x82];
+    let toks: Vec<Tok> = Lexer::from_read(&cut[..]).collect();
+    assert_eq!(toks, vec![Tok::Error("EOF in Literal".to_string()), Tok::Error("Invalid UTF-8 at byte 2".to_string())]);
+}
+
+#[test]
+fn test_stream_large() {
+    // a column of many literals, read through a small buffer
+    let n = 20_000;
+    let mut text = String::from("@column {");
+    for i in 0..n {
+        text.push_str(&format!(" 'v{}'", i));
+    }
+    text.push_str(" }");
+    let lits = Lexer::new(io::BufReader::with_capacity(64, text.as_bytes()))
+        .filter(|t| matches!(*t, Tok::Literal(_)))
+        .count();
+    assert_eq!(lits, n);
+}
+
+#[test]
+fn test_borrowed() {
+    let text = "@struct { n\"it's\" 'x\\ny' n\"a\\\\b\" # c\n}";
+    let toks: Vec<Token> = tokens(text).map(|(_, t)| t).collect();
+    let lit = |t: &Token| match *t {
+        Token::Literal(Cow::Borrowed(s)) => Some((true, s.to_string())),
+        Token::Literal(Cow::Owned(ref s)) => Some((false, s.clone())),
+        _ => None,
+    };
+    assert_eq!(toks.iter().filter_map(lit).collect::<Vec<_>>(),
+        vec![(true, "it's".to_string()), (false, "x\ny".to_string()), (false, "a\\b".to_string())]);
+    assert_eq!(toks[toks.len() - 2], Token::Comment(" c"));
+    slex(text);
+}
@@ -0,0 +1,163 @@
+//! Read and evaluate bedval (`.bv`) documents: text literals, and
+//! columns and structs of values.
+
+mod lex;
+mod ast;
+mod fig;
+mod error;
+mod json;
+mod layout;
+mod cache;
+pub mod deps;
+pub mod types;
+pub mod schema;
+pub mod query;
+pub mod diff;
+pub mod merge;
+pub mod events;
+pub mod arena;
+pub mod debug;
+pub mod parallel;
+pub mod corpus;
+pub mod lsp;
+pub mod repl;
+pub mod watch;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+use std::rc::Rc;
+use std::time::Duration;
+
+pub use ast::{Bind, Expr};
+pub use error::{Code, Error};
+pub use fig::{Args, Cell, Env, EnvBuilder, Native, Struct, Trace, Value};
+pub use lex::{lex_spans, tokens, Key, Lexer, Span, Spanned, Tok, Token, Tokens};
+
+/// A lexer or parser error, located in the source text.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Bounds on the work a document may cause, for documents from elsewhere.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Limits {
+    /// expressions nested in the source, values nested in each other,
+    /// and keys in a @from
+    pub depth: usize,
+    /// cells computed by one evaluation
+    pub steps: usize,
+    /// cells computing at once, each waiting on the next
+    pub calls: usize,
+    /// bytes in a text, or elements in a column
+    pub size: usize,
+    /// time for one evaluation
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits{depth: 128, steps: 1_000_000, calls: 128, size: 1 << 24, timeout: None}
+    }
+}
+
+/// A parsed source: its text, syntax tree, and any errors found on the way.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Document {
+    file: Option<path::PathBuf>,
+    text: String,
+    expr: Expr,
+    arena: Rc<arena::Ast>, // the same tree, which cells point into
+    diagnostics: Vec<Diagnostic>,
+    sites: Vec<ast::Site>,
+    refs: Vec<ast::Ref>,
+    limits: Limits,
+}
+
+impl Document {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The file it was read from, if any.
+    pub fn file(&self) -> Option<&path::Path> {
+        self.file.as_deref()
+    }
+
+    /// The syntax tree as numbered nodes; with spans unless the source has
+    /// errors.
+    pub fn arena(&self) -> &arena::Ast {
+        &self.arena
+    }
+
+    /// An environment that evaluates on up to `threads` threads at once.
+    pub fn parallel_env(&self, threads: usize) -> parallel::Env {
+        let b = Env::builder().limits(self.limits.clone());
+        match self.file {
+            Some(ref f) => b.file(f).build_parallel(&self.expr, threads),
+            None => b.build_parallel(&self.expr, threads),
+        }
+    }
+
+    /// A fresh evaluation environment; nothing is computed until asked.
+    pub fn env(&self) -> Env {
+        let b = Env::builder().source(self).limits(self.limits.clone());
+        match self.file {
+            Some(ref f) => b.file(f).build_arena(&self.arena),
+            None => b.build_arena(&self.arena),
+        }
+    }
+}
+
+pub fn parse_str(text: &str) -> Document {
+    parse_str_with(text, &Limits::default())
+}
+
+/// Parse within the given limits, which its `env()` keeps to as well.
+pub fn parse_str_with(text: &str, limits: &Limits) -> Document {
+    let parsed = ast::parse_spans(lex::lex_spans(text), limits.depth);
+    let arena = arena::Ast::parse(text, limits.depth).unwrap_or_else(|_| arena::Ast::from_expr(&parsed.expr));
+    let diagnostics = parsed.errors.into_iter().map(|(span, message)| {
+        let (line, column) = lex::line_col(text, span.lo);
+        Diagnostic{span, line, column, message}
+    }).collect();
+    Document{file: None, text: text.to_string(), expr: parsed.expr, arena: Rc::new(arena), diagnostics,
+        sites: parsed.sites, refs: parsed.refs, limits: limits.clone()}
+}
+
+fn empty_text() -> String {
+    String::with_capacity(50)
+}
+
+pub fn parse_file<P: AsRef<path::Path>>(filename: P) -> io::Result<Document> {
+    parse_file_with(filename, &Limits::default())
+}
+
+pub fn parse_file_with<P: AsRef<path::Path>>(filename: P, limits: &Limits) -> io::Result<Document> {
+    let mut fr = fs::File::open(filename.as_ref())?;
+    let mut s = empty_text();
+    fr.read_to_string(&mut s)?;
+    let mut doc = parse_str_with(&s, limits);
+    doc.file = Some(filename.as_ref().to_path_buf());
+    Ok(doc)
+}
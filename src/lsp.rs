@@ -0,0 +1,492 @@
+use ast;
+use fig;
+use json;
+use json::{num, obj, Json};
+use layout;
+use lex;
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use {parse_str, Document};
+
+// Language server over stdio: diagnostics, hover, definitions, symbols,
+// formatting and keyword completion for .bv files.
+pub fn serve<R: BufRead, W: Write>(mut input: R, out: &mut W) -> io::Result<()> {
+    let mut server = Server{docs: HashMap::new()};
+    loop {
+        let msg = match read_message(&mut input)? {
+            None => return Ok(()),
+            Some(Ok(msg)) => msg,
+            Some(Err(e)) => {
+                let err = obj(vec![("code", Json::Num(-32700.0)), ("message", json::str(&e))]);
+                write_message(out, &obj(vec![("jsonrpc", json::str("2.0")), ("id", Json::Null), ("error", err)]))?;
+                continue
+            },
+        };
+        if !server.handle(&msg, out)? {
+            return Ok(())
+        }
+    }
+}
+
+const MAX_MESSAGE: usize = 1 << 26;
+
+// None at end of input; Some(Err) for a message that cannot be read, which
+// is answered and passed over
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Result<Json, String>>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None)
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+    let len = match len {
+        Some(len) => len,
+        None => return Ok(Some(Err("missing Content-Length".to_string()))),
+    };
+    if len > MAX_MESSAGE {
+        // passed over unread, however long it turns out to be
+        io::copy(&mut input.by_ref().take(len as u64), &mut io::sink())?;
+        return Ok(Some(Err(format!("message longer than {} bytes", MAX_MESSAGE))))
+    }
+    // as the bytes come, not as many as the header claims
+    let mut body = vec![];
+    if input.by_ref().take(len as u64).read_to_end(&mut body)? < len {
+        return Ok(Some(Err("message cut short".to_string())))
+    }
+    match String::from_utf8(body) {
+        Ok(text) => Ok(Some(json::parse(&text))),
+        Err(_) => Ok(Some(Err("message is not UTF-8".to_string()))),
+    }
+}
+
+fn write_message<W: Write>(out: &mut W, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    obj(vec![("jsonrpc", json::str("2.0")), ("method", json::str(method)), ("params", params)])
+}
+
+// LSP counts lines from 0 and characters in UTF-16 units
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(|c| c.len_utf16()).sum();
+    obj(vec![("line", num(before.matches('\n').count())), ("character", num(character))])
+}
+
+fn offset(text: &str, pos: &Json) -> usize {
+    let line = pos.get("line").and_then(|l| l.as_usize()).unwrap_or(0);
+    let character = pos.get("character").and_then(|c| c.as_usize()).unwrap_or(0);
+    let mut lo = 0;
+    for _ in 0..line {
+        match text[lo..].find('\n') {
+            Some(i) => lo += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[lo..].char_indices() {
+        if units >= character || c == '\n' {
+            return lo + i
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, span: lex::Span) -> Json {
+    obj(vec![("start", position(text, span.lo)), ("end", position(text, span.hi))])
+}
+
+fn contains(span: lex::Span, at: usize) -> bool {
+    span.lo <= at && at <= span.hi
+}
+
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    let bytes = rest.as_bytes();
+    let mut path = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = rest.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                path.push(b);
+                i += 3;
+            },
+            (b, _) => {
+                path.push(b);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8(path).ok().map(PathBuf::from)
+}
+
+fn path_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+fn symbol_kind(x: Option<&ast::Expr>) -> usize {
+    match x {
+        Some(&ast::Expr::Struct(_)) => 23,
+        Some(&ast::Expr::Column(_)) => 18,
+        Some(&ast::Expr::Literal(_)) => 15,
+        Some(&ast::Expr::Call{..}) => 12,
+        Some(&ast::Expr::Import(_)) => 2,
+        _ => 13,
+    }
+}
+
+struct Server {
+    docs: HashMap<String, Document>,
+}
+
+impl Server {
+    fn open(&mut self, uri: &str, text: &str) -> Json {
+        let mut doc = parse_str(text);
+        doc.file = uri_path(uri);
+        let diags: Vec<Json> = doc.diagnostics().iter().map(|d| obj(vec![
+            ("range", range(text, d.span)),
+            ("severity", num(1)),
+            ("source", json::str("val")),
+            ("message", json::str(&d.message)),
+        ])).collect();
+        self.docs.insert(uri.to_string(), doc);
+        notification("textDocument/publishDiagnostics",
+            obj(vec![("uri", json::str(uri)), ("diagnostics", Json::Arr(diags))]))
+    }
+
+    // false once the client says exit
+    fn handle<W: Write>(&mut self, msg: &Json, out: &mut W) -> io::Result<bool> {
+        let method = msg.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params.at(&["textDocument", "uri"]).and_then(|u| u.as_str()).unwrap_or("").to_string();
+        let result = match method {
+            "initialize" => Ok(obj(vec![
+                ("capabilities", obj(vec![
+                    ("textDocumentSync", num(1)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true)),
+                    ("documentFormattingProvider", Json::Bool(true)),
+                    ("completionProvider", obj(vec![("triggerCharacters", Json::Arr(vec![json::str("@")]))])),
+                ])),
+                ("serverInfo", obj(vec![("name", json::str("val"))])),
+            ])),
+            "shutdown" => Ok(Json::Null),
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params.at(&["textDocument", "text"]).and_then(|t| t.as_str()).unwrap_or("");
+                let note = self.open(&uri, text);
+                write_message(out, &note)?;
+                return Ok(true)
+            },
+            "textDocument/didChange" => {
+                let text = match params.get("contentChanges") {
+                    Some(Json::Arr(changes)) => changes.last()
+                        .and_then(|c| c.get("text")).and_then(|t| t.as_str()).unwrap_or(""),
+                    _ => "",
+                };
+                let note = self.open(&uri, text);
+                write_message(out, &note)?;
+                return Ok(true)
+            },
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                write_message(out, &notification("textDocument/publishDiagnostics",
+                    obj(vec![("uri", json::str(&uri)), ("diagnostics", Json::Arr(vec![]))])))?;
+                return Ok(true)
+            },
+            _ => match self.docs.get(&uri) {
+                Some(doc) => {
+                    let at = offset(doc.text(), params.get("position").unwrap_or(&Json::Null));
+                    match method {
+                        "textDocument/hover" => Ok(hover(doc, at)),
+                        "textDocument/definition" => Ok(definition(doc, &uri, at)),
+                        "textDocument/documentSymbol" => Ok(Json::Arr(symbols(doc, &sorted_sites(doc)))),
+                        "textDocument/formatting" => Ok(formatting(doc)),
+                        "textDocument/completion" => Ok(completion(doc, at)),
+                        _ => Err((-32601, format!("unknown method {}", method))),
+                    }
+                },
+                None if method.starts_with("textDocument/") => Err((-32602, format!("unknown document {}", uri))),
+                None => Err((-32601, format!("unknown method {}", method))),
+            },
+        };
+        let id = match msg.get("id") {
+            Some(id) => id.clone(),
+            None => return Ok(true), // a notification wants no answer
+        };
+        let reply = match result {
+            Ok(r) => ("result", r),
+            Err((code, m)) => ("error", obj(vec![("code", Json::Num(code as f64)), ("message", json::str(&m))])),
+        };
+        write_message(out, &obj(vec![("jsonrpc", json::str("2.0")), ("id", id), reply]))?;
+        Ok(true)
+    }
+}
+
+// the innermost site around the offset
+fn site_at(doc: &Document, at: usize) -> Option<&ast::Site> {
+    doc.sites.iter().filter(|s| contains(s.whole, at)).min_by_key(|s| s.whole.hi - s.whole.lo)
+}
+
+fn ref_at(doc: &Document, at: usize) -> Option<&ast::Ref> {
+    doc.refs.iter().filter(|r| contains(r.span, at)).min_by_key(|r| r.span.hi - r.span.lo)
+}
+
+fn hover(doc: &Document, at: usize) -> Json {
+    let (path, span) = match (ref_at(doc, at), site_at(doc, at)) {
+        (Some(&ast::Ref{span, target: ast::Target::Path(ref p)}), _) => (p.clone(), span),
+        (_, Some(site)) => (site.path.clone(), site.whole),
+        _ => return Json::Null,
+    };
+    let v = doc.env().at(&path);
+    obj(vec![
+        ("contents", obj(vec![("kind", json::str("plaintext")),
+            ("value", json::str(&format!("{}\n{}", fig::path_name(&path), v)))])),
+        ("range", range(doc.text(), span)),
+    ])
+}
+
+fn definition(doc: &Document, uri: &str, at: usize) -> Json {
+    match ref_at(doc, at).map(|r| &r.target) {
+        Some(ast::Target::Path(p)) => {
+            // the deepest bind on the way there, the last of its name as in evaluation
+            let site = (0..p.len() + 1).rev()
+                .filter_map(|n| doc.sites.iter().rev().find(|s| s.path[..] == p[..n]))
+                .next();
+            match site {
+                Some(s) => obj(vec![("uri", json::str(uri)), ("range", range(doc.text(), s.name))]),
+                None => Json::Null,
+            }
+        },
+        Some(ast::Target::Import(name)) => {
+            let path = match doc.file().and_then(|f| f.parent()) {
+                Some(dir) => dir.join(name),
+                None => PathBuf::from(name),
+            };
+            if !path.exists() {
+                return Json::Null
+            }
+            let path = path.canonicalize().unwrap_or(path);
+            let start = obj(vec![("line", num(0)), ("character", num(0))]);
+            obj(vec![("uri", json::str(&path_uri(&path))),
+                ("range", obj(vec![("start", start.clone()), ("end", start)]))])
+        },
+        None => Json::Null,
+    }
+}
+
+fn sorted_sites(doc: &Document) -> Vec<&ast::Site> {
+    let mut sites: Vec<&ast::Site> = doc.sites.iter().collect();
+    sites.sort_by_key(|s| (s.whole.lo, !s.whole.hi));
+    sites
+}
+
+// nest each site under the one whose span holds it
+fn symbols(doc: &Document, sites: &[&ast::Site]) -> Vec<Json> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < sites.len() {
+        let s = sites[i];
+        let mut j = i + 1;
+        while j < sites.len() && sites[j].whole.hi <= s.whole.hi {
+            j += 1;
+        }
+        let name = s.path.last().cloned().unwrap_or_default();
+        out.push(obj(vec![
+            ("name", Json::Str(name)),
+            ("kind", num(symbol_kind(ast::find(doc.expr(), &s.path)))),
+            ("range", range(doc.text(), s.whole)),
+            ("selectionRange", range(doc.text(), s.name)),
+            ("children", Json::Arr(symbols(doc, &sites[i + 1..j]))),
+        ]));
+        i = j;
+    }
+    out
+}
+
+fn formatting(doc: &Document) -> Json {
+    match layout::format(doc.text()) {
+        Some(ref text) if text != doc.text() => {
+            let all = lex::Span{lo: 0, hi: doc.text().len()};
+            Json::Arr(vec![obj(vec![("range", range(doc.text(), all)), ("newText", json::str(text))])])
+        },
+        _ => Json::Arr(vec![]),
+    }
+}
+
+fn completion(doc: &Document, at: usize) -> Json {
+    let after_at = doc.text()[..at].ends_with('@');
+    Json::Arr(lex::Key::all().into_iter().map(|k| {
+        let word = format!("@{}", k);
+        let insert = if after_at { k.to_string() } else { word.clone() };
+        obj(vec![("label", Json::Str(word)), ("kind", num(14)), ("insertText", Json::Str(insert))])
+    }).collect())
+}
+
+#[cfg(test)]
+fn script(msgs: &[Json]) -> Vec<Json> {
+    let mut input = vec![];
+    for m in msgs {
+        write_message(&mut input, m).unwrap();
+    }
+    let mut out = vec![];
+    serve(&input[..], &mut out).unwrap();
+    let mut replies = vec![];
+    let mut rest = &out[..];
+    while let Some(reply) = read_message(&mut rest).unwrap() {
+        replies.push(reply.unwrap());
+    }
+    replies
+}
+
+#[cfg(test)]
+fn request(id: usize, method: &str, params: Json) -> Json {
+    obj(vec![("jsonrpc", json::str("2.0")), ("id", num(id)), ("method", json::str(method)), ("params", params)])
+}
+
+#[cfg(test)]
+fn open_doc(text: &str) -> Json {
+    notification("textDocument/didOpen", obj(vec![("textDocument", obj(vec![
+        ("uri", json::str("file:///tmp/t.bv")), ("text", json::str(text))]))]))
+}
+
+#[cfg(test)]
+fn at_pos(method: &str, id: usize, line: usize, character: usize) -> Json {
+    request(id, method, obj(vec![
+        ("textDocument", obj(vec![("uri", json::str("file:///tmp/t.bv"))])),
+        ("position", obj(vec![("line", num(line)), ("character", num(character))])),
+    ]))
+}
+
+#[cfg(test)]
+fn result(replies: &[Json], id: usize) -> Json {
+    replies.iter().find(|r| r.get("id") == Some(&num(id)))
+        .and_then(|r| r.get("result")).cloned().unwrap_or(Json::Null)
+}
+
+#[test]
+fn test_lsp_initialize_shutdown() {
+    let replies = script(&[request(1, "initialize", obj(vec![])), request(2, "shutdown", Json::Null),
+        notification("exit", Json::Null), request(3, "shutdown", Json::Null)]);
+    assert_eq!(replies.len(), 2);
+    assert_eq!(result(&replies, 1).at(&["capabilities", "hoverProvider"]), Some(&Json::Bool(true)));
+}
+
+#[test]
+fn test_lsp_bad_messages() {
+    let mut input = b"X-Nope: 1\r\n\r\nContent-Length: 2\r\n\r\n\xff\xfe".to_vec();
+    write_message(&mut input, &request(1, "shutdown", Json::Null)).unwrap();
+    let mut out = vec![];
+    serve(&input[..], &mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.contains(r#""message":"missing Content-Length""#), "{}", text);
+    assert!(text.contains(r#""message":"message is not UTF-8""#), "{}", text);
+    assert!(text.ends_with(r#"{"jsonrpc":"2.0","id":1,"result":null}"#), "{}", text);
+    // a length too big to hold, or longer than what follows, is answered too
+    for header in &["Content-Length: 99999999999999999", "Content-Length: 18446744073709551615", "Content-Length: 10"] {
+        let mut out = vec![];
+        serve(format!("{}\r\n\r\n{{}}", header).as_bytes(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(r#""code":-32700"#), "{}", text);
+    }
+}
+
+#[test]
+fn test_lsp_diagnostics() {
+    let replies = script(&[open_doc("@column {\n 'a' _ }")]);
+    assert_eq!(replies[0].to_string(), concat!(
+        r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///tmp/t.bv","#,
+        r#""diagnostics":[{"range":{"start":{"line":1,"character":5},"end":{"line":1,"character":6}},"#,
+        r#""severity":1,"source":"val","message":"Bad char: _"}]}}"#));
+}
+
+#[test]
+fn test_lsp_hover() {
+    let text = "@struct {\n  @bind a { 'x' }\n  @bind b { @from @my { 'a' } }\n}";
+    let replies = script(&[open_doc(text), at_pos("textDocument/hover", 1, 1, 9),
+        at_pos("textDocument/hover", 2, 2, 14), at_pos("textDocument/hover", 3, 3, 1)]);
+    assert_eq!(result(&replies, 1).at(&["contents", "value"]), Some(&json::str("root.a\n'x'")));
+    assert_eq!(result(&replies, 2).at(&["contents", "value"]), Some(&json::str("root.a\n'x'")));
+    assert_eq!(result(&replies, 3), Json::Null);
+}
+
+#[test]
+fn test_lsp_definition() {
+    let text = "@struct {\n  @bind a { @column { 'x' } }\n  @bind b { @from @root { 'a' '0' } }\n  @bind c { @import 'nowhere.bv' }\n}";
+    let replies = script(&[open_doc(text), at_pos("textDocument/definition", 1, 2, 14),
+        at_pos("textDocument/definition", 2, 3, 14)]);
+    assert_eq!(result(&replies, 1).to_string(),
+        r#"{"uri":"file:///tmp/t.bv","range":{"start":{"line":1,"character":8},"end":{"line":1,"character":9}}}"#);
+    assert_eq!(result(&replies, 2), Json::Null);
+    let text = "@struct {\n  @bind a { 'x' }\n  @bind a { 'y' }\n  @bind b { @from @my { 'a' } }\n}";
+    let replies = script(&[open_doc(text), at_pos("textDocument/definition", 1, 3, 14)]);
+    assert_eq!(result(&replies, 1).at(&["range", "start", "line"]), Some(&num(2)));
+}
+
+#[test]
+fn test_lsp_symbols() {
+    let text = "@struct { @bind a { @struct { @bind b { 'x' } } } @bind c { @column {} } }";
+    let replies = script(&[open_doc(text), request(1, "textDocument/documentSymbol",
+        obj(vec![("textDocument", obj(vec![("uri", json::str("file:///tmp/t.bv"))]))]))]);
+    let syms = result(&replies, 1);
+    let names = |j: &Json| match *j {
+        Json::Arr(ref xs) => xs.iter().map(|x| (x.get("name").cloned(), x.get("kind").cloned())).collect(),
+        _ => vec![],
+    };
+    assert_eq!(names(&syms), vec![(Some(json::str("a")), Some(num(23))), (Some(json::str("c")), Some(num(18)))]);
+    match syms {
+        Json::Arr(ref xs) => assert_eq!(names(xs[0].get("children").unwrap()), vec![(Some(json::str("b")), Some(num(15)))]),
+        _ => panic!("{}", syms),
+    }
+}
+
+#[test]
+fn test_lsp_formatting_completion() {
+    let replies = script(&[open_doc("@column{'a'}"),
+        request(1, "textDocument/formatting", obj(vec![("textDocument", obj(vec![("uri", json::str("file:///tmp/t.bv"))]))])),
+        at_pos("textDocument/completion", 2, 0, 1)]);
+    match result(&replies, 1) {
+        Json::Arr(ref edits) => assert_eq!(edits[0].get("newText"), Some(&json::str("@column { 'a' }\n"))),
+        r => panic!("{}", r),
+    }
+    match result(&replies, 2) {
+        Json::Arr(ref items) => {
+            assert_eq!(items.len(), lex::Key::all().len());
+            assert_eq!(items[0].get("insertText"), Some(&json::str("bind")));
+        },
+        r => panic!("{}", r),
+    }
+}
+
+#[test]
+fn test_lsp_positions() {
+    let text = "a\n\u{1F600}b";
+    assert_eq!(offset(text, &obj(vec![("line", num(1)), ("character", num(2))])), 6);
+    assert_eq!(position(text, 6), obj(vec![("line", num(1)), ("character", num(2))]));
+    assert_eq!(uri_path("file:///a%20b/c.bv"), Some(PathBuf::from("/a b/c.bv")));
+}
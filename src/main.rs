@@ -1,42 +1,372 @@
-mod lex;
-mod ast;
-mod fig;
+extern crate val;
 
-use std::io;
-use std::io::Read;
+use std::env;
 use std::fs;
-use std::path;
+use std::io;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use val::watch::Watch;
+
+const USAGE: &str = "usage: val eval [--trace] [--cache DIR] [--watch] FILE
+                        print the evaluated document; --trace writes each step
+                        of evaluation to stderr, --cache keeps values in DIR
+                        for the next run, --watch evaluates again whenever
+                        FILE or a file it imports changes
+       val debug FILE [NAME...]
+                        step through evaluation, stopping where NAME is bound
+       val check [--watch] FILE
+       val check --watch DIR
+                        report lexer and parser errors, again on each change
+       val repl [FILE]  evaluate expressions typed at a prompt
+       val deps FILE [--format dot|json]
+                        show which cells read which
+       val types FILE   show the type of each bind, and calls that cannot work
+       val query FILE QUERY [--format bv|json|raw]
+                        print the values QUERY finds, e.g. 'hosts[*].name'
+       val diff OLD NEW [--format text|json]
+                        what changed between two files, by path
+       val merge BASE OURS THEIRS [-o OUT]
+                        merge the changes from BASE to THEIRS into OURS
+       val validate --schema SCHEMA FILE
+                        report where FILE does not keep to SCHEMA
+       val lsp          serve the language server protocol on stdio";
 
 fn main() {
-    let filen = "sample/t4.bv";
-    let ok = process_file(filen);
-    match ok {
-        Ok(()) => println!("Ok"),
-        Err(e) => println!("{}: {}", filen, e),
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match (args.first().map(|a| a.as_str()), args.len()) {
+        (Some("eval"), _) => match eval_options(&args[1..]) {
+            Some((file, ref opts)) if opts.watch => watch_eval(file, opts),
+            Some((file, opts)) => run(file, |f, doc| eval_file(f, doc, &opts)),
+            None => usage(),
+        },
+        (Some("debug"), n) if n >= 2 => run(&args[1], |f, doc| debug(f, doc, &args[2..])),
+        (Some("check"), 2) => run(&args[1], check_file),
+        (Some("check"), 3) if args[1] == "--watch" => watch_check(&args[2]),
+        (Some("repl"), 1) => repl(None),
+        (Some("repl"), 2) => run(&args[1], |_, doc| repl(Some(doc))),
+        (Some("deps"), 2) => run(&args[1], |f, doc| deps(f, doc, "dot")),
+        (Some("deps"), 4) if args[2] == "--format" => {
+            let format = args[3].clone();
+            run(&args[1], |f, doc| deps(f, doc, &format))
+        },
+        (Some("types"), 2) => run(&args[1], types),
+        (Some("query"), 3) => run(&args[1], |f, doc| query(f, doc, &args[2], "bv")),
+        (Some("query"), 5) if args[3] == "--format" => run(&args[1], |f, doc| query(f, doc, &args[2], &args[4])),
+        (Some("diff"), 3) => run(&args[1], |f, a| run(&args[2], |g, b| diff(f, a, g, b, "text"))),
+        (Some("diff"), 5) if args[3] == "--format" =>
+            run(&args[1], |f, a| run(&args[2], |g, b| diff(f, a, g, b, &args[4]))),
+        (Some("merge"), 4) => merge(&args[1..4], None),
+        (Some("merge"), 6) if args[4] == "-o" => merge(&args[1..4], Some(&args[5])),
+        (Some("validate"), 4) if args[1] == "--schema" => run(&args[2], |sf, schema| {
+            match val::schema::Schema::from_document(schema) {
+                Ok(s) => run(&args[3], |f, doc| validate(f, doc, &s)),
+                Err(e) => {
+                    eprintln!("{}: {}", sf, e);
+                    1
+                },
+            }
+        }),
+        (Some("lsp"), 1) => lsp(),
+        _ => usage(),
+    };
+    process::exit(code);
+}
+
+fn usage() -> i32 {
+    eprintln!("{}", USAGE);
+    2
+}
+
+#[derive(Default)]
+struct EvalOptions {
+    trace: bool,
+    cache: Option<String>,
+    watch: bool,
+}
+
+// the file to evaluate, and how
+fn eval_options(args: &[String]) -> Option<(&str, EvalOptions)> {
+    let mut opts = EvalOptions::default();
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--trace" => opts.trace = true,
+            "--cache" => opts.cache = Some(args.next()?.clone()),
+            "--watch" => opts.watch = true,
+            _ if file.is_none() && !a.starts_with("--") => file = Some(a.as_str()),
+            _ => return None,
+        }
+    }
+    Some((file?, opts))
+}
+
+fn run<F: FnOnce(&str, &val::Document) -> i32>(filen: &str, f: F) -> i32 {
+    match val::parse_file(filen) {
+        Ok(doc) => f(filen, &doc),
+        Err(e) => {
+            eprintln!("{}: {}", filen, e);
+            1
+        },
+    }
+}
+
+fn report(filen: &str, doc: &val::Document) -> bool {
+    for d in doc.diagnostics() {
+        eprintln!("{}:{}", filen, d);
+    }
+    doc.diagnostics().is_empty()
+}
+
+fn eval_file(filen: &str, doc: &val::Document, opts: &EvalOptions) -> i32 {
+    let env = doc.env();
+    prepare(&env, opts);
+    evaluate(filen, doc, &env)
+}
+
+fn prepare(env: &val::Env, opts: &EvalOptions) {
+    if opts.trace {
+        env.trace(|step, e| eprintln!("{}{}", "  ".repeat(e.computing().len()), step));
+    }
+    if let Some(ref dir) = opts.cache {
+        env.cache(dir);
+    }
+}
+
+// print the value of doc's env, and keep it if there is a cache
+fn evaluate(filen: &str, doc: &val::Document, env: &val::Env) -> i32 {
+    let ok = report(filen, doc);
+    println!("{}", env.eval());
+    if let Err(e) = env.save_cache() {
+        eprintln!("cache: {}", e);
+        return 1
+    }
+    if ok { 0 } else { 1 }
+}
+
+fn watcher() -> Watch {
+    Watch::new(Duration::from_millis(200))
+}
+
+fn changed(files: &[PathBuf]) {
+    let names: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+    eprintln!("-- changed: {}", names.join(", "));
+}
+
+// evaluate again whenever the file or one it imports changes, until
+// killed; the same env is kept throughout, given each edit, so only the
+// cells an edit reaches are computed again
+fn watch_eval(filen: &str, opts: &EvalOptions) -> i32 {
+    let mut w = watcher();
+    w.set(vec![PathBuf::from(filen)]);
+    let mut doc = loop {
+        match val::parse_file(filen) {
+            Ok(doc) => break doc,
+            Err(e) => eprintln!("{}: {}", filen, e),
+        }
+        changed(&w.wait());
+    };
+    let env = doc.env();
+    prepare(&env, opts);
+    let mut readable = true;
+    loop {
+        if readable {
+            evaluate(filen, &doc, &env);
+        }
+        w.set(env.files());
+        let files = w.wait();
+        changed(&files);
+        for f in &files {
+            if f != Path::new(filen) {
+                env.reload(f);
+                continue
+            }
+            match val::parse_file(filen) {
+                Ok(to) => {
+                    env.edit(&doc, &to);
+                    doc = to;
+                    readable = true;
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", filen, e);
+                    readable = false;
+                },
+            }
+        }
+    }
+}
+
+// check again whenever a .bv file in or below dir changes, comes or goes,
+// until killed; dir may be just a file
+fn watch_check(dir: &str) -> i32 {
+    let tree = || if Path::new(dir).is_dir() { val::watch::bv_tree(Path::new(dir)) } else { vec![PathBuf::from(dir)] };
+    let mut w = watcher();
+    w.set(tree());
+    loop {
+        let files = tree();
+        let mut ok = true;
+        for f in files.iter().filter(|f| !f.is_dir()) {
+            ok &= run(&f.to_string_lossy(), |f, doc| if report(f, doc) { 0 } else { 1 }) == 0;
+        }
+        if ok {
+            println!("Ok");
+        }
+        w.set(files);
+        changed(&w.wait());
+    }
+}
+
+fn check_file(filen: &str, doc: &val::Document) -> i32 {
+    if report(filen, doc) {
+        println!("Ok");
+        0
+    } else {
+        1
+    }
+}
+
+fn deps(filen: &str, doc: &val::Document, format: &str) -> i32 {
+    let ok = report(filen, doc);
+    let g = val::deps::graph(doc.expr());
+    match format {
+        "dot" => print!("{}", g.dot()),
+        "json" => println!("{}", g.json()),
+        _ => {
+            eprintln!("unknown format {} (dot or json)", format);
+            return 2
+        },
+    }
+    if ok { 0 } else { 1 }
+}
+
+fn types(filen: &str, doc: &val::Document) -> i32 {
+    let ok = report(filen, doc);
+    let t = val::types::check(doc.expr());
+    print!("{}", t);
+    for m in &t.mismatches {
+        eprintln!("{}: {}", filen, m);
+    }
+    if ok && t.mismatches.is_empty() { 0 } else { 1 }
+}
+
+fn query(filen: &str, doc: &val::Document, q: &str, format: &str) -> i32 {
+    let ok = report(filen, doc);
+    let q = match val::query::Query::parse(q) {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("bad query: {}", e);
+            return 2
+        },
+    };
+    let r = q.run(&doc.env());
+    match format {
+        "bv" => println!("{}", r.bv()),
+        "json" => println!("{}", r.json()),
+        "raw" => print!("{}", r.raw()),
+        _ => {
+            eprintln!("unknown format {} (bv, json or raw)", format);
+            return 2
+        },
     }
+    if ok && r.errors().is_empty() { 0 } else { 1 }
 }
 
-fn process_file<P: AsRef<path::Path>>(filename: P) -> io::Result<()> {
-    let src = try!(read_file(filename));
-    let toks = lex::lex(src.text);
-    let ast = ast::parse(toks);
-    let env = fig::create_env(ast);
-    println!("{:?}", env);
-    Ok(())
+// exits as diff does: 0 for the same, 1 for different, 2 for trouble
+fn diff(filen: &str, a: &val::Document, filen2: &str, b: &val::Document, format: &str) -> i32 {
+    if !report(filen, a) | !report(filen2, b) {
+        return 2
+    }
+    let d = val::diff::diff(a.expr(), b.expr());
+    match format {
+        "text" => print!("{}", d.text()),
+        "json" => println!("{}", d.json()),
+        _ => {
+            eprintln!("unknown format {} (text or json)", format);
+            return 2
+        },
+    }
+    if d.changes.is_empty() { 0 } else { 1 }
 }
 
-fn empty_text() -> String {
-    String::with_capacity(50)
+// as a git merge driver: 0 when clean, 1 with conflicts marked in comments
+fn merge(files: &[String], out: Option<&str>) -> i32 {
+    let mut docs = vec![];
+    for f in files {
+        match val::parse_file(f) {
+            Ok(doc) => docs.push(doc),
+            Err(e) => {
+                eprintln!("{}: {}", f, e);
+                return 2
+            },
+        }
+    }
+    let m = match val::merge::merge(&docs[0], &docs[1], &docs[2]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("merge: {}", e);
+            return 2
+        },
+    };
+    for c in &m.conflicts {
+        eprintln!("conflict at {}", c);
+    }
+    match out {
+        Some(f) => if let Err(e) = fs::write(f, &m.text) {
+            eprintln!("{}: {}", f, e);
+            return 2
+        },
+        None => print!("{}", m.text),
+    }
+    if m.conflicts.is_empty() { 0 } else { 1 }
 }
 
-fn read_file<P: AsRef<path::Path>>(filename: P) -> io::Result<BvSource> {
-    let mut fr = try!(fs::File::open(filename));
-    let mut s = empty_text();
-    try!(fr.read_to_string(&mut s));
-    Ok(BvSource { text: s.to_string() })
+fn validate(filen: &str, doc: &val::Document, schema: &val::schema::Schema) -> i32 {
+    let ok = report(filen, doc);
+    let violations = schema.validate(doc);
+    for v in &violations {
+        eprintln!("{}:{}", filen, v);
+    }
+    if !ok || !violations.is_empty() {
+        return 1
+    }
+    println!("Ok");
+    0
 }
 
-struct BvSource {
-    text: String,
-    // name: ...
+fn repl(doc: Option<&val::Document>) -> i32 {
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    match val::repl::Repl::new(doc).run(stdin.lock(), &mut io::stdout(), prompt) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("repl: {}", e);
+            1
+        },
+    }
+}
+
+fn debug(filen: &str, doc: &val::Document, breaks: &[String]) -> i32 {
+    let ok = report(filen, doc);
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    match val::debug::run(doc, breaks, stdin.lock(), io::stdout(), prompt) {
+        Ok(_) => if ok { 0 } else { 1 },
+        Err(e) => {
+            eprintln!("debug: {}", e);
+            1
+        },
+    }
+}
+
+fn lsp() -> i32 {
+    let stdin = io::stdin();
+    match val::lsp::serve(stdin.lock(), &mut io::stdout()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("lsp: {}", e);
+            1
+        },
+    }
 }
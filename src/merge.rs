@@ -0,0 +1,338 @@
+// Three-way merge of documents by their struct and column tree. The result
+// is our source with their changes spliced in, so the comments of both
+// sides survive, laid out again by layout::format.
+
+use ast::{self, Expr};
+use fig::path_name;
+use layout;
+use lex::{self, Span, Tok};
+use std::collections::HashMap;
+use std::fmt;
+use Document;
+
+// A place both sides changed differently; our side is kept there.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conflict {
+    pub path: Vec<String>,
+    // None where the bind is not there
+    pub base: Option<Expr>,
+    pub ours: Option<Expr>,
+    pub theirs: Option<Expr>,
+}
+
+fn side(x: &Option<Expr>) -> String {
+    match *x {
+        Some(ref x) => ast::source(x),
+        None => "removed".to_string(),
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: base {}, ours {}, theirs {}", path_name(&self.path),
+            side(&self.base), side(&self.ours), side(&self.theirs))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Merge {
+    pub text: String,
+    pub conflicts: Vec<Conflict>,
+}
+
+// where things are in one side's source
+struct Source<'a> {
+    text: &'a str,
+    toks: Vec<(Span, Tok)>,
+    binds: HashMap<&'a [String], &'a ast::Site>, // the last of each path
+}
+
+impl<'a> Source<'a> {
+    fn new(doc: &'a Document) -> Source<'a> {
+        let toks = lex::lex_spans(&doc.text).into_iter()
+            .filter(|t| matches!(t.1, Tok::CurlL | Tok::CurlR))
+            .collect();
+        let binds = doc.sites.iter().map(|s| (&s.path[..], s)).collect();
+        Source{text: &doc.text, toks, binds}
+    }
+
+    // the whole @bind at path
+    fn bind(&self, path: &[String]) -> Option<Span> {
+        self.binds.get(path).map(|s| s.whole)
+    }
+
+    // what is between the curls of the @bind at path; the whole text at the root
+    fn value(&self, path: &[String]) -> Option<Span> {
+        if path.is_empty() {
+            return Some(Span{lo: 0, hi: self.text.len()})
+        }
+        let site = self.binds.get(path)?;
+        let i = self.toks.partition_point(|t| t.0.lo < site.name.hi);
+        Some(Span{lo: self.toks.get(i)?.0.hi, hi: site.whole.hi - 1})
+    }
+
+    // just before the curl closing the struct at path
+    fn struct_end(&self, path: &[String]) -> Option<usize> {
+        let v = self.value(path)?;
+        let i = self.toks.partition_point(|t| t.0.lo < v.hi);
+        match i.checked_sub(1).and_then(|i| self.toks.get(i)) {
+            Some(&(span, Tok::CurlR)) if span.lo >= v.lo => Some(span.lo),
+            _ => None,
+        }
+    }
+
+    // the whole @bind at path with any comment after it on its line
+    fn bind_line(&self, path: &[String]) -> Option<Span> {
+        let whole = self.bind(path)?;
+        let rest = &self.text[whole.hi..];
+        let gap = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+        let hi = if rest[gap..].starts_with('#') {
+            whole.hi + rest.find('\n').unwrap_or(rest.len())
+        } else {
+            whole.hi
+        };
+        Some(Span{lo: whole.lo, hi})
+    }
+
+    // what to take out to remove the @bind at path: the whole line when it
+    // has one to itself
+    fn removal(&self, path: &[String]) -> Option<Span> {
+        let s = self.bind_line(path)?;
+        let start = self.text[..s.lo].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let alone = self.text[start..s.lo].trim().is_empty() && self.text[s.hi..].starts_with('\n');
+        Some(if alone { Span{lo: start, hi: s.hi + 1} } else { s })
+    }
+
+    fn slice(&self, span: Span) -> &'a str {
+        &self.text[span.lo..span.hi]
+    }
+}
+
+// replace the text in span
+type Edit = (Span, String);
+
+struct Merger<'a> {
+    ours: Source<'a>,
+    theirs: Source<'a>,
+    conflicts: Vec<Conflict>,
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+// the last bind of each name, as evaluation sees them
+fn bound<'b>(bs: &'b [ast::Bind], name: &str) -> Option<&'b Expr> {
+    bs.iter().rev().find(|b| b.name == name).map(|b| &b.value)
+}
+
+fn names(bs: &[ast::Bind], into: &mut Vec<String>) {
+    for b in bs {
+        if !into.contains(&b.name) {
+            into.push(b.name.clone());
+        }
+    }
+}
+
+impl<'a> Merger<'a> {
+    // keep ours, note the conflict, and mark it in a comment
+    fn conflict(&mut self, path: &[String], base: Option<&Expr>, ours: Option<&Expr>, theirs: Option<&Expr>) -> Vec<Edit> {
+        let c = Conflict{path: path.to_vec(), base: base.cloned(), ours: ours.cloned(), theirs: theirs.cloned()};
+        let at = (0..=path.len()).rev().find_map(|n| self.ours.bind(&path[..n])).map(|s| s.lo).unwrap_or(0);
+        let note = format!("# conflict at {}\n", c.to_string().replace('\n', " "));
+        self.conflicts.push(c);
+        vec![(Span{lo: at, hi: at}, note)]
+    }
+
+    // the merged value at path, and the edits to our text making it; no
+    // edits when they cannot be placed, and the caller writes it out instead
+    fn node(&mut self, path: &[String], b: &Expr, o: &Expr, t: &Expr) -> (Expr, Option<Vec<Edit>>) {
+        if o == t || t == b {
+            return (o.clone(), Some(vec![]))
+        }
+        if o == b {
+            let edit = match (self.ours.value(path), self.theirs.value(path)) {
+                (Some(os), Some(ts)) => Some(vec![(os, self.theirs.slice(ts).to_string())]),
+                _ => None,
+            };
+            return (t.clone(), edit)
+        }
+        match (b, o, t) {
+            (Expr::Struct(bs), Expr::Struct(os), Expr::Struct(ts)) => self.structs(path, bs, os, ts),
+            (Expr::Column(bs), Expr::Column(os), Expr::Column(ts)) if bs.len() == os.len() && bs.len() == ts.len() => {
+                let mut xs = vec![];
+                let mut edits = Some(vec![]);
+                for i in 0..bs.len() {
+                    let (x, e) = self.node(&child(path, &i.to_string()), &bs[i], &os[i], &ts[i]);
+                    xs.push(x);
+                    edits = match (edits, e) {
+                        (Some(mut es), Some(e)) => {
+                            es.extend(e);
+                            Some(es)
+                        },
+                        _ => None,
+                    };
+                }
+                self.placed(path, Expr::Column(xs), edits)
+            },
+            _ => {
+                let e = self.conflict(path, Some(b), Some(o), Some(t));
+                (o.clone(), Some(e))
+            },
+        }
+    }
+
+    // edits for a value, written out whole if its parts could not be placed
+    fn placed(&self, path: &[String], x: Expr, edits: Option<Vec<Edit>>) -> (Expr, Option<Vec<Edit>>) {
+        match (edits, self.ours.value(path)) {
+            (Some(es), _) => (x, Some(es)),
+            (None, Some(span)) => {
+                let text = format!(" {} ", ast::source(&x));
+                (x, Some(vec![(span, text)]))
+            },
+            (None, None) => (x, None),
+        }
+    }
+
+    fn structs(&mut self, path: &[String], bs: &[ast::Bind], os: &[ast::Bind], ts: &[ast::Bind]) -> (Expr, Option<Vec<Edit>>) {
+        let mut all = vec![];
+        names(os, &mut all);
+        names(ts, &mut all);
+        names(bs, &mut all);
+        let mut binds = vec![];
+        let mut edits = Some(vec![]);
+        for name in all {
+            let p = child(path, &name);
+            let (b, o, t) = (bound(bs, &name), bound(os, &name), bound(ts, &name));
+            let (x, e) = match (b, o, t) {
+                (_, Some(o), None) if b.is_none() || b == Some(o) => match b {
+                    // added on our side, or removed on theirs
+                    None => (Some(o.clone()), Some(vec![])),
+                    Some(_) => (None, self.ours.removal(&p).map(|s| vec![(s, String::new())])),
+                },
+                (_, None, Some(t)) if b.is_none() || b == Some(t) => match b {
+                    // added on theirs, or removed on ours
+                    None => {
+                        let e = match (self.ours.struct_end(path), self.theirs.bind_line(&p)) {
+                            (Some(at), Some(s)) => {
+                                // on a line of its own before the closing curl
+                                let own = self.ours.text[..at].trim_end_matches([' ', '\t']).ends_with('\n');
+                                let lead = if own { "" } else { "\n" };
+                                Some(vec![(Span{lo: at, hi: at}, format!("{}{}\n", lead, self.theirs.slice(s)))])
+                            },
+                            _ => None,
+                        };
+                        (Some(t.clone()), e)
+                    },
+                    Some(_) => (None, Some(vec![])),
+                },
+                (Some(b), Some(o), Some(t)) => {
+                    let (x, e) = self.node(&p, b, o, t);
+                    (Some(x), e)
+                },
+                (_, None, None) => (None, Some(vec![])),
+                (b, o, t) => {
+                    // added differently on both sides, or removed on one and changed on the other
+                    let e = self.conflict(&p, b, o, t);
+                    (o.cloned(), Some(e))
+                },
+            };
+            if let Some(x) = x {
+                binds.push(ast::Bind{name, value: x});
+            }
+            edits = match (edits, e) {
+                (Some(mut es), Some(e)) => {
+                    es.extend(e);
+                    Some(es)
+                },
+                _ => None,
+            };
+        }
+        self.placed(path, Expr::Struct(binds), edits)
+    }
+}
+
+// our text with the edits made, each edit in its own span
+fn apply(text: &str, mut edits: Vec<Edit>) -> String {
+    edits.sort_by_key(|e| e.0.lo);
+    let mut out = String::with_capacity(text.len());
+    let mut at = 0;
+    for (span, new) in edits {
+        out.push_str(&text[at..span.lo.max(at)]);
+        out.push_str(&new);
+        at = at.max(span.hi);
+    }
+    out.push_str(&text[at..]);
+    out
+}
+
+// merge their changes from base into ours
+pub fn merge(base: &Document, ours: &Document, theirs: &Document) -> Result<Merge, String> {
+    for (name, doc) in &[("base", base), ("ours", ours), ("theirs", theirs)] {
+        if let Some(d) = doc.diagnostics().first() {
+            return Err(format!("{} does not parse: {}", name, d))
+        }
+    }
+    let mut m = Merger{ours: Source::new(ours), theirs: Source::new(theirs), conflicts: vec![]};
+    let (x, edits) = m.node(&[], base.expr(), ours.expr(), theirs.expr());
+    // the root always has a place, so there are always edits
+    let spliced = apply(&ours.text, edits.unwrap_or_default());
+    let text = match layout::format(&spliced) {
+        Some(t) => t,
+        None => spliced,
+    };
+    // should the splicing have gone wrong, write the merged tree out whole
+    let text = if ::parse_str(&text).expr() == &x {
+        text
+    } else {
+        let whole = ast::source(&x);
+        layout::format(&whole).unwrap_or(whole)
+    };
+    Ok(Merge{text, conflicts: m.conflicts})
+}
+
+#[cfg(test)]
+fn smerge(base: &str, ours: &str, theirs: &str) -> Merge {
+    merge(&::parse_str(base), &::parse_str(ours), &::parse_str(theirs)).unwrap()
+}
+
+#[test]
+fn test_merge_clean() {
+    let base = "@struct {\n  @bind a { 'x' }\n  @bind b { 'y' }\n  @bind c { 'z' }\n}\n";
+    let ours = "@struct {\n  # ours\n  @bind a { 'x2' }\n  @bind b { 'y' }\n  @bind c { 'z' }\n}\n";
+    let theirs = "@struct {\n  @bind a { 'x' }\n  @bind b { # theirs\n'y2' }\n  @bind d { 'new' } # added\n}\n";
+    let m = smerge(base, ours, theirs);
+    assert!(m.conflicts.is_empty());
+    assert_eq!(m.text, "@struct {\n  # ours\n  @bind a { 'x2' }\n  @bind b { # theirs\n    'y2' }\n  @bind d { 'new' } # added\n}\n");
+}
+
+#[test]
+fn test_merge_nested() {
+    let base = "@struct { @bind s { @struct { @bind p { '1' } @bind q { '2' } } } @bind h { @column { 'a' 'b' } } }";
+    let ours = "@struct { @bind s { @struct { @bind p { '10' } @bind q { '2' } } } @bind h { @column { 'A' 'b' } } }";
+    let theirs = "@struct { @bind s { @struct { @bind p { '1' } @bind q { '20' } } } @bind h { @column { 'a' 'B' } } }";
+    let m = smerge(base, ours, theirs);
+    assert!(m.conflicts.is_empty());
+    assert_eq!(m.text, "@struct { @bind s { @struct { @bind p { '10' } @bind q { '20' } } } @bind h { @column { 'A' 'B' } } }\n");
+    // a bind added inside a column element has no place of its own, so the column is written out
+    let m = smerge("@struct { @bind h { @column { @struct { @bind n { 'a' } } } } @bind k { 'x' } # k\n}",
+        "@struct { @bind h { @column { @struct { @bind n { 'a' } } } } @bind k { 'y' } # k\n}",
+        "@struct { @bind h { @column { @struct { @bind n { 'a' } @bind m { 'b' } } } } @bind k { 'x' } }");
+    assert_eq!(m.text, "@struct { @bind h { @column { @struct { @bind n { 'a' } @bind m { 'b' } } } } @bind k { 'y' } # k\n}\n");
+}
+
+#[test]
+fn test_merge_conflicts() {
+    let base = "@struct {\n  @bind a { 'x' }\n  @bind b { 'y' }\n}\n";
+    let ours = "@struct {\n  @bind a { 'ours' }\n  @bind b { 'y2' }\n}\n";
+    let theirs = "@struct {\n  @bind a { 'theirs' }\n}\n";
+    let m = smerge(base, ours, theirs);
+    let cs: Vec<String> = m.conflicts.iter().map(|c| c.to_string()).collect();
+    assert_eq!(cs, vec!["root.a: base 'x', ours 'ours', theirs 'theirs'", "root.b: base 'y', ours 'y2', theirs removed"]);
+    assert_eq!(m.text, "@struct {\n  # conflict at root.a: base 'x', ours 'ours', theirs 'theirs'\n  @bind a { 'ours' }\n  \
+        # conflict at root.b: base 'y', ours 'y2', theirs removed\n  @bind b { 'y2' }\n}\n");
+    let bad = merge(&::parse_str("@struct {"), &::parse_str("'a'"), &::parse_str("'b'"));
+    assert!(bad.unwrap_err().starts_with("base does not parse: "));
+}
@@ -0,0 +1,633 @@
+// Evaluation on several threads at once: the values of fig::Env, with each
+// cell behind a lock. A cell is computed by one thread; another that reads
+// it meanwhile waits, unless its wait would close a circle of threads each
+// waiting on the next, which is a cycle like any other.
+
+use ast;
+use error::{Code, Error};
+use fig::{self, path_name, Native};
+use lex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+use {parse_file_with, Limits};
+
+// as fig::Value, shared between threads
+#[derive(Debug, Clone)]
+enum Value {
+    Err(Error),
+    Text(String),
+    Column(Arc<[Value]>),
+    Sheet(Arc<Sheet>),
+    Ftn(Native),
+}
+
+type Sheet = HashMap<String, Arc<Cell>>;
+
+#[derive(Debug)]
+enum State {
+    Red,
+    Yellow(usize), // being computed by this worker
+    Green(Value),
+    Stopped, // a copy of a cell being computed, so never done
+}
+
+#[derive(Debug)]
+struct Cell {
+    path: Vec<String>,
+    expr: Option<ast::Expr>, // none for natives
+    state: Mutex<State>,
+}
+
+// who is computing and waiting on what
+#[derive(Debug, Default)]
+struct Sched {
+    stacks: HashMap<usize, Vec<(usize, Vec<String>)>>, // each worker's cells, innermost last
+    owners: HashMap<usize, usize>, // cell -> the worker computing it
+    waits: HashMap<usize, usize>, // worker -> the cell it waits on
+    broken: HashMap<Vec<String>, String>, // cells found on a cycle, with the chain
+}
+
+#[derive(Debug)]
+pub struct Env {
+    sys: Arc<Sheet>,
+    lib: Arc<Sheet>,
+    top: Arc<Cell>,
+    file: Option<PathBuf>,
+    importing: Vec<PathBuf>,
+    imports: Mutex<HashMap<PathBuf, Value>>,
+    sched: Mutex<Sched>,
+    wake: Condvar, // a cell has its value
+    workers: AtomicUsize, // ids handed out
+    steps: AtomicUsize,
+    limits: Limits,
+    started: Mutex<Option<(Instant, usize)>>,
+    threads: usize,
+}
+
+// a poisoned lock only means another worker panicked; what it guards is whole
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+fn fail<S: Into<String>>(code: Code, message: S) -> Value {
+    Value::Err(Error::new(code, message))
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+fn parent(path: &[String]) -> &[String] {
+    &path[..path.len().saturating_sub(1)]
+}
+
+fn canonical(p: &Path) -> PathBuf {
+    fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
+}
+
+fn new_cell(path: Vec<String>, expr: Option<ast::Expr>, state: State) -> Arc<Cell> {
+    Arc::new(Cell{path, expr, state: Mutex::new(state)})
+}
+
+// cells are told apart by where they live, not by their path: a @call's
+// arguments can make cells with the path of another
+fn cell_id(c: &Arc<Cell>) -> usize {
+    Arc::as_ptr(c) as usize
+}
+
+fn from_fig(v: &fig::Value) -> Value {
+    match *v {
+        fig::Value::Err(ref e) => Value::Err(e.clone()),
+        fig::Value::Text(ref t) => Value::Text(t.clone()),
+        fig::Value::Column(ref xs) => Value::Column(xs.iter().map(from_fig).collect()),
+        fig::Value::Sheet(ref s) => Value::Sheet(Arc::new(from_fig_sheet(s))),
+        fig::Value::Ftn(ref f) => Value::Ftn(f.clone()),
+    }
+}
+
+fn from_fig_sheet(s: &fig::Struct) -> Sheet {
+    s.iter().map(|(k, c)| {
+        let v = c.value().map_or_else(|| fail(Code::Internal, "Native value not computed"), |v| from_fig(&v));
+        (k.clone(), new_cell(vec![], None, State::Green(v)))
+    }).collect()
+}
+
+fn to_fig(v: &Value) -> fig::Value {
+    match *v {
+        Value::Err(ref e) => fig::Value::Err(e.clone()),
+        Value::Text(ref t) => fig::Value::Text(t.clone()),
+        Value::Column(ref xs) => fig::Value::Column(xs.iter().map(to_fig).collect()),
+        Value::Sheet(ref s) => fig::Value::Sheet(::std::rc::Rc::new(s.iter().map(|(k, c)| {
+            // out of the lock before going in, as the value may hold this cell
+            let v = match *lock(&c.state) {
+                State::Green(ref v) => v.clone(),
+                State::Red => return (k.clone(), fig::Cell::pending()),
+                _ => return (k.clone(), fig::Cell::in_progress()),
+            };
+            (k.clone(), fig::Cell::new(to_fig(&v)))
+        }).collect())),
+        Value::Ftn(ref f) => fig::Value::Ftn(f.clone()),
+    }
+}
+
+impl Env {
+    pub(crate) fn new(sys: &fig::Struct, lib: &fig::Struct, expr: &ast::Expr, file: Option<PathBuf>,
+            importing: Vec<PathBuf>, limits: Limits, threads: usize) -> Env {
+        Env{sys: Arc::new(from_fig_sheet(sys)), lib: Arc::new(from_fig_sheet(lib)),
+            top: new_cell(vec![], Some(expr.clone()), State::Red), file, importing,
+            imports: Mutex::new(HashMap::new()), sched: Mutex::new(Sched::default()), wake: Condvar::new(),
+            workers: AtomicUsize::new(0), steps: AtomicUsize::new(0), limits, started: Mutex::new(None),
+            threads: threads.max(1)}
+    }
+
+    // evaluate the whole document, every cell included, as fig::Env::eval
+    // would; independent cells are computed on different threads
+    pub fn eval(&self) -> fig::Value {
+        *lock(&self.started) = Some((Instant::now(), self.steps.load(Ordering::SeqCst)));
+        let queue = Queue{todo: Mutex::new(Todo{cells: vec![(self.top.clone(), 0)], busy: 0}), more: Condvar::new()};
+        if self.threads == 1 {
+            self.work(&queue);
+        } else {
+            thread::scope(|s| {
+                let mut failed = false;
+                for _ in 0..self.threads {
+                    // as deep as the main thread goes
+                    failed |= thread::Builder::new().stack_size(8 << 20).spawn_scoped(s, || self.work(&queue)).is_err();
+                }
+                // a worker that could not start is made up for here, so the
+                // queue is emptied even with no thread to spare
+                if failed {
+                    self.work(&queue);
+                }
+            });
+        }
+        to_fig(&self.worker().fill(&self.top))
+    }
+
+    // how many cells have been computed
+    pub fn steps(&self) -> usize {
+        self.steps.load(Ordering::SeqCst)
+    }
+
+    fn worker(&self) -> Worker<'_> {
+        Worker{e: self, id: self.workers.fetch_add(1, Ordering::SeqCst)}
+    }
+
+    // compute cells from the queue, and queue the cells in their values,
+    // until there are none and no worker may find more
+    fn work(&self, q: &Queue) {
+        let w = self.worker();
+        loop {
+            let (c, depth) = {
+                let mut todo = lock(&q.todo);
+                loop {
+                    if let Some(item) = todo.cells.pop() {
+                        todo.busy += 1;
+                        break item
+                    }
+                    if todo.busy == 0 {
+                        q.more.notify_all();
+                        return
+                    }
+                    todo = q.more.wait(todo).unwrap_or_else(|p| p.into_inner());
+                }
+            };
+            let v = w.fill(&c);
+            let mut found = vec![];
+            w.cells_in(&v, depth, &mut found);
+            let mut todo = lock(&q.todo);
+            // last first, so one worker goes in name order like fig::Env
+            todo.cells.extend(found.into_iter().rev());
+            todo.busy -= 1;
+            q.more.notify_all();
+        }
+    }
+}
+
+struct Queue {
+    todo: Mutex<Todo>,
+    more: Condvar,
+}
+
+struct Todo {
+    cells: Vec<(Arc<Cell>, usize)>, // to compute, with their depth
+    busy: usize, // workers that may yet find more
+}
+
+// one thread's share of an evaluation
+struct Worker<'a> {
+    e: &'a Env,
+    id: usize,
+}
+
+impl<'a> Worker<'a> {
+    // the cell's value, computed here or by whoever got to it first
+    fn fill(&self, c: &Arc<Cell>) -> Value {
+        match *lock(&c.state) {
+            State::Green(ref v) => return v.clone(),
+            State::Stopped => return fail(Code::Circular, format!("Circular reference: {}", path_name(&c.path))),
+            _ => {},
+        }
+        let id = cell_id(c);
+        let mut g = lock(&self.e.sched);
+        loop {
+            let owner = match *lock(&c.state) {
+                State::Green(ref v) => return v.clone(),
+                State::Yellow(o) => o,
+                State::Red => break,
+                State::Stopped => return fail(Code::Circular, format!("Circular reference: {}", path_name(&c.path))),
+            };
+            if let Some(chain) = self.cycle(&mut g, c, owner) {
+                return fail(Code::Circular, chain)
+            }
+            g.waits.insert(self.id, id);
+            g = self.e.wake.wait(g).unwrap_or_else(|p| p.into_inner());
+            g.waits.remove(&self.id);
+        }
+        if let Some(err) = self.over_limit(&g) {
+            return err
+        }
+        *lock(&c.state) = State::Yellow(self.id);
+        g.owners.insert(id, self.id);
+        g.stacks.entry(self.id).or_default().push((id, c.path.clone()));
+        drop(g);
+        self.e.steps.fetch_add(1, Ordering::SeqCst);
+        let mut v = match c.expr {
+            Some(ref x) => self.eval(x, &c.path, parent(&c.path)),
+            None => fail(Code::Internal, "Cell has no expression"),
+        };
+        let mut g = lock(&self.e.sched);
+        if let Some(s) = g.stacks.get_mut(&self.id) {
+            s.pop();
+        }
+        g.owners.remove(&id);
+        if let Some(chain) = g.broken.remove(&c.path) {
+            v = fail(Code::Circular, chain);
+        }
+        let v = locate(v, c);
+        *lock(&c.state) = State::Green(v.clone());
+        drop(g);
+        self.e.wake.notify_all();
+        v
+    }
+
+    // waiting on c, computed by owner, would close a circle of waits:
+    // name the chain of reads around it and break every cell on the way
+    fn cycle(&self, g: &mut Sched, c: &Arc<Cell>, owner: usize) -> Option<String> {
+        let mut o = owner;
+        for _ in 0..=g.waits.len() {
+            if o == self.id {
+                break
+            }
+            o = *g.owners.get(g.waits.get(&o)?)?;
+        }
+        if o != self.id {
+            return None
+        }
+        let (mut o, mut at) = (owner, cell_id(c));
+        let mut ring = vec![];
+        loop {
+            let stack = g.stacks.get(&o).map_or(&[][..], |s| &s[..]);
+            let i = stack.iter().rposition(|s| s.0 == at).unwrap_or(stack.len());
+            ring.extend(stack[i..].iter().map(|s| s.1.clone()));
+            if o == self.id {
+                break
+            }
+            at = g.waits[&o];
+            o = g.owners[&at];
+        }
+        let mut hops: Vec<String> = ring.iter().map(|p| path_name(p)).collect();
+        hops.push(path_name(&c.path));
+        let chain = format!("Circular reference: {}", hops.join(" -> "));
+        for p in ring {
+            g.broken.insert(p, chain.clone());
+        }
+        Some(chain)
+    }
+
+    fn over_limit(&self, g: &Sched) -> Option<Value> {
+        let (start, base) = lock(&self.e.started).unwrap_or_else(|| (Instant::now(), 0));
+        let limits = &self.e.limits;
+        if g.stacks.get(&self.id).map_or(0, |s| s.len()) >= limits.calls {
+            return Some(fail(Code::DepthLimit, format!("More than {} cells computing at once", limits.calls)))
+        }
+        if self.e.steps.load(Ordering::SeqCst) - base >= limits.steps {
+            return Some(fail(Code::StepLimit, format!("More than {} cells computed", limits.steps)))
+        }
+        match limits.timeout {
+            Some(t) if start.elapsed() > t => Some(fail(Code::Timeout, format!("Evaluation took longer than {:?}", t))),
+            _ => None,
+        }
+    }
+
+    // the cells of the structs in v, in name order, to the depth limit
+    fn cells_in(&self, v: &Value, depth: usize, out: &mut Vec<(Arc<Cell>, usize)>) {
+        if depth >= self.e.limits.depth {
+            return
+        }
+        match *v {
+            Value::Sheet(ref s) => {
+                let mut names: Vec<&String> = s.keys().collect();
+                names.sort();
+                out.extend(names.into_iter().map(|k| (s[k].clone(), depth + 1)));
+            },
+            Value::Column(ref xs) => for x in xs.iter() { self.cells_in(x, depth + 1, out) },
+            _ => {},
+        }
+    }
+
+    fn sized(&self, v: Value) -> Value {
+        let (what, n) = match v {
+            Value::Text(ref t) => ("Text", t.len()),
+            Value::Column(ref xs) => ("Column", xs.len()),
+            _ => return v,
+        };
+        if n > self.e.limits.size {
+            return fail(Code::SizeLimit, format!("{} larger than {}", what, self.e.limits.size))
+        }
+        v
+    }
+
+    fn copy(&self, v: &Value) -> Value {
+        if deeper_than(v, self.e.limits.depth) {
+            return fail(Code::DepthLimit, format!("Value nested deeper than {} levels", self.e.limits.depth))
+        }
+        if self.in_progress(v, self.e.limits.depth) {
+            return self.settled(v)
+        }
+        v.clone()
+    }
+
+    // whether v holds a cell this worker is computing, looking no deeper than n;
+    // cells other workers compute are shared, as they will finish
+    fn in_progress(&self, v: &Value, n: usize) -> bool {
+        match *v {
+            Value::Sheet(ref s) => s.values().any(|c| {
+                let v = match *lock(&c.state) {
+                    State::Yellow(o) => return o == self.id,
+                    State::Green(ref v) => v.clone(),
+                    _ => return false,
+                };
+                n > 0 && self.in_progress(&v, n - 1)
+            }),
+            Value::Column(ref xs) => xs.iter().any(|x| n > 0 && self.in_progress(x, n - 1)),
+            _ => false,
+        }
+    }
+
+    // as fig: a copy of v in which the cells this worker is computing are
+    // stopped, so that none of them can end up holding itself
+    fn settled(&self, v: &Value) -> Value {
+        match *v {
+            Value::Sheet(ref s) => Value::Sheet(Arc::new(s.iter().map(|(k, c)| {
+                let state = match *lock(&c.state) {
+                    State::Yellow(o) if o == self.id => State::Stopped,
+                    State::Green(ref v) => State::Green(v.clone()),
+                    _ => return (k.clone(), c.clone()),
+                };
+                let state = match state {
+                    State::Green(v) => State::Green(self.settled(&v)),
+                    s => s,
+                };
+                (k.clone(), new_cell(c.path.clone(), c.expr.clone(), state))
+            }).collect())),
+            Value::Column(ref xs) => Value::Column(xs.iter().map(|x| self.settled(x)).collect()),
+            _ => v.clone(),
+        }
+    }
+
+    fn eval(&self, x: &ast::Expr, here: &[String], my: &[String]) -> Value {
+        match *x {
+            ast::Expr::Literal(ref s) => self.sized(Value::Text(s.clone())),
+            ast::Expr::Column(ref xs) => self.sized(Value::Column(xs.iter().enumerate()
+                .map(|(i, x)| self.eval(x, &child(here, &i.to_string()), my))
+                .collect())),
+            ast::Expr::Struct(ref binds) => Value::Sheet(Arc::new(binds.iter()
+                .map(|b| (b.name.clone(), new_cell(child(here, &b.name), Some(b.value.clone()), State::Red)))
+                .collect())),
+            ast::Expr::KeyRoot | ast::Expr::KeySys | ast::Expr::KeyLib | ast::Expr::KeyUp | ast::Expr::KeyMy =>
+                self.from(x, &[], my),
+            ast::Expr::From(ref xs) => {
+                let mut keys = Vec::with_capacity(xs.len());
+                for k in &xs[1..] {
+                    match self.eval(k, here, my) {
+                        Value::Text(t) => keys.push(t),
+                        Value::Err(err) => return Value::Err(err),
+                        _ => return fail(Code::BadKey, "@from path must be text"),
+                    }
+                }
+                if keys.len() > self.e.limits.depth {
+                    return fail(Code::DepthLimit, format!("@from path longer than {} keys", self.e.limits.depth))
+                }
+                self.from(&xs[0], &keys, my)
+            },
+            ast::Expr::Call{ref function, ref arguments} => {
+                let args = arguments.iter()
+                    .map(|b| (b.name.clone(), to_fig(&self.eval(&b.value, &child(here, &b.name), my))))
+                    .collect();
+                match self.eval(function, here, my) {
+                    Value::Ftn(f) => self.sized(from_fig(&f.call(args))),
+                    Value::Err(err) => Value::Err(err),
+                    _ => fail(Code::NotFunction, "@call expects a function"),
+                }
+            },
+            ast::Expr::Import(ref name) => self.import(name),
+            ast::Expr::Error(ref s) => fail(Code::Syntax, s.clone()),
+        }
+    }
+
+    // follow keys from the head of a @from
+    fn from(&self, head: &ast::Expr, keys: &[String], my: &[String]) -> Value {
+        let base = match *head {
+            ast::Expr::KeyRoot => vec![],
+            ast::Expr::KeyMy => my.to_vec(),
+            ast::Expr::KeyUp => {
+                if my.is_empty() {
+                    return fail(Code::NoParent, "@up has no struct above the root")
+                }
+                parent(my).to_vec()
+            },
+            ast::Expr::KeySys => return self.lookup_sheet(&self.e.sys, keys),
+            ast::Expr::KeyLib => return self.lookup_sheet(&self.e.lib, keys),
+            _ => {
+                let v = self.eval(head, my, my);
+                return match keys.split_first() {
+                    None => v,
+                    Some((k, rest)) => self.lookup_value(&v, k, rest),
+                }
+            },
+        };
+        self.lookup(&self.e.top, &[base, keys.to_vec()].concat())
+    }
+
+    fn lookup(&self, c: &Arc<Cell>, keys: &[String]) -> Value {
+        let v = self.fill(c);
+        match keys.split_first() {
+            None => self.copy(&v),
+            Some((k, rest)) => self.lookup_value(&v, k, rest),
+        }
+    }
+
+    fn lookup_sheet(&self, s: &Arc<Sheet>, keys: &[String]) -> Value {
+        match keys.split_first() {
+            None => Value::Sheet(s.clone()),
+            Some((k, rest)) => match s.get(k) {
+                Some(c) => self.lookup(c, rest),
+                None => fail(Code::NoField, format!("No field {}", lex::quote_name(k))),
+            },
+        }
+    }
+
+    fn lookup_value(&self, v: &Value, k: &str, rest: &[String]) -> Value {
+        match *v {
+            Value::Sheet(ref s) => match s.get(k) {
+                Some(c) => self.lookup(c, rest),
+                None => fail(Code::NoField, format!("No field {}", lex::quote_name(k))),
+            },
+            Value::Column(ref xs) => match k.parse::<usize>().ok().and_then(|i| xs.get(i)) {
+                Some(x) => match rest.split_first() {
+                    None => self.copy(x),
+                    Some((k2, rest2)) => self.lookup_value(x, k2, rest2),
+                },
+                None => fail(Code::NoElement, format!("No element {} in column", lex::quote_name(k))),
+            },
+            Value::Err(_) => v.clone(),
+            _ => fail(Code::NotContainer, format!("Cannot look up {} outside a struct or column", lex::quote_name(k))),
+        }
+    }
+
+    // the evaluated root of another file, on this worker's thread alone
+    fn import(&self, name: &str) -> Value {
+        let e = self.e;
+        let path = match e.file.as_ref().and_then(|f| f.parent()) {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+        let key = canonical(&path);
+        if let Some(v) = lock(&e.imports).get(&key) {
+            return v.clone()
+        }
+        if e.importing.contains(&key) {
+            return fail(Code::CircularImport, format!("Circular @import {}", lex::quote(name)))
+        }
+        let doc = match parse_file_with(&path, &e.limits) {
+            Ok(doc) => doc,
+            Err(err) => return fail(Code::Import, format!("@import {}: {}", lex::quote(name), err)),
+        };
+        if let Some(d) = doc.diagnostics().first() {
+            return fail(Code::Import, format!("@import {}: {}", lex::quote(name), d))
+        }
+        let mut importing = e.importing.clone();
+        importing.push(key.clone());
+        let sub = Env{sys: e.sys.clone(), lib: e.lib.clone(), top: new_cell(vec![], Some(doc.expr().clone()), State::Red),
+            file: Some(path), importing, imports: Mutex::new(HashMap::new()), sched: Mutex::new(Sched::default()),
+            wake: Condvar::new(), workers: AtomicUsize::new(0), steps: AtomicUsize::new(0), limits: e.limits.clone(),
+            started: Mutex::new(None), threads: 1};
+        let v = from_fig(&sub.eval());
+        lock(&e.imports).insert(key, v.clone());
+        v
+    }
+}
+
+// whether v has values nested more than n deep, looking no further
+fn deeper_than(v: &Value, n: usize) -> bool {
+    let inside = |v: &Value| n == 0 || deeper_than(v, n - 1);
+    match *v {
+        Value::Sheet(ref s) => s.values().any(|c| {
+            let v = match *lock(&c.state) {
+                State::Green(ref v) => v.clone(),
+                _ => return false,
+            };
+            inside(&v)
+        }),
+        Value::Column(ref xs) => xs.iter().any(inside),
+        _ => false,
+    }
+}
+
+// an error a cell ends up with says which cell: the one it started in, or
+// the one it passed through on its way from another
+fn locate(v: Value, c: &Cell) -> Value {
+    match v {
+        Value::Err(err) => Value::Err(match err.path.clone() {
+            None => Error{path: Some(c.path.clone()), ..err},
+            Some(ref p) if *p != c.path => err.through(&c.path, None, None),
+            Some(_) => err,
+        }),
+        v => v,
+    }
+}
+
+#[cfg(test)]
+fn both(text: &str, threads: usize) -> (fig::Value, fig::Value, usize) {
+    let b = || fig::Env::builder();
+    let x = ::parse_str(text).expr().clone();
+    let e = b().build_parallel(&x, threads);
+    let v = e.eval();
+    (b().build(&x).eval(), v, e.steps())
+}
+
+#[test]
+fn test_parallel_same_values() {
+    for shape in ::corpus::SHAPES {
+        let text = ::corpus::generate(shape, 30).unwrap();
+        for &threads in &[1, 4] {
+            let (a, b, _) = both(&text, threads);
+            assert_eq!(a, b, "{} on {}", shape, threads);
+        }
+    }
+    let (a, b, steps) = both("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } }
+        @bind c { @call @from @sys { 'error' 'catch' } { @bind value { @from @my { 'nope' } } } } }", 3);
+    assert_eq!(a, b);
+    assert_eq!(steps, 4);
+}
+
+#[test]
+fn test_parallel_cycles() {
+    let text = "@struct { @bind a { @from @my { 'b' } } @bind b { @from @my { 'c' } } @bind c { @from @my { 'a' } }
+        @bind d { @from @my { 'e' } } @bind e { 'e' } }";
+    let (a, b, _) = both(text, 1);
+    assert_eq!(a, b);
+    // which cell meets the cycle first depends on the threads, but every
+    // cell on it is an error and nothing else is
+    for _ in 0..20 {
+        let (_, b, _) = both(text, 4);
+        let s = match b {
+            fig::Value::Sheet(s) => s,
+            v => panic!("{}", v),
+        };
+        for (k, c) in s.iter() {
+            match (k.as_str(), c.value()) {
+                ("a", Some(fig::Value::Err(ref e))) | ("b", Some(fig::Value::Err(ref e))) | ("c", Some(fig::Value::Err(ref e))) =>
+                    assert_eq!(e.code, Code::Circular),
+                ("d", Some(v)) | ("e", Some(v)) => assert_eq!(v, fig::Value::Text("e".to_string())),
+                (k, v) => panic!("{} {:?}", k, v),
+            }
+        }
+    }
+    // a cell that reads its own struct gets a copy, not itself
+    for &threads in &[1, 4] {
+        let (a, b, _) = both("@struct { @bind a { @from @root {} } @bind b { @from @my { 'a' 'a' } } }", threads);
+        assert_eq!(a.to_string(), b.to_string(), "on {}", threads);
+    }
+}
+
+#[test]
+fn test_parallel_send_sync() {
+    fn shared<T: Send + Sync>(_: &T) {}
+    let e = ::parse_str("@column { 'x' }").parallel_env(2);
+    shared(&e);
+    let e = Arc::new(e);
+    let t = {
+        let e = e.clone();
+        thread::spawn(move || e.eval().to_string())
+    };
+    assert_eq!(t.join().unwrap(), e.eval().to_string());
+}
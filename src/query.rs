@@ -0,0 +1,299 @@
+// Queries pulling values out of a document, computing only the cells they
+// reach.
+//
+// A query is a path as errors show it, e.g. root.server.port, where the
+// leading root. may be left out and names that are not bare are quoted,
+// plus these:
+//   [2]            element 2 of a column, the same as .2
+//   [*]            every element of a column
+//   .*             every field of a struct, by name
+//   [name='web']   the elements whose name is the text web; the key may be
+//                  a path, e.g. [meta.role=db]
+
+use fig::{Env, Value};
+use json::{self, Json};
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, PartialEq, Clone)]
+enum Step {
+    Key(String),
+    Each, // element or field
+    Filter(Vec<String>, String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Query {
+    steps: Vec<Step>,
+}
+
+// the values a query found, each with its path
+#[derive(Debug, PartialEq, Clone)]
+pub struct Results {
+    pub values: Vec<(Vec<String>, Value)>,
+    many: bool, // from a [*], .* or filter, so shown as a column
+}
+
+type Src<'a> = Peekable<Chars<'a>>;
+
+// a bare name, or a quoted one as lex::quote writes it
+fn key(src: &mut Src) -> Result<String, String> {
+    match src.peek() {
+        Some(&'\'') => {
+            src.next();
+            let mut k = String::new();
+            loop {
+                match src.next() {
+                    Some('\'') => return Ok(k),
+                    Some('\\') => match src.next() {
+                        Some('n') => k.push('\n'),
+                        Some('t') => k.push('\t'),
+                        Some('0') => k.push('\0'),
+                        Some(c) => k.push(c),
+                        None => return Err("unterminated name".to_string()),
+                    },
+                    Some(c) => k.push(c),
+                    None => return Err("unterminated name".to_string()),
+                }
+            }
+        },
+        _ => {
+            let mut k = String::new();
+            while let Some(&c) = src.peek() {
+                if !c.is_alphanumeric() {
+                    break
+                }
+                k.push(c);
+                src.next();
+            }
+            if k.is_empty() {
+                return Err(match src.peek() {
+                    Some(c) => format!("expected a name, found {}", c),
+                    None => "expected a name".to_string(),
+                })
+            }
+            Ok(k)
+        },
+    }
+}
+
+fn bracket(src: &mut Src) -> Result<Step, String> {
+    let step = if src.peek() == Some(&'*') {
+        src.next();
+        Step::Each
+    } else {
+        let mut keys = vec![key(src)?];
+        while src.peek() == Some(&'.') {
+            src.next();
+            keys.push(key(src)?);
+        }
+        if src.peek() == Some(&'=') {
+            src.next();
+            Step::Filter(keys, key(src)?)
+        } else if keys.len() == 1 {
+            Step::Key(keys.remove(0))
+        } else {
+            return Err("expected = after a path in [ ]".to_string())
+        }
+    };
+    match src.next() {
+        Some(']') => Ok(step),
+        _ => Err("expected ]".to_string()),
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query, String> {
+        let text = text.trim();
+        let text = text.strip_prefix('@').unwrap_or(text);
+        let text = match text.strip_prefix("root") {
+            Some(rest) if rest.is_empty() || rest.starts_with('.') || rest.starts_with('[') =>
+                rest.strip_prefix('.').unwrap_or(rest),
+            _ => text,
+        };
+        let mut src = text.chars().peekable();
+        let mut steps = vec![];
+        let mut first = true;
+        while let Some(&c) = src.peek() {
+            match c {
+                '[' => {
+                    src.next();
+                    steps.push(bracket(&mut src)?);
+                },
+                '.' if !first => {
+                    src.next();
+                    if src.peek() == Some(&'*') {
+                        src.next();
+                        steps.push(Step::Each);
+                    } else {
+                        steps.push(Step::Key(key(&mut src)?));
+                    }
+                },
+                _ if first => steps.push(Step::Key(key(&mut src)?)),
+                _ => return Err(format!("expected . or [, found {}", c)),
+            }
+            first = false;
+        }
+        Ok(Query{steps})
+    }
+
+    // run the query, computing the cells it passes through and those of
+    // the values it finds, and no others
+    pub fn run(&self, e: &Env) -> Results {
+        let mut paths: Vec<Vec<String>> = vec![vec![]];
+        for step in &self.steps {
+            paths = match *step {
+                Step::Key(ref k) => paths.into_iter().map(|p| child(&p, k)).collect(),
+                Step::Each => paths.iter().flat_map(|p| each(e, p)).collect(),
+                Step::Filter(ref ks, ref want) => paths.iter().flat_map(|p| each(e, p)).filter(|p| {
+                    match e.peek(&[&p[..], &ks[..]].concat()) {
+                        Value::Text(ref t) => t == want,
+                        _ => false,
+                    }
+                }).collect(),
+            };
+        }
+        let many = self.steps.iter().any(|s| !matches!(*s, Step::Key(_)));
+        let values = paths.into_iter().map(|p| {
+            let v = e.at(&p);
+            (p, v)
+        }).collect();
+        Results{values, many}
+    }
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+// the paths of the elements of a column in order, or the fields of a
+// struct by name
+fn each(e: &Env, path: &[String]) -> Vec<Vec<String>> {
+    let keys: Vec<String> = match e.peek(path) {
+        Value::Column(ref xs) => (0..xs.len()).map(|i| i.to_string()).collect(),
+        Value::Sheet(ref s) => {
+            let mut keys: Vec<String> = s.keys().cloned().collect();
+            keys.sort();
+            keys
+        },
+        _ => vec![],
+    };
+    keys.iter().map(|k| child(path, k)).collect()
+}
+
+// text as a string, columns as arrays, structs as objects with their
+// fields by name, errors as {"error": {...}}
+pub(crate) fn to_json(v: &Value) -> Json {
+    match *v {
+        Value::Text(ref t) => json::str(t),
+        Value::Column(ref xs) => Json::Arr(xs.iter().map(to_json).collect()),
+        Value::Sheet(ref s) => {
+            let mut names: Vec<&String> = s.keys().collect();
+            names.sort();
+            Json::Obj(names.into_iter().map(|k| {
+                let v = s[k].value().map(|v| to_json(&v)).unwrap_or(Json::Null);
+                (k.clone(), v)
+            }).collect())
+        },
+        Value::Err(ref e) => json::obj(vec![("error", json::obj(vec![
+            ("code", json::str(e.code.id())),
+            ("name", json::str(e.code.name())),
+            ("message", json::str(&e.message)),
+        ]))]),
+        Value::Ftn(_) => json::str(&v.kind()),
+    }
+}
+
+impl Results {
+    pub fn errors(&self) -> Vec<&(Vec<String>, Value)> {
+        self.values.iter().filter(|r| matches!(r.1, Value::Err(_))).collect()
+    }
+
+    // as .bv; many values as a column of them
+    pub fn bv(&self) -> String {
+        if !self.many && self.values.len() == 1 {
+            return self.values[0].1.to_string()
+        }
+        let xs: Vec<Value> = self.values.iter().map(|r| r.1.clone()).collect();
+        Value::Column(xs.into()).to_string()
+    }
+
+    pub fn json(&self) -> String {
+        if !self.many && self.values.len() == 1 {
+            return to_json(&self.values[0].1).to_string()
+        }
+        Json::Arr(self.values.iter().map(|r| to_json(&r.1)).collect()).to_string()
+    }
+
+    // texts as they are, one per line, for scripts; anything else as .bv
+    pub fn raw(&self) -> String {
+        let mut out = String::new();
+        for (_, v) in &self.values {
+            match *v {
+                Value::Text(ref t) => out.push_str(t),
+                _ => out.push_str(&v.to_string()),
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+const HOSTS: &str = "@struct {
+    @bind server { @struct { @bind port { '8080' } @bind 'the name' { 'web' } } }
+    @bind hosts { @column {
+        @struct { @bind name { 'a' } @bind role { 'web' } }
+        @struct { @bind name { 'b' } @bind role { 'db' } }
+        @struct { @bind name { 'c' } @bind role { 'web' } } } } }";
+
+#[cfg(test)]
+fn query(doc: &str, q: &str) -> Results {
+    Query::parse(q).unwrap().run(&::parse_str(doc).env())
+}
+
+#[test]
+fn test_query_parse() {
+    let keys = |ks: &[&str]| ks.iter().map(|k| Step::Key(k.to_string())).collect::<Vec<Step>>();
+    assert_eq!(Query::parse("root.a.b").unwrap().steps, keys(&["a", "b"]));
+    assert_eq!(Query::parse("@root").unwrap().steps, vec![]);
+    assert_eq!(Query::parse("a[2]'x y'").unwrap_err(), "expected . or [, found '");
+    assert_eq!(Query::parse("rooted.'x y'[2]").unwrap().steps, keys(&["rooted", "x y", "2"]));
+    assert_eq!(Query::parse("a[*].*[m.r=w]").unwrap().steps, vec![Step::Key("a".to_string()), Step::Each, Step::Each,
+        Step::Filter(vec!["m".to_string(), "r".to_string()], "w".to_string())]);
+    assert_eq!(Query::parse("a[").unwrap_err(), "expected a name");
+    assert_eq!(Query::parse("a.b.c[x.y]").unwrap_err(), "expected = after a path in [ ]");
+}
+
+#[test]
+fn test_query_values() {
+    assert_eq!(query(HOSTS, "server.port").raw(), "8080\n");
+    assert_eq!(query(HOSTS, "root.server.'the name'").json(), "\"web\"");
+    assert_eq!(query(HOSTS, "hosts[*].name").bv(), "@column {\n  'a'\n  'b'\n  'c'\n}");
+    assert_eq!(query(HOSTS, "hosts[role=web].name").json(), "[\"a\",\"c\"]");
+    assert_eq!(query(HOSTS, "hosts[1]").json(), "{\"name\":\"b\",\"role\":\"db\"}");
+    assert_eq!(query(HOSTS, "server.*").raw(), "8080\nweb\n");
+    // fields by name, even when the names are numbers
+    let numbered = "@struct { @bind n { @struct { @bind '2' { 'b' } @bind '10' { 'c' } @bind '1' { 'a' } } } }";
+    for _ in 0..10 {
+        assert_eq!(query(numbered, "n.*").raw(), "a\nc\nb\n");
+    }
+    let r = query(HOSTS, "hosts[5].name");
+    assert_eq!(r.errors().len(), 1);
+    assert_eq!(r.json(), "{\"error\":{\"code\":\"E0105\",\"name\":\"no-element\",\"message\":\"No element 5 in column\"}}");
+}
+
+#[test]
+fn test_query_lazy() {
+    let doc = ::parse_str("@struct { @bind a { 'x' } @bind b { @struct { @bind c { 'y' } @bind d { 'z' } } }
+        @bind e { @from @my { 'b' } } }");
+    let e = doc.env();
+    Query::parse("b.c").unwrap().run(&e);
+    assert_eq!(e.steps(), 3); // the root, b and c
+    e.eval();
+    assert_eq!(e.steps(), 6);
+    let paths: Vec<String> = query(HOSTS, "hosts[*].name").values.iter().map(|r| ::fig::path_name(&r.0)).collect();
+    assert_eq!(paths, vec!["root.hosts.0.name", "root.hosts.1.name", "root.hosts.2.name"]);
+}
@@ -0,0 +1,194 @@
+use fig;
+use lex;
+use std::io;
+use std::io::{BufRead, Write};
+use {parse_file, parse_str, Diagnostic, Document};
+
+const HELP: &str = "EXPR            evaluate an expression, with @my and @root at the document
+:type EXPR      describe the value of EXPR
+:tokens TEXT    show how TEXT lexes
+:ast TEXT       show how TEXT parses
+:load FILE      evaluate against FILE from now on
+:history        list earlier entries; !N runs entry N again
+:help           this text
+:quit           leave";
+
+// an evaluation session, reading one entry at a time
+pub struct Repl {
+    env: fig::Env,
+    history: Vec<String>,
+}
+
+fn empty_doc() -> Document {
+    parse_str("@struct {}")
+}
+
+// how many more '{' than '}' there are so far
+fn open_curls(text: &str) -> isize {
+    lex::lex_spans(text).iter().fold(0, |n, (_, tok)| match *tok {
+        lex::Tok::CurlL => n + 1,
+        lex::Tok::CurlR => n - 1,
+        _ => n,
+    })
+}
+
+fn write_diagnostics<W: Write>(out: &mut W, prefix: &str, ds: &[Diagnostic]) -> io::Result<()> {
+    for d in ds {
+        writeln!(out, "{}{}", prefix, d)?;
+    }
+    Ok(())
+}
+
+impl Repl {
+    pub fn new(doc: Option<&Document>) -> Repl {
+        let env = match doc {
+            Some(d) => d.env(),
+            None => empty_doc().env(),
+        };
+        Repl{env, history: vec![]}
+    }
+
+    // read entries until EOF or :quit; an entry runs on until its curls close
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W, prompt: bool) -> io::Result<()> {
+        let mut lines = input.lines();
+        let mut chunk = String::new();
+        loop {
+            if prompt {
+                write!(out, "{}", if chunk.is_empty() { "> " } else { ". " })?;
+                out.flush()?;
+            }
+            match lines.next() {
+                Some(line) => {
+                    chunk.push_str(&line?);
+                    chunk.push('\n');
+                    if open_curls(&chunk) > 0 {
+                        continue
+                    }
+                },
+                None => {
+                    if !chunk.trim().is_empty() {
+                        self.entry(chunk.trim(), out)?;
+                    }
+                    return Ok(())
+                },
+            }
+            let entry = chunk.trim().to_string();
+            chunk.clear();
+            if !entry.is_empty() && !self.entry(&entry, out)? {
+                return Ok(())
+            }
+        }
+    }
+
+    // handle one complete entry; false means stop
+    pub fn entry<W: Write>(&mut self, entry: &str, out: &mut W) -> io::Result<bool> {
+        if let Some(n) = entry.strip_prefix('!') {
+            let old = n.trim().parse::<usize>().ok()
+                .and_then(|i| i.checked_sub(1))
+                .and_then(|i| self.history.get(i).cloned());
+            return match old {
+                Some(e) => {
+                    writeln!(out, "{}", e)?;
+                    self.entry(&e, out)
+                },
+                None => {
+                    writeln!(out, "no history entry {}", n.trim())?;
+                    Ok(true)
+                },
+            }
+        }
+        self.history.push(entry.to_string());
+        let (cmd, rest) = match entry.find(char::is_whitespace) {
+            Some(i) if entry.starts_with(':') => (&entry[..i], entry[i..].trim()),
+            _ if entry.starts_with(':') => (entry, ""),
+            _ => ("", entry),
+        };
+        match cmd {
+            "" => {
+                let doc = parse_str(rest);
+                write_diagnostics(out, "", doc.diagnostics())?;
+                writeln!(out, "{}", self.env.evaluate(doc.expr()))?;
+            },
+            ":type" => {
+                let doc = parse_str(rest);
+                write_diagnostics(out, "", doc.diagnostics())?;
+                writeln!(out, "{}", self.env.evaluate(doc.expr()).kind())?;
+            },
+            ":tokens" => {
+                for (span, tok) in lex::lex_spans(rest) {
+                    writeln!(out, "{}..{} {}", span.lo, span.hi, tok)?;
+                }
+            },
+            ":ast" => {
+                let doc = parse_str(rest);
+                write_diagnostics(out, "", doc.diagnostics())?;
+                writeln!(out, "{:?}", doc.expr())?;
+            },
+            ":load" => match parse_file(rest) {
+                Ok(doc) => {
+                    write_diagnostics(out, &format!("{}:", rest), doc.diagnostics())?;
+                    self.env = doc.env();
+                    writeln!(out, "loaded {}", rest)?;
+                },
+                Err(e) => writeln!(out, "{}: {}", rest, e)?,
+            },
+            ":history" => {
+                for (i, e) in self.history.iter().enumerate() {
+                    writeln!(out, "{:>4}  {}", i + 1, e)?;
+                }
+            },
+            ":help" => writeln!(out, "{}", HELP)?,
+            ":quit" | ":q" => return Ok(false),
+            _ => writeln!(out, "unknown command {} (try :help)", cmd)?,
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+fn session(doc: Option<&str>, input: &str) -> String {
+    let d = doc.map(parse_str);
+    let mut out = vec![];
+    Repl::new(d.as_ref()).run(input.as_bytes(), &mut out, false).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_repl_eval() {
+    assert_eq!(session(Some("@struct { @bind a { 'x' } }"), "@from @my { 'a' }\n'y'\n"), "'x'\n'y'\n");
+}
+
+#[test]
+fn test_repl_multiline() {
+    assert_eq!(session(None, "@column {\n  'a'\n  'b' }\n"), "@column {\n  'a'\n  'b'\n}\n");
+}
+
+#[test]
+fn test_repl_type() {
+    assert_eq!(session(Some("@struct { @bind b { 'x' } @bind a { @column {} } }"), ":type @root\n:type @from @root { 'a' }\n"),
+        "struct { a b }\ncolumn of 0\n");
+}
+
+#[test]
+fn test_repl_tokens_ast() {
+    assert_eq!(session(None, ":tokens @my {}\n:ast @my\n"), "0..3 @my\n3..4 White 1\n4..5 Curl Start\n5..6 Curl End\nKeyMy\n");
+}
+
+#[test]
+fn test_repl_history() {
+    assert_eq!(session(None, "'a'\n:history\n!1\n!9\n:quit\n'b'\n"),
+        "'a'\n   1  'a'\n   2  :history\n'a'\n'a'\nno history entry 9\n");
+}
+
+#[test]
+fn test_repl_errors() {
+    assert_eq!(session(None, "@column { _ }\n:nope\n"), "1:11: Bad char: _\n@column {}\nunknown command :nope (try :help)\n");
+}
+
+#[test]
+fn test_repl_own_cells() {
+    // a struct typed in holds its own cells, apart from the document's of the same names
+    assert_eq!(session(Some("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } }"),
+        "@struct { @bind a { 'y' } @bind b { @from @my { 'a' } } @bind c { @from @up { 'b' } } }\n@from @my { 'b' }\n"),
+        "@struct {\n  @bind a { 'y' }\n  @bind b { 'y' }\n  @bind c { 'x' }\n}\n'x'\n");
+}
@@ -0,0 +1,354 @@
+// Schemas, themselves .bv documents, and checking evaluated documents
+// against them.
+//
+// A schema describes a value with a struct of these, all optional:
+//   kind      'text', 'number', 'column', 'struct', 'function' or 'any'
+//   pattern   for text, with * for any run of characters and ? for one
+//   min, max  for numbers
+//   elements  the schema of each element of a column
+//   fields    a struct of the schema of each field of a struct
+//   optional  'true' if the field may be left out
+//   open      'true' if a struct may have fields not named in fields
+// Without a kind, elements makes it a column, fields a struct, pattern text
+// and min or max a number. A text alone, e.g. 'number', is the schema with
+// only that kind.
+
+use fig::{path_name, Value};
+use lex::{self, Span};
+use std::collections::HashMap;
+use std::fmt;
+use types;
+use Document;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Kind {
+    Any,
+    Text,
+    Number,
+    Column,
+    Struct,
+    Function,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Schema {
+    kind: Kind,
+    pattern: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    elements: Option<Box<Schema>>,
+    fields: Vec<(String, Schema)>, // by name
+    optional: bool,
+    open: bool,
+}
+
+// where a document does not keep to its schema
+#[derive(Debug, PartialEq, Clone)]
+pub struct Violation {
+    pub path: Vec<String>,
+    pub message: String,
+    // the @bind the value comes from, or the nearest one around it
+    pub span: Option<Span>,
+    pub line_col: Option<(usize, usize)>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((line, col)) = self.line_col {
+            write!(f, "{}:{}: ", line, col)?;
+        }
+        write!(f, "{}: {}", path_name(&self.path), self.message)
+    }
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+fn flag(v: &Value, at: &[String]) -> Result<bool, String> {
+    match *v {
+        Value::Text(ref t) if t == "true" => Ok(true),
+        Value::Text(ref t) if t == "false" => Ok(false),
+        _ => Err(format!("{}: expected 'true' or 'false', not {}", path_name(at), shown(v))),
+    }
+}
+
+fn bound(v: &Value, at: &[String]) -> Result<f64, String> {
+    match *v {
+        Value::Text(ref t) if types::number(t) => t.parse().map_err(|_| format!("{}: bad number {}", path_name(at), t)),
+        _ => Err(format!("{}: expected a number, not {}", path_name(at), shown(v))),
+    }
+}
+
+// a value as a violation message shows it
+fn shown(v: &Value) -> String {
+    match *v {
+        Value::Text(ref t) => lex::quote(t),
+        Value::Err(ref e) => format!("an error ({}: {})", e.code, e.message),
+        _ => v.kind(),
+    }
+}
+
+// the fields of an evaluated struct, by name
+fn fields(v: &Value) -> Option<Vec<(String, Value)>> {
+    match *v {
+        Value::Sheet(ref s) => {
+            let mut fs: Vec<(String, Value)> = s.iter()
+                .map(|(k, c)| (k.clone(), c.value().unwrap_or(Value::Text(String::new()))))
+                .collect();
+            fs.sort_by(|a, b| a.0.cmp(&b.0));
+            Some(fs)
+        },
+        _ => None,
+    }
+}
+
+// * matches any run of characters, ? any one; on a mismatch only the last
+// * takes one more character, so this is O(pattern * text) at worst
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None; // the last * seen, and where in text its run ends
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Schema {
+    fn any() -> Schema {
+        Schema{kind: Kind::Any, pattern: None, min: None, max: None, elements: None, fields: vec![],
+            optional: false, open: false}
+    }
+
+    // read a schema from the evaluated schema document
+    pub fn from_value(v: &Value) -> Result<Schema, String> {
+        Schema::read(v, &[])
+    }
+
+    // evaluate a schema document and read it
+    pub fn from_document(doc: &Document) -> Result<Schema, String> {
+        if let Some(d) = doc.diagnostics().first() {
+            return Err(d.to_string())
+        }
+        Schema::from_value(&doc.env().eval())
+    }
+
+    fn read(v: &Value, at: &[String]) -> Result<Schema, String> {
+        let mut s = Schema::any();
+        let fs = match *v {
+            Value::Text(_) => vec![("kind".to_string(), v.clone())],
+            _ => match fields(v) {
+                Some(fs) => fs,
+                None => return Err(format!("{}: expected a schema, not {}", path_name(at), shown(v))),
+            },
+        };
+        for (k, v) in fs {
+            let at = child(at, &k);
+            match (k.as_str(), &v) {
+                ("kind", Value::Text(t)) => s.kind = match t.as_str() {
+                    "any" => Kind::Any,
+                    "text" => Kind::Text,
+                    "number" => Kind::Number,
+                    "column" => Kind::Column,
+                    "struct" => Kind::Struct,
+                    "function" => Kind::Function,
+                    _ => return Err(format!("{}: unknown kind {}", path_name(&at), lex::quote(t))),
+                },
+                ("pattern", Value::Text(t)) => s.pattern = Some(t.clone()),
+                ("min", _) => s.min = Some(bound(&v, &at)?),
+                ("max", _) => s.max = Some(bound(&v, &at)?),
+                ("optional", _) => s.optional = flag(&v, &at)?,
+                ("open", _) => s.open = flag(&v, &at)?,
+                ("elements", _) => s.elements = Some(Box::new(Schema::read(&v, &at)?)),
+                ("fields", _) => match fields(&v) {
+                    Some(fs) => for (name, f) in fs {
+                        let f = Schema::read(&f, &child(&at, &name))?;
+                        s.fields.push((name, f));
+                    },
+                    None => return Err(format!("{}: expected a struct, not {}", path_name(&at), shown(&v))),
+                },
+                ("kind", _) | ("pattern", _) =>
+                    return Err(format!("{}: expected text, not {}", path_name(&at), shown(&v))),
+                _ => return Err(format!("{}: unknown schema key {}", path_name(&at), lex::quote_name(&k))),
+            }
+        }
+        if s.kind == Kind::Any {
+            s.kind = match s {
+                Schema{elements: Some(_), ..} => Kind::Column,
+                Schema{ref fields, ..} if !fields.is_empty() => Kind::Struct,
+                Schema{pattern: Some(_), ..} => Kind::Text,
+                Schema{min: Some(_), ..} | Schema{max: Some(_), ..} => Kind::Number,
+                _ => Kind::Any,
+            };
+        }
+        Ok(s)
+    }
+
+    // every place the evaluated value does not keep to the schema
+    pub fn check(&self, v: &Value) -> Vec<Violation> {
+        let mut out = vec![];
+        self.check_at(v, &[], &mut out);
+        out
+    }
+
+    fn check_at(&self, v: &Value, at: &[String], out: &mut Vec<Violation>) {
+        let mut fail = |message: String| out.push(Violation{path: at.to_vec(), message, span: None, line_col: None});
+        if let Value::Err(_) = *v {
+            return fail(format!("Expected a value, not {}", shown(v)))
+        }
+        let kind = match (self.kind, v) {
+            (Kind::Any, _) | (Kind::Text, Value::Text(_)) | (Kind::Column, Value::Column(_))
+                | (Kind::Struct, Value::Sheet(_)) | (Kind::Function, Value::Ftn(_)) => None,
+            (Kind::Number, Value::Text(t)) if types::number(t) => None,
+            (Kind::Text, _) => Some("text"),
+            (Kind::Number, _) => Some("a number"),
+            (Kind::Column, _) => Some("a column"),
+            (Kind::Struct, _) => Some("a struct"),
+            (Kind::Function, _) => Some("a function"),
+        };
+        if let Some(k) = kind {
+            return fail(format!("Expected {}, not {}", k, shown(v)))
+        }
+        if let Value::Text(ref t) = *v {
+            if let Some(ref p) = self.pattern {
+                let (p, c): (Vec<char>, Vec<char>) = (p.chars().collect(), t.chars().collect());
+                if !matches(&p, &c) {
+                    fail(format!("{} does not match {}", lex::quote(t), lex::quote(&p.iter().collect::<String>())));
+                }
+            }
+            if self.min.is_some() || self.max.is_some() {
+                match t.parse::<f64>() {
+                    Ok(n) if types::number(t) => match (self.min, self.max) {
+                        (Some(lo), _) if n < lo => fail(format!("{} is less than {}", t, lo)),
+                        (_, Some(hi)) if n > hi => fail(format!("{} is more than {}", t, hi)),
+                        _ => {},
+                    },
+                    _ => fail(format!("Expected a number, not {}", lex::quote(t))),
+                }
+            }
+        }
+        if let (Some(ref e), Value::Column(xs)) = (&self.elements, v) {
+            for (i, x) in xs.iter().enumerate() {
+                e.check_at(x, &child(at, &i.to_string()), out);
+            }
+            return
+        }
+        let fs = match fields(v) {
+            Some(fs) => fs,
+            None => return,
+        };
+        for (name, f) in &self.fields {
+            match fs.iter().find(|x| &x.0 == name) {
+                Some(x) => f.check_at(&x.1, &child(at, name), out),
+                None if f.optional => {},
+                None => out.push(Violation{path: at.to_vec(), message: format!("Missing field {}", lex::quote_name(name)),
+                    span: None, line_col: None}),
+            }
+        }
+        if !self.open && self.kind == Kind::Struct {
+            for (name, _) in fs.iter().filter(|x| !self.fields.iter().any(|f| f.0 == x.0)) {
+                out.push(Violation{path: child(at, name), message: "Field not in the schema".to_string(),
+                    span: None, line_col: None});
+            }
+        }
+    }
+
+    // evaluate a document and check it, locating each violation in its source
+    pub fn validate(&self, doc: &Document) -> Vec<Violation> {
+        let sites: HashMap<&[String], Span> = doc.sites.iter().map(|s| (&s.path[..], s.name)).collect();
+        let mut vs = self.check(&doc.env().eval());
+        for v in &mut vs {
+            // the root has no @bind; it starts the document
+            let span = (0..=v.path.len()).rev().find_map(|n| sites.get(&v.path[..n]).cloned())
+                .unwrap_or_default();
+            v.span = Some(span);
+            v.line_col = Some(lex::line_col(doc.text(), span.lo));
+        }
+        vs
+    }
+}
+
+#[cfg(test)]
+fn schema(s: &str) -> Schema {
+    Schema::from_document(&::parse_str(s)).unwrap()
+}
+
+#[cfg(test)]
+fn violations(schema: &Schema, doc: &str) -> Vec<String> {
+    schema.validate(&::parse_str(doc)).iter().map(|v| v.to_string()).collect()
+}
+
+#[cfg(test)]
+const SERVER: &str = "@struct { @bind kind { 'struct' } @bind fields { @struct {
+    @bind port { @struct { @bind kind { 'number' } @bind min { '1' } @bind max { '65535' } } }
+    @bind name { @struct { @bind pattern { 'web-*' } } }
+    @bind debug { @struct { @bind kind { 'text' } @bind optional { 'true' } } }
+    @bind hosts { @struct { @bind elements { @struct { @bind fields { @struct { @bind ip { 'text' } } } } } } } } } }";
+
+#[test]
+fn test_schema_valid() {
+    let s = schema(SERVER);
+    assert!(violations(&s, "@struct { @bind port { '8080' } @bind name { 'web-1' }
+        @bind hosts { @column { @struct { @bind ip { '10.0.0.1' } } } } }").is_empty());
+    assert!(violations(&schema("'any'"), "@column { 'x' }").is_empty());
+}
+
+#[test]
+fn test_schema_violations() {
+    let s = schema(SERVER);
+    assert_eq!(violations(&s, "@struct {
+        @bind port { '70000' } @bind name { 'db-1' } @bind extra { 'x' }
+        @bind hosts { @column { @struct { @bind ip { @column {} } } @struct {} } } }"), vec![
+        "3:49: root.hosts.0.ip: Expected text, not column of 0",
+        "3:15: root.hosts.1: Missing field ip",
+        "2:38: root.name: 'db-1' does not match 'web-*'",
+        "2:15: root.port: 70000 is more than 65535",
+        "2:60: root.extra: Field not in the schema",
+    ]);
+    assert_eq!(violations(&s, "@struct { @bind port { 'x' } @bind name { @from @my { 'nope' } } @bind hosts { 'h' } }"), vec![
+        "1:72: root.hosts: Expected a column, not 'h'",
+        "1:36: root.name: Expected a value, not an error (E0101 no-field: No field nope)",
+        "1:17: root.port: Expected a number, not 'x'",
+    ]);
+}
+
+#[test]
+fn test_schema_errors() {
+    let err = |s: &str| Schema::from_document(&::parse_str(s)).unwrap_err();
+    assert_eq!(err("'float'"), "root.kind: unknown kind 'float'");
+    assert_eq!(err("@struct { @bind min { 'low' } }"), "root.min: expected a number, not 'low'");
+    assert_eq!(err("@struct { @bind colour { 'red' } }"), "root.colour: unknown schema key colour");
+    assert_eq!(err("@struct { @bind open { 'yes' } }"), "root.open: expected 'true' or 'false', not 'yes'");
+    assert!(matches(&['a', '*', '?'], &['a', 'b', 'c']) && !matches(&['a', '?'], &['a']));
+}
+
+#[test]
+fn test_schema_glob() {
+    let m = |p: &str, t: &str| matches(&p.chars().collect::<Vec<_>>(), &t.chars().collect::<Vec<_>>());
+    assert!(m("", "") && m("*", "") && m("**", "abc") && m("a*c", "abbbc") && m("*b?d*", "abcde"));
+    assert!(m("web-*", "web-") && m("*x*x", "axbxcx x") && !m("*x*x", "axb"));
+    assert!(!m("", "a") && !m("a*c", "abcd") && !m("?", "") && !m("*b", "aaa"));
+    // many stars and a long text that does not match, without backtracking forever
+    let text = "a".repeat(10_000);
+    assert!(!m(&format!("{}b", "*a".repeat(20)), &text));
+    assert!(m(&format!("{}*", "*a".repeat(20)), &text));
+}
@@ -0,0 +1,443 @@
+// Types of a document's binds, found without evaluating it.
+
+use ast::{Bind, Expr};
+use fig::path_name;
+use lex;
+use std::collections::HashMap;
+use std::fmt;
+use Limits;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Type {
+    Any, // not known before evaluation
+    Text,
+    Number, // text that reads as a number
+    Column(Box<Type>),
+    Struct(Vec<(String, Type)>), // by name
+    Function{params: Vec<(String, Type)>, result: Box<Type>},
+}
+
+fn write_fields(f: &mut fmt::Formatter, fields: &[(String, Type)]) -> fmt::Result {
+    if fields.is_empty() {
+        return write!(f, "{{}}")
+    }
+    write!(f, "{{")?;
+    for (name, t) in fields {
+        write!(f, " {}: {}", lex::quote_name(name), t)?;
+    }
+    write!(f, " }}")
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Any => write!(f, "any"),
+            Type::Text => write!(f, "text"),
+            Type::Number => write!(f, "number"),
+            Type::Column(ref t) => write!(f, "column of {}", t),
+            Type::Struct(ref fields) => {
+                write!(f, "struct ")?;
+                write_fields(f, fields)
+            },
+            Type::Function{ref params, ref result} => {
+                write!(f, "function ")?;
+                write_fields(f, params)?;
+                write!(f, " -> {}", result)
+            },
+        }
+    }
+}
+
+impl Type {
+    // whether a value of type t can stand where this type is wanted
+    pub fn accepts(&self, t: &Type) -> bool {
+        match (self, t) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Text, Type::Text) | (Type::Text, Type::Number) | (Type::Number, Type::Number) => true,
+            (Type::Column(a), Type::Column(b)) => a.accepts(b),
+            (Type::Struct(want), Type::Struct(have)) => want.iter()
+                .all(|(k, w)| have.iter().any(|(n, h)| n == k && w.accepts(h))),
+            (Type::Function{..}, Type::Function{..}) => true,
+            _ => false,
+        }
+    }
+
+    // the narrowest type holding both, e.g. for the elements of a column
+    pub fn join(&self, t: &Type) -> Type {
+        match (self, t) {
+            _ if self == t => self.clone(),
+            (Type::Text, Type::Number) | (Type::Number, Type::Text) => Type::Text,
+            (Type::Column(a), Type::Column(b)) => Type::Column(Box::new(a.join(b))),
+            (Type::Struct(a), Type::Struct(b))
+                    if a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.0 == y.0) =>
+                Type::Struct(a.iter().zip(b).map(|(x, y)| (x.0.clone(), x.1.join(&y.1))).collect()),
+            _ => Type::Any,
+        }
+    }
+}
+
+pub(crate) fn number(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let mut parts = digits.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let frac = parts.next();
+    !whole.is_empty() && whole.chars().all(|c| c.is_ascii_digit())
+        && frac.is_none_or(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn function(params: &[(&str, Type)], result: Type) -> Type {
+    Type::Function{params: params.iter().map(|(p, t)| (p.to_string(), t.clone())).collect(), result: Box::new(result)}
+}
+
+// what the built-in @sys functions take and give
+fn sys(keys: &[String]) -> Type {
+    let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+    match keys[..] {
+        ["text", "reverse"] => function(&[("a", Type::Text)], Type::Text),
+        ["error", "raise"] => function(&[("message", Type::Text)], Type::Any),
+        ["error", "catch"] => function(&[("value", Type::Any)], Type::Any),
+        ["error", "default"] | ["try"] => function(&[("value", Type::Any), ("default", Type::Any)], Type::Any),
+        _ => Type::Any,
+    }
+}
+
+// something that cannot go right when the document is evaluated
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mismatch {
+    pub path: Vec<String>, // of the cell it is in
+    pub message: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", path_name(&self.path), self.message)
+    }
+}
+
+// every @bind with its type, in document order, and what cannot work
+#[derive(Debug, PartialEq, Clone)]
+pub struct Types {
+    pub binds: Vec<(Vec<String>, Type)>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+// one bind per line
+impl fmt::Display for Types {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (p, t) in &self.binds {
+            writeln!(f, "{}: {}", path_name(p), t)?;
+        }
+        Ok(())
+    }
+}
+
+enum Known {
+    Busy, // being worked out; reading it again is a cycle
+    Done(Type),
+}
+
+struct Infer<'a> {
+    root: &'a Expr,
+    known: HashMap<Vec<String>, Known>,
+    fields: HashMap<Vec<String>, HashMap<&'a str, &'a Expr>>, // each struct's binds by name, as looked in
+    cells: Vec<Vec<String>>, // being worked out, innermost last
+    calls: usize, // how many cells may be worked out at once, as in evaluation
+    mismatches: Vec<Mismatch>,
+}
+
+fn child(path: &[String], name: &str) -> Vec<String> {
+    let mut p = path.to_vec();
+    p.push(name.to_string());
+    p
+}
+
+fn parent(path: &[String]) -> &[String] {
+    &path[..path.len().saturating_sub(1)]
+}
+
+impl<'a> Infer<'a> {
+    fn report(&mut self, message: String) -> Type {
+        let path = self.cells.last().cloned().unwrap_or_default();
+        self.mismatches.push(Mismatch{path, message});
+        Type::Any
+    }
+
+    // the type of the value at a path from the root
+    fn at(&mut self, path: &[String]) -> Type {
+        match self.known.get(path) {
+            Some(Known::Done(t)) => return t.clone(),
+            Some(Known::Busy) => return Type::Any,
+            None => {},
+        }
+        if self.cells.len() >= self.calls {
+            return self.report(format!("More than {} cells computing at once", self.calls))
+        }
+        self.known.insert(path.to_vec(), Known::Busy);
+        let t = self.walk(path);
+        self.known.insert(path.to_vec(), Known::Done(t.clone()));
+        t
+    }
+
+    // follow the source down a path; where it is not spelled out, go by type.
+    // a path that leads nowhere is reported in the cell that read it
+    fn walk(&mut self, path: &[String]) -> Type {
+        let mut x = self.root;
+        let mut my: &[String] = &[];
+        for (i, k) in path.iter().enumerate() {
+            let here = &path[..i];
+            match *x {
+                Expr::Struct(ref binds) => match self.bind(here, binds, k) {
+                    Some(v) => {
+                        my = here;
+                        x = v;
+                    },
+                    None => return self.report(format!("No field {}", lex::quote_name(k))),
+                },
+                Expr::Column(ref xs) => match k.parse::<usize>().ok().and_then(|n| xs.get(n)) {
+                    Some(e) => x = e,
+                    None => return self.report(format!("No element {} in column", lex::quote_name(k))),
+                },
+                _ => {
+                    let t = self.at(here);
+                    return self.field(t, &path[i..])
+                },
+            }
+        }
+        let my = my.to_vec();
+        self.cells.push(path.to_vec());
+        let t = self.expr(x, path, &my);
+        self.cells.pop();
+        t
+    }
+
+    // the value of the struct at here bound to k; a later bind of a name
+    // is the one that counts
+    fn bind(&mut self, here: &[String], binds: &'a [Bind], k: &str) -> Option<&'a Expr> {
+        let fields = self.fields.entry(here.to_vec())
+            .or_insert_with(|| binds.iter().map(|b| (&b.name[..], &b.value)).collect());
+        fields.get(k).cloned()
+    }
+
+    // look keys up in a value of type t
+    fn field(&mut self, t: Type, keys: &[String]) -> Type {
+        let (k, rest) = match keys.split_first() {
+            None => return t,
+            Some(p) => p,
+        };
+        let inner = match t {
+            Type::Any => return Type::Any,
+            Type::Struct(ref fields) => match fields.iter().find(|f| &f.0 == k) {
+                Some(f) => f.1.clone(),
+                None => return self.report(format!("No field {}", lex::quote_name(k))),
+            },
+            Type::Column(ref e) if k.parse::<usize>().is_ok() => (**e).clone(),
+            ref t => return self.report(format!("Cannot look up {} in {}", lex::quote_name(k), t)),
+        };
+        self.field(inner, rest)
+    }
+
+    // as fig::eval, with types for values
+    fn expr(&mut self, x: &Expr, here: &[String], my: &[String]) -> Type {
+        match *x {
+            Expr::Literal(ref s) if number(s) => Type::Number,
+            Expr::Literal(_) => Type::Text,
+            Expr::Column(ref xs) => {
+                let ts: Vec<Type> = xs.iter().enumerate()
+                    .map(|(i, x)| self.expr(x, &child(here, &i.to_string()), my))
+                    .collect();
+                let elem = match ts.split_first() {
+                    Some((t, rest)) => rest.iter().fold(t.clone(), |a, b| a.join(b)),
+                    None => Type::Any,
+                };
+                Type::Column(Box::new(elem))
+            },
+            Expr::Struct(ref binds) => {
+                let mut names: Vec<&String> = binds.iter().map(|b| &b.name).collect();
+                names.sort();
+                names.dedup();
+                Type::Struct(names.into_iter().map(|n| (n.clone(), self.at(&child(here, n)))).collect())
+            },
+            Expr::KeyRoot | Expr::KeySys | Expr::KeyLib | Expr::KeyUp | Expr::KeyMy => self.from(x, &[], my),
+            Expr::From(ref xs) => {
+                let mut keys = vec![];
+                for k in &xs[1..] {
+                    match *k {
+                        Expr::Literal(ref t) => keys.push(t.clone()),
+                        _ => {
+                            let t = self.expr(k, here, my);
+                            if !Type::Text.accepts(&t) {
+                                self.report(format!("@from path must be text, not {}", t));
+                            }
+                            return Type::Any
+                        },
+                    }
+                }
+                self.from(&xs[0], &keys, my)
+            },
+            Expr::Call{ref function, ref arguments} => {
+                let args: Vec<(String, Type)> = arguments.iter()
+                    .map(|b| (b.name.clone(), self.expr(&b.value, &child(here, &b.name), my)))
+                    .collect();
+                let f = self.expr(function, here, my);
+                self.call(function, f, &args)
+            },
+            Expr::Import(_) | Expr::Error(_) => Type::Any,
+        }
+    }
+
+    fn from(&mut self, head: &Expr, keys: &[String], my: &[String]) -> Type {
+        let base = match *head {
+            Expr::KeyRoot => vec![],
+            Expr::KeyMy => my.to_vec(),
+            Expr::KeyUp if my.is_empty() => return self.report("@up has no struct above the root".to_string()),
+            Expr::KeyUp => parent(my).to_vec(),
+            Expr::KeySys => return sys(keys),
+            Expr::KeyLib => return Type::Any,
+            _ => {
+                let t = self.expr(head, my, my);
+                return self.field(t, keys)
+            },
+        };
+        self.at(&[base, keys.to_vec()].concat())
+    }
+
+    fn call(&mut self, function: &Expr, f: Type, args: &[(String, Type)]) -> Type {
+        let name = match *function {
+            Expr::From(ref xs) if xs[0] == Expr::KeySys => {
+                let keys: Vec<String> = xs[1..].iter().map(|k| match *k {
+                    Expr::Literal(ref s) => s.clone(),
+                    _ => "?".to_string(),
+                }).collect();
+                format!("@sys.{}", keys.join("."))
+            },
+            _ => "the function".to_string(),
+        };
+        let (params, result) = match f {
+            Type::Any => return Type::Any,
+            Type::Function{params, result} => (params, result),
+            t => return self.report(format!("@call expects a function, not {}", t)),
+        };
+        for (p, want) in &params {
+            match args.iter().find(|a| &a.0 == p) {
+                None => { self.report(format!("{} expects argument {}", name, lex::quote(p))); },
+                Some((_, have)) if !want.accepts(have) => {
+                    self.report(format!("{} expects {} argument {}, not {}", name, want, lex::quote(p), have));
+                },
+                Some(_) => {},
+            }
+        }
+        for (a, _) in args {
+            if !params.iter().any(|p| &p.0 == a) {
+                self.report(format!("{} takes no argument {}", name, lex::quote(a)));
+            }
+        }
+        *result
+    }
+}
+
+// the paths of every @bind outside a @call or @from, in document order
+fn binds(x: &Expr, here: &[String], out: &mut Vec<Vec<String>>) {
+    match *x {
+        Expr::Struct(ref bs) => for b in bs {
+            let p = child(here, &b.name);
+            out.push(p.clone());
+            binds(&b.value, &p, out)
+        },
+        Expr::Column(ref xs) => for (i, x) in xs.iter().enumerate() {
+            binds(x, &child(here, &i.to_string()), out)
+        },
+        _ => {},
+    }
+}
+
+// infer the type of every @bind in a document
+pub fn check(x: &Expr) -> Types {
+    let mut paths = vec![];
+    binds(x, &[], &mut paths);
+    let mut infer = Infer{root: x, known: HashMap::new(), fields: HashMap::new(), cells: vec![], calls: Limits::default().calls,
+        mismatches: vec![]};
+    // in document order, so a chain of binds each reading the one before
+    // is worked out a link at a time
+    let binds = paths.into_iter().map(|p| {
+        let t = infer.at(&p);
+        (p, t)
+    }).collect();
+    infer.at(&[]);
+    Types{binds, mismatches: infer.mismatches}
+}
+
+#[cfg(test)]
+fn types(s: &str) -> Types {
+    check(::parse_str(s).expr())
+}
+
+#[cfg(test)]
+fn shown(t: &Types) -> Vec<String> {
+    t.to_string().lines().map(|l| l.to_string()).collect()
+}
+
+#[test]
+fn test_types_basic() {
+    let t = types("@struct {
+        @bind server { @struct { @bind port { '8080' } @bind name { 'web' } } }
+        @bind hosts { @column { @struct { @bind name { 'a' } } @struct { @bind name { 'b' } } } }
+        @bind port { @from @my { 'server' 'port' } }
+        @bind first { @from @my { 'hosts' '0' 'name' } } }");
+    assert_eq!(shown(&t), vec![
+        "root.server: struct { name: text port: number }",
+        "root.server.port: number",
+        "root.server.name: text",
+        "root.hosts: column of struct { name: text }",
+        "root.hosts.0.name: text",
+        "root.hosts.1.name: text",
+        "root.port: number",
+        "root.first: text",
+    ]);
+    assert!(t.mismatches.is_empty());
+}
+
+#[test]
+fn test_types_functions() {
+    let t = types("@struct { @bind r { @from @sys { 'text' 'reverse' } }
+        @bind x { @call @from @my { 'r' } { @bind a { 'abc' } } } }");
+    assert_eq!(shown(&t), vec!["root.r: function { a: text } -> text", "root.x: text"]);
+}
+
+#[test]
+fn test_types_mismatches() {
+    let t = types("@struct { @bind a { @call @from @my { 'b' } {} } @bind b { 'x' }
+        @bind c { @call @from @sys { 'text' 'reverse' } { @bind a { @column { 'x' } } @bind z { 'y' } } }
+        @bind d { @from @my { 'b' 'k' } } @bind e { @from @my { 'nope' } } }");
+    let ms: Vec<String> = t.mismatches.iter().map(|m| m.to_string()).collect();
+    assert_eq!(ms, vec![
+        "root.a: @call expects a function, not text",
+        "root.c: @sys.text.reverse expects text argument 'a', not column of text",
+        "root.c: @sys.text.reverse takes no argument 'z'",
+        "root.d: Cannot look up k in text",
+        "root.e: No field nope",
+    ]);
+}
+
+#[test]
+fn test_types_cycles_and_joins() {
+    let t = types("@struct { @bind a { @from @my { 'a' } } @bind b { @column { '1' 'x' } }
+        @bind c { @column { '1' @column {} } } @bind d { @column {} } }");
+    assert_eq!(shown(&t), vec!["root.a: any", "root.b: column of text", "root.c: column of any", "root.d: column of any"]);
+    assert!(number("-1.5") && number("10") && !number("1.") && !number("x1") && !number("-"));
+}
+
+#[test]
+fn test_types_long_chain() {
+    let t = types(&::corpus::wide(20_000));
+    assert!(t.mismatches.is_empty());
+    assert_eq!(t.binds[19_999], (vec!["k19999".to_string()], Type::Text));
+    // each reading the one after: too deep to work out, as to evaluate
+    let mut text = String::from("@struct {\n");
+    for i in 0..20_000 {
+        text.push_str(&format!("  @bind k{} {{ @from @my {{ 'k{}' }} }}\n", i, i + 1));
+    }
+    text.push_str("  @bind k20000 { 'last' }\n}\n");
+    let t = types(&text);
+    assert_eq!(t.binds[0].1, Type::Any);
+    assert_eq!(t.mismatches[0].to_string(), "root.k127: More than 128 cells computing at once");
+}
@@ -0,0 +1,115 @@
+// Waiting for files to change. Each file's modification time and length
+// are looked at every so often; it needs nothing from the platform, and
+// works the same on a network drive.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// what a file looked like, or None if it was not there
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(p: &Path) -> Stamp {
+    let m = fs::metadata(p).ok()?;
+    Some((m.modified().ok()?, m.len()))
+}
+
+#[derive(Debug)]
+pub struct Watch {
+    seen: HashMap<PathBuf, Stamp>,
+    interval: Duration,
+}
+
+impl Watch {
+    pub fn new(interval: Duration) -> Watch {
+        Watch{seen: HashMap::new(), interval}
+    }
+
+    // watch just these files; one watched already keeps what it looked like
+    // then, so a change since is still seen
+    pub fn set<I: IntoIterator<Item = PathBuf>>(&mut self, files: I) {
+        let mut seen = HashMap::new();
+        for f in files {
+            let s = self.seen.remove(&f).unwrap_or_else(|| stamp(&f));
+            seen.insert(f, s);
+        }
+        self.seen = seen;
+    }
+
+    // the files that changed, appeared or went away since last looked at
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        for (f, s) in self.seen.iter_mut() {
+            let now = stamp(f);
+            if now != *s {
+                *s = now;
+                changed.push(f.clone());
+            }
+        }
+        changed.sort();
+        changed
+    }
+
+    // wait until some file changes
+    pub fn wait(&mut self) -> Vec<PathBuf> {
+        loop {
+            let changed = self.changed();
+            if !changed.is_empty() {
+                return changed
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+// the .bv files in dir and below it, and the directories, which change
+// when a file comes or goes
+pub fn bv_tree(dir: &Path) -> Vec<PathBuf> {
+    let mut found = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(d) = dirs.pop() {
+        if let Ok(entries) = fs::read_dir(&d) {
+            for p in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if p.is_dir() {
+                    dirs.push(p);
+                } else if p.extension().is_some_and(|x| x == "bv") {
+                    found.push(p);
+                }
+            }
+        }
+        found.push(d);
+    }
+    found.sort();
+    found
+}
+
+#[test]
+fn test_watch_changes() {
+    let dir = ::std::env::temp_dir().join(format!("val-watch-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let (a, b) = (dir.join("a.bv"), dir.join("sub").join("b.bv"));
+    fs::write(&a, "'a'").unwrap();
+    fs::write(&b, "'b'").unwrap();
+    fs::write(dir.join("notes.txt"), "").unwrap();
+    assert_eq!(bv_tree(&dir), vec![dir.clone(), a.clone(), dir.join("sub"), b.clone()]);
+    let mut w = Watch::new(Duration::from_millis(10));
+    w.set(vec![a.clone(), b.clone()]);
+    assert_eq!(w.changed(), Vec::<PathBuf>::new());
+    fs::write(&a, "'aa'").unwrap();
+    // set again before looking: the change is still seen
+    w.set(vec![b.clone(), a.clone()]);
+    assert_eq!(w.wait(), vec![a.clone()]);
+    assert_eq!(w.changed(), Vec::<PathBuf>::new());
+    fs::remove_file(&b).unwrap();
+    assert_eq!(w.changed(), vec![b.clone()]);
+    fs::write(&b, "'b'").unwrap();
+    assert_eq!(w.changed(), vec![b.clone()]);
+    // a file no longer watched is not looked at
+    w.set(vec![b.clone()]);
+    fs::write(&a, "'aaa'").unwrap();
+    assert_eq!(w.changed(), Vec::<PathBuf>::new());
+    let _ = fs::remove_dir_all(&dir);
+}
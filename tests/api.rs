@@ -0,0 +1,162 @@
+extern crate val;
+
+use std::collections::HashMap;
+use val::{Cell, Code, Error, Value};
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+#[test]
+fn test_parse_str_ok() {
+    let doc = val::parse_str("@column { 'a' 'b' }");
+    assert!(doc.diagnostics().is_empty());
+    assert_eq!(doc.env().eval(), Value::Column(vec![text("a"), text("b")].into()));
+}
+
+#[test]
+fn test_parse_str_diagnostics() {
+    let doc = val::parse_str("@struct {\n  @bind a { 'x' _ }\n}");
+    let msgs: Vec<String> = doc.diagnostics().iter().map(|d| d.to_string()).collect();
+    assert_eq!(msgs, vec!["2:17: Bad char: _"]);
+}
+
+#[test]
+fn test_eval_struct() {
+    let doc = val::parse_str("@struct { @bind a { 'x' } @bind b { @from @my { 'a' } } }");
+    let mut expect = HashMap::new();
+    expect.insert("a".to_string(), Cell::new(text("x")));
+    expect.insert("b".to_string(), Cell::new(text("x")));
+    assert_eq!(doc.env().eval(), Value::Sheet(expect.into()));
+}
+
+#[test]
+fn test_display_round_trip() {
+    let doc = val::parse_str("@struct { @bind 'a b' { @column { n\"it's\" 'ok' } } }");
+    let shown = doc.env().eval().to_string();
+    let again = val::parse_str(&shown);
+    assert!(again.diagnostics().is_empty());
+    assert_eq!(again.env().eval().to_string(), shown);
+}
+
+#[test]
+fn test_parse_file() {
+    let doc = val::parse_file("sample/t4.bv").unwrap();
+    assert_eq!(doc.env().eval(), Value::Column(vec![text("one"), text("two"), text("three")].into()));
+    assert!(val::parse_file("sample/missing.bv").is_err());
+}
+
+fn scratch(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("val-test-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_import() {
+    let dir = scratch("import");
+    std::fs::write(dir.join("lib.bv"), "@struct { @bind port { '80' } @bind me { @from @my { 'port' } } }").unwrap();
+    std::fs::write(dir.join("main.bv"), "@struct { @bind base { @import 'lib.bv' }
+        @bind port { @from @root { 'base' 'me' } } }").unwrap();
+    let doc = val::parse_file(dir.join("main.bv")).unwrap();
+    assert_eq!(val::parse_str(&doc.env().eval().to_string()).env().eval().to_string(),
+        "@struct {\n  @bind base { @struct {\n    @bind me { '80' }\n    @bind port { '80' }\n  } }\n  @bind port { '80' }\n}");
+}
+
+#[test]
+fn test_import_lazy() {
+    let dir = scratch("import-lazy");
+    std::fs::write(dir.join("shared.bv"), "@struct { @bind web { @struct { @bind port { '80' } } }
+        @bind db { @from @my { 'nope' } } }").unwrap();
+    std::fs::write(dir.join("web.bv"), "@struct { @bind shared { @import 'shared.bv' }
+        @bind port { @from @my { 'shared' 'web' 'port' } } }").unwrap();
+    let env = val::parse_file(dir.join("web.bv")).unwrap().env();
+    assert_eq!(env.files(), vec![dir.join("web.bv")]);
+    assert_eq!(env.get(&["port"]), text("80"));
+    assert_eq!(env.files(), vec![dir.join("web.bv"), dir.join("shared.bv")]);
+    // port, shared and the root here; web, its port and the root there
+    assert_eq!(env.steps(), 6);
+    assert!(env.eval().to_string().contains("<error E0101: No field nope>"));
+}
+
+#[test]
+fn test_import_errors() {
+    let dir = scratch("import-errors");
+    std::fs::write(dir.join("a.bv"), "@column { @import 'b.bv' }").unwrap();
+    std::fs::write(dir.join("b.bv"), "@column { @import 'a.bv' @import 'c.bv' }").unwrap();
+    let env = val::parse_file(dir.join("a.bv")).unwrap().env();
+    match env.eval() {
+        Value::Column(ref xs) => match xs[0] {
+            Value::Column(ref ys) => {
+                assert_eq!(ys[0], Value::Err(Error::new(Code::CircularImport, "Circular @import 'a.bv'")));
+                assert!(ys[1].to_string().starts_with("<error E0111: @import 'c.bv': "));
+            },
+            ref v => panic!("{}", v),
+        },
+        ref v => panic!("{}", v),
+    }
+    // c.bv is watched for, though it is not there
+    assert_eq!(env.files(), vec![dir.join("a.bv"), dir.join("b.bv"), dir.join("c.bv")]);
+}
+
+#[test]
+fn test_edit() {
+    let doc = |c: &str, e: &str| val::parse_str(&format!("@struct {{ @bind a {{ 'abc' }}
+        @bind r {{ @call @from @sys {{ 'text' 'reverse' }} {{ @bind a {{ @from @my {{ 'a' }} }} }} }}
+        @bind c {{ '{}' }} @bind d {{ @column {{ 'p' @from @my {{ 'c' }} }} }} {} }}", c, e));
+    let (first, second, third) = (doc("x", ""), doc("y", ""), doc("y", "@bind e { @from @my { 'r' } }"));
+    let env = first.env();
+    env.eval();
+    let steps = env.steps();
+    env.edit(&first, &second);
+    assert_eq!(env.eval(), second.env().eval());
+    // c and d, which read it; not a or r
+    assert_eq!(env.steps() - steps, 2);
+    // a bind added or removed gives the struct around it a new expression
+    let steps = env.steps();
+    env.edit(&second, &third);
+    assert_eq!(env.eval(), third.env().eval());
+    assert_eq!(env.steps() - steps, 6);
+}
+
+#[test]
+fn test_reload() {
+    let dir = scratch("reload");
+    std::fs::write(dir.join("base.bv"), "@struct { @bind port { '80' } }").unwrap();
+    std::fs::write(dir.join("lib.bv"), "@struct { @bind base { @import 'base.bv' } @bind name { 'web' } }").unwrap();
+    std::fs::write(dir.join("main.bv"), "@struct { @bind lib { @import 'lib.bv' } @bind other { 'x' }
+        @bind port { @from @my { 'lib' 'base' 'port' } } @bind bad { @import 'bad.bv' } }").unwrap();
+    let env = val::parse_file(dir.join("main.bv")).unwrap().env();
+    assert!(env.eval().to_string().contains("@bind port { '80' }"));
+    assert!(!env.reload(&dir.join("other.bv")));
+    std::fs::write(dir.join("base.bv"), "@struct { @bind port { '81' } }").unwrap();
+    assert!(env.reload(&dir.join("base.bv")));
+    // what read it, through lib, is computed again
+    assert_eq!(env.get(&["port"]), text("81"));
+    let v = env.eval().to_string();
+    assert!(v.contains("<error E0111: @import 'bad.bv'"), "{}", v);
+    // a file that could not be read is read again once it is there
+    std::fs::write(dir.join("bad.bv"), "'ok'").unwrap();
+    assert!(env.reload(&dir.join("bad.bv")));
+    assert!(env.eval().to_string().contains("@bind bad { 'ok' }"));
+}
+
+#[test]
+fn test_limits_adversarial() {
+    let deep = format!("{}'x'{}", "@struct { @bind a { ".repeat(50_000), " } }".repeat(50_000));
+    let doc = val::parse_str(&deep);
+    assert_eq!(doc.diagnostics().len(), 1);
+    assert_eq!(doc.diagnostics()[0].message, "Nested deeper than 128 levels");
+    assert!(matches!(doc.env().eval(), Value::Err(_)));
+
+    let long = format!("@from @root {{ {} }}", "'a' ".repeat(50_000));
+    match val::parse_str(&long).env().eval() {
+        Value::Err(e) => assert_eq!(e.code, Code::DepthLimit),
+        v => panic!("not an error: {}", v),
+    }
+
+    let limits = val::Limits{depth: 8, ..val::Limits::default()};
+    let doc = val::parse_str_with("@column { @column { @column { 'x' } } }", &limits);
+    assert!(doc.diagnostics().is_empty());
+    assert!(!val::parse_str_with(&"@column { ".repeat(9), &limits).diagnostics().is_empty());
+}
@@ -0,0 +1,52 @@
+extern crate val;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use val::{Code, Env, Error, Value};
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+#[test]
+fn test_closure_with_state() {
+    let mut table = HashMap::new();
+    table.insert("db".to_string(), "10.0.0.7".to_string());
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let env = Env::builder()
+        .register("host.lookup", &["name"], move |a| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let name = a.text("name")?;
+            match table.get(name) {
+                Some(ip) => Ok(Value::Text(ip.clone())),
+                None => Err(format!("{}: unknown host {}", a.name(), name).into()),
+            }
+        })
+        .build(val::parse_str("@column {
+            @call @from @lib { 'host' 'lookup' } { @bind name { 'db' } }
+            @call @from @lib { 'host' 'lookup' } { @bind name { 'web' } }
+        }").expr());
+    assert_eq!(env.eval(), Value::Column(vec![
+        text("10.0.0.7"),
+        Value::Err(Error::new(Code::Native, "@lib.host.lookup: unknown host web")),
+    ].into()));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_typed_arguments() {
+    let env = Env::builder()
+        .register("count", &["xs"], |a| Ok(Value::Text(a.column("xs")?.len().to_string())))
+        .build(val::parse_str("@column {
+            @call @from @lib { 'count' } { @bind xs { @column { 'a' 'b' } } }
+            @call @from @lib { 'count' } { @bind xs { 'a' } }
+            @call @from @lib { 'count' } {}
+        }").expr());
+    assert_eq!(env.eval(), Value::Column(vec![
+        text("2"),
+        Value::Err(Error::new(Code::WrongArgumentType, "@lib.count expects column argument 'xs'")),
+        Value::Err(Error::new(Code::MissingArgument, "@lib.count expects argument 'xs'")),
+    ].into()));
+}